@@ -0,0 +1,81 @@
+//! Measures how long it takes to analyze the cost of every node in a large
+//! generated revset, both with a [`CostCache`] shared between every node, and
+//! with a new cache for each node as if costs weren't cached.
+//!
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::Duration;
+use std::time::Instant;
+
+use jj_analyze::expr::Expr;
+use jj_analyze::expr::ResolvedReference;
+use jj_analyze::parse::ReferenceMap;
+use jj_analyze::print::FormatOptions;
+use jj_analyze::tree::AnalyzeContext;
+use jj_analyze::tree::CostCache;
+use jj_analyze::tree::walk;
+use jj_lib::revset::GENERATION_RANGE_FULL;
+use jj_lib::revset::PARENTS_RANGE_FULL;
+use jj_lib::revset::ResolvedExpression;
+
+/// Number of unions and intersections in the generated revset.
+const SIZE: usize = 2_000;
+
+/// Generates a revset like `((::r0 & ::r1) | ::r2) & ::r3`, where the cost of
+/// each operation depends on the cost of every operation nested inside of it.
+/// Unions and intersections alternate so that they can't be flattened.
+fn generate(reference_map: &mut ReferenceMap) -> ResolvedExpression {
+    let mut ancestors = |index: usize| {
+        let commit_id = reference_map.insert(ResolvedReference::new_owned(format!("r{index}")));
+        Box::new(ResolvedExpression::Ancestors {
+            heads: Box::new(ResolvedExpression::Commits(vec![commit_id])),
+            generation: GENERATION_RANGE_FULL,
+            parents_range: PARENTS_RANGE_FULL,
+        })
+    };
+    let mut backend_expr = *ancestors(0);
+    for index in 1..=SIZE {
+        backend_expr = if index % 2 == 0 {
+            ResolvedExpression::Union(Box::new(backend_expr), ancestors(index))
+        } else {
+            ResolvedExpression::Intersection(Box::new(backend_expr), ancestors(index))
+        };
+    }
+    backend_expr
+}
+
+fn measure(name: &str, expr: &Expr, new_cache: impl Fn() -> Option<CostCache>) -> Duration {
+    let options = FormatOptions::default();
+    let shared = CostCache::new();
+    let start = Instant::now();
+    walk(
+        expr,
+        AnalyzeContext::Lazy,
+        &options,
+        &mut |node| match new_cache() {
+            Some(cache) => {
+                black_box(cache.cost(node.tree, node.context));
+            }
+            None => {
+                black_box(shared.cost(node.tree, node.context));
+            }
+        },
+    );
+    let duration = start.elapsed();
+    println!("{name:<14} {duration:>12.2?}");
+    duration
+}
+
+fn main() {
+    let mut reference_map = ReferenceMap::new();
+    let backend_expr = generate(&mut reference_map);
+    let expr = Expr::parse(backend_expr, &reference_map);
+    println!("Analyzing the cost of every node with {SIZE} nested operations");
+    let shared = measure("shared cache", &expr, || None);
+    let uncached = measure("new caches", &expr, || Some(CostCache::new()));
+    println!(
+        "Sharing the cache is {:.1}x faster",
+        uncached.as_secs_f64() / shared.as_secs_f64()
+    );
+}
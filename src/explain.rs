@@ -0,0 +1,266 @@
+use std::io;
+use std::io::Write;
+
+use colored::Colorize;
+use indexmap::IndexSet;
+use itertools::Itertools as _;
+
+use crate::expr::operation_examples;
+use crate::parse::ReferenceMap;
+use crate::print::FormatOptions;
+use crate::tree::AnalyzeContext;
+use crate::tree::AnalyzeCost;
+use crate::tree::AnalyzeTree;
+use crate::tree::CostCache;
+use crate::tree::find_node_with_path;
+use crate::tree::node_entry;
+use crate::tree::resolve_node_number;
+use crate::tree::walk;
+
+pub fn explain_node(
+    out: &mut dyn Write,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    analyze: bool,
+    options: &FormatOptions,
+) -> anyhow::Result<()> {
+    let path = &resolve_node_number(tree, context, path, options)?;
+    let root_context = context;
+    let ((node, context), node_path) = find_node_with_path(tree, context, path, options)?;
+    let entry = node_entry(node, context, &node_path, options);
+    let mut sentences = vec![node.description()];
+    if analyze {
+        sentences.push(match entry.context {
+            AnalyzeContext::Eager => "It is evaluated eagerly, so all matching revisions are \
+                                      immediately collected into a list."
+                .into(),
+            AnalyzeContext::Lazy => "It is evaluated lazily, so matching revisions are streamed \
+                                     and evaluation can stop early if no longer required."
+                .into(),
+            AnalyzeContext::Predicate => "It is evaluated as a predicate, so it is tested against \
+                                          candidate revisions instead of producing revisions."
+                .into(),
+            AnalyzeContext::Resolved => {
+                "It is already resolved, so it doesn't need to be evaluated.".into()
+            }
+        });
+        if entry.context == AnalyzeContext::Resolved {
+            // Nothing to explain
+        } else if options.context_overrides.get(&node_path).is_some() {
+            sentences.push("This is determined by `--select-context`.".into());
+        } else if entry.context != context {
+            sentences.push("This is required by the operation itself.".into());
+        } else if path.split('.').all(|segment| segment.is_empty()) {
+            sentences.push("This is determined by `--context`.".into());
+        } else {
+            sentences.push("This is determined by how its parent uses it.".into());
+        }
+        let cache = CostCache::for_tree(tree, root_context, options);
+        match (cache.cost(node, context), node.cost_reason(context, &cache)) {
+            (AnalyzeCost::Fast, _) => {
+                sentences.push("It is not expected to be expensive.".into());
+            }
+            (_, Some(reason)) => {
+                sentences.push(format!("It may be expensive because {reason}.").into());
+            }
+            (_, None) => sentences.push("It may be expensive.".into()),
+        }
+    }
+    writeln!(out, "{}", entry.name.bold())?;
+    writeln!(out, "{}", sentences.iter().join(" "))?;
+    if !entry.children.is_empty() {
+        let children = entry
+            .children
+            .iter()
+            .enumerate()
+            .map(|(index, child)| match &child.label {
+                Some(label) => label.to_string(),
+                None => index.to_string(),
+            })
+            .join(", ");
+        writeln!(out, "{}", format!("Children: {children}").dimmed())?;
+    }
+    Ok(())
+}
+
+/// Writes one line for each expensive node, with its path and the reason it
+/// may be expensive. Nothing is written if there are no expensive nodes.
+pub fn print_expensive_nodes(
+    out: &mut dyn Write,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &FormatOptions,
+) -> anyhow::Result<()> {
+    let cache = CostCache::for_tree(tree, context, options);
+    let mut lines = Vec::new();
+    walk(tree, context, options, &mut |node| {
+        if cache.cost(node.tree, node.context) < AnalyzeCost::Slow {
+            return;
+        }
+        let path = if node.path.is_empty() { "." } else { node.path };
+        let mut line = format!("{} {}", format!("{path}:").dimmed(), node.entry.name.bold());
+        if let Some(reason) = node.tree.cost_reason(node.context, &cache) {
+            line.push_str(&format!(" ({reason})").dimmed().to_string());
+        }
+        lines.push(line);
+    });
+    for line in lines {
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Finds the references and filters which appear in `unoptimized` but don't
+/// appear anywhere in `optimized`, since they were removed by optimization.
+pub fn removed_by_optimization(
+    unoptimized: &dyn AnalyzeTree,
+    optimized: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    reference_map: &ReferenceMap,
+    options: &FormatOptions,
+) -> Vec<String> {
+    let references = reference_names(reference_map);
+    let remaining = leaf_names(optimized, context, &references, options);
+    leaf_names(unoptimized, context, &references, options)
+        .into_iter()
+        .filter(|name| !remaining.contains(name))
+        .collect()
+}
+
+/// Returns the name of every reference in the tree, sorted and without
+/// duplicates.
+pub fn list_references(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    reference_map: &ReferenceMap,
+    options: &FormatOptions,
+) -> Vec<String> {
+    let references = reference_names(reference_map);
+    let mut names = Vec::new();
+    walk(tree, context, options, &mut |node| {
+        let name = &node.entry.name;
+        if node.entry.children.is_empty() && references.contains(name.as_ref()) {
+            names.push(name.to_string());
+        }
+    });
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn reference_names(reference_map: &ReferenceMap) -> IndexSet<String> {
+    reference_map
+        .iter()
+        .map(|(_, reference)| reference.to_string())
+        .collect()
+}
+
+/// Returns the names of every reference and filter in the tree.
+fn leaf_names(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    references: &IndexSet<String>,
+    options: &FormatOptions,
+) -> IndexSet<String> {
+    let mut names = IndexSet::new();
+    walk(tree, context, options, &mut |node| {
+        let name = &node.entry.name;
+        if node.entry.children.is_empty()
+            && (node.entry.context == AnalyzeContext::Predicate
+                || references.contains(name.as_ref()))
+        {
+            names.insert(name.to_string());
+        }
+    });
+    names
+}
+
+/// The cost of every expensive node, in a form which can be serialized for
+/// other tools.
+#[derive(Debug, serde::Serialize)]
+pub struct CostReport {
+    /// The highest cost of any node in the tree.
+    pub max_cost: AnalyzeCost,
+    pub nodes: Vec<CostReportNode>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CostReportNode {
+    pub path: String,
+    pub name: String,
+    pub cost: AnalyzeCost,
+    pub reason: Option<String>,
+}
+
+impl CostReport {
+    /// Collects every node which may be expensive, in the same order as
+    /// [`print_expensive_nodes`].
+    pub fn new(tree: &dyn AnalyzeTree, context: AnalyzeContext, options: &FormatOptions) -> Self {
+        let cache = CostCache::for_tree(tree, context, options);
+        let mut max_cost = AnalyzeCost::Fast;
+        let mut nodes = Vec::new();
+        walk(tree, context, options, &mut |node| {
+            let cost = cache.cost(node.tree, node.context);
+            max_cost = max_cost.max(cost);
+            if cost < AnalyzeCost::Medium {
+                return;
+            }
+            nodes.push(CostReportNode {
+                path: if node.path.is_empty() {
+                    ".".to_owned()
+                } else {
+                    node.path.to_owned()
+                },
+                name: node.entry.name.to_string(),
+                cost,
+                reason: node
+                    .tree
+                    .cost_reason(node.context, &cache)
+                    .map(|reason| reason.into_owned()),
+            });
+        });
+        Self { max_cost, nodes }
+    }
+}
+
+/// Writes a short description of every operation which can appear in the tree,
+/// along with how each of its children is evaluated.
+pub fn describe_operations(out: &mut dyn Write, options: &FormatOptions) -> io::Result<()> {
+    for (index, example) in operation_examples().iter().enumerate() {
+        let entry = example.entry(AnalyzeContext::Lazy, options);
+        if index > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "{}", entry.name.bold())?;
+        writeln!(out, "{}", example.description())?;
+        if entry.context != AnalyzeContext::Lazy {
+            writeln!(
+                out,
+                "It is always evaluated {}, regardless of how its parent uses it.",
+                match entry.context {
+                    AnalyzeContext::Eager => "eagerly",
+                    AnalyzeContext::Lazy => "lazily",
+                    AnalyzeContext::Predicate => "as a predicate",
+                    AnalyzeContext::Resolved => "before the revset",
+                }
+            )?;
+        }
+        let unlabeled = if entry.children.len() == 1 {
+            "input"
+        } else {
+            "members"
+        };
+        let children = entry
+            .children
+            .iter()
+            .map(|child| {
+                let label = child.label.as_deref().unwrap_or(unlabeled);
+                format!("{label} ({})", child.context)
+            })
+            .unique()
+            .join(", ");
+        writeln!(out, "{}", format!("Children: {children}").dimmed())?;
+    }
+    Ok(())
+}
@@ -1,5 +1,9 @@
+use std::any::TypeId;
 use std::borrow::Cow;
 use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem;
 use std::ops::Range;
 
 use jj_lib::fileset::FilesetExpression;
@@ -7,25 +11,53 @@ use jj_lib::revset::GENERATION_RANGE_FULL;
 use jj_lib::revset::PARENTS_RANGE_FULL;
 use jj_lib::revset::ResolvedExpression;
 use jj_lib::revset::ResolvedPredicateExpression;
+use jj_lib::revset::ResolvedRevsetExpression;
+use jj_lib::revset::RevsetExpression;
 use jj_lib::revset::RevsetFilterPredicate;
+use jj_lib::str_util::StringExpression;
+use jj_lib::str_util::StringPattern;
 
+use crate::parse::PreservedFilter;
 use crate::parse::ReferenceMap;
+use crate::print::FormatOptions;
 use crate::print::format_date_pattern;
 use crate::print::format_fileset_expression;
 use crate::print::format_range;
-use crate::print::format_string_expression;
+use crate::print::write_string_expression;
 use crate::tree::AnalyzeContext;
 use crate::tree::AnalyzeCost;
 use crate::tree::AnalyzeTree;
 use crate::tree::Child;
+use crate::tree::CostCache;
 use crate::tree::TreeEntry;
+use crate::tree::count_label;
+use crate::tree::is_shown;
+use crate::tree::only_present;
 
-#[derive(Debug, Hash, PartialEq, Eq)]
-pub struct ResolvedReference<'a>(pub Cow<'a, str>);
+#[derive(Clone, Debug)]
+pub struct ResolvedReference<'a> {
+    /// The reference as it is written in a revset, such as `present(main)`.
+    pub name: Cow<'a, str>,
+    /// The operation from an enclosing `at_operation()`, which is displayed
+    /// after the name.
+    pub operation: Option<Cow<'a, str>>,
+    /// Why an ID prefix couldn't be resolved, such as `ambiguous`, which is
+    /// displayed after the name.
+    pub problem: Option<&'static str>,
+    /// The alias which the reference was expanded from with
+    /// `--alias-origins`, such as `trunk()`. It is only displayed, so it
+    /// isn't compared.
+    pub alias_origin: Option<Cow<'a, str>>,
+}
 
 impl ResolvedReference<'static> {
     pub const fn new_static(reference: &'static str) -> Self {
-        Self(Cow::Borrowed(reference))
+        Self {
+            name: Cow::Borrowed(reference),
+            operation: None,
+            problem: None,
+            alias_origin: None,
+        }
     }
 
     pub const fn root() -> Self {
@@ -45,28 +77,94 @@ impl ResolvedReference<'static> {
     }
 
     pub fn new_owned(reference: String) -> Self {
-        Self(Cow::Owned(reference))
+        Self {
+            name: Cow::Owned(reference),
+            operation: None,
+            problem: None,
+            alias_origin: None,
+        }
+    }
+}
+
+impl ResolvedReference<'_> {
+    pub fn into_owned(self) -> ResolvedReference<'static> {
+        ResolvedReference {
+            name: Cow::Owned(self.name.into_owned()),
+            operation: (self.operation).map(|operation| Cow::Owned(operation.into_owned())),
+            problem: self.problem,
+            alias_origin: self
+                .alias_origin
+                .map(|alias| Cow::Owned(alias.into_owned())),
+        }
+    }
+}
+
+// The problem is determined by the name, so it doesn't need to be compared
+impl PartialEq for ResolvedReference<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.operation == other.operation
+    }
+}
+
+impl Eq for ResolvedReference<'_> {}
+
+impl Hash for ResolvedReference<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.operation.hash(state);
     }
 }
 
 impl fmt::Display for ResolvedReference<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.name)?;
+        if let Some(operation) = &self.operation {
+            write!(f, " at operation {operation}")?;
+        }
+        if let Some(problem) = self.problem {
+            write!(f, " ({problem})")?;
+        }
+        Ok(())
     }
 }
 
 impl AnalyzeTree for ResolvedReference<'_> {
-    fn entry(&self, _context: AnalyzeContext) -> TreeEntry<'_> {
+    fn entry(&self, _context: AnalyzeContext, _options: &FormatOptions) -> TreeEntry<'_> {
         TreeEntry {
-            name: self.0.as_ref().into(),
+            name: if self.operation.is_none() && self.problem.is_none() {
+                self.name.as_ref().into()
+            } else {
+                self.to_string().into()
+            },
+            pattern_kinds: vec![],
             context: AnalyzeContext::Resolved,
             children: vec![],
         }
     }
 
-    fn cost(&self, _context: AnalyzeContext) -> AnalyzeCost {
+    fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
         AnalyzeCost::Fast
     }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        _cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        None
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<ResolvedReference<'static>>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        "A reference to revisions which is resolved before the revset is evaluated.".into()
+    }
+
+    fn alias_origin(&self) -> Option<&str> {
+        self.alias_origin.as_deref()
+    }
 }
 
 #[derive(Debug)]
@@ -78,19 +176,120 @@ pub enum Predicate<'a> {
     Intersection(Vec<Self>),
 }
 
+impl Predicate<'_> {
+    /// Converts any borrowed references into owned references, so that the
+    /// predicate no longer borrows from the `ReferenceMap`.
+    pub fn into_owned(self) -> Predicate<'static> {
+        match self {
+            Self::Filter(filter) => Predicate::Filter(filter),
+            Self::Set(expr) => Predicate::Set(Box::new(expr.into_owned())),
+            Self::NotIn(predicate) => Predicate::NotIn(Box::new(predicate.into_owned())),
+            Self::Union(predicates) => {
+                Predicate::Union(predicates.into_iter().map(Self::into_owned).collect())
+            }
+            Self::Intersection(predicates) => {
+                Predicate::Intersection(predicates.into_iter().map(Self::into_owned).collect())
+            }
+        }
+    }
+}
+
+// Filters can't be compared directly, so they are compared by how they are
+// displayed without truncating any patterns
+impl PartialEq for Predicate<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Filter(filter1), Self::Filter(filter2)) => {
+                filter_key(filter1) == filter_key(filter2)
+            }
+            (Self::Set(expr1), Self::Set(expr2)) => expr1 == expr2,
+            (Self::NotIn(predicate1), Self::NotIn(predicate2)) => predicate1 == predicate2,
+            (Self::Union(predicates1), Self::Union(predicates2))
+            | (Self::Intersection(predicates1), Self::Intersection(predicates2)) => {
+                predicates1 == predicates2
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Predicate<'_> {}
+
+impl Hash for Predicate<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Self::Filter(filter) => filter_key(filter).hash(state),
+            Self::Set(expr) => expr.hash(state),
+            Self::NotIn(predicate) => predicate.hash(state),
+            Self::Union(predicates) | Self::Intersection(predicates) => predicates.hash(state),
+        }
+    }
+}
+
+fn filter_key(filter: &RevsetFilterPredicate) -> Cow<'static, str> {
+    let options = FormatOptions {
+        max_pattern_width: None,
+        ..FormatOptions::default()
+    };
+    filter_to_string(filter, &options)
+}
+
+impl Predicate<'_> {
+    /// Checks whether the predicate can never match any revisions, including
+    /// if it is `none()`.
+    pub fn never_matches(&self, cache: &CostCache) -> bool {
+        matches!(self, Self::Set(expr) if expr.is_none()) || cache.is_always_empty(self)
+    }
+
+    /// Checks whether the predicate only combines filters, without any sets.
+    fn is_filter_combination(&self) -> bool {
+        match self {
+            Self::Filter(_) => true,
+            Self::Set(_) => false,
+            Self::NotIn(predicate) => predicate.is_filter_combination(),
+            Self::Union(predicates) | Self::Intersection(predicates) => {
+                predicates.iter().all(Self::is_filter_combination)
+            }
+        }
+    }
+
+    /// Returns the highest cost of any filter in the predicate. Sets are
+    /// evaluated separately, so they aren't included.
+    pub fn slowest_filter(&self) -> AnalyzeCost {
+        match self {
+            Self::Filter(filter) => filter_cost(filter).0,
+            Self::Set(_) => AnalyzeCost::Fast,
+            Self::NotIn(predicate) => predicate.slowest_filter(),
+            Self::Union(predicates) | Self::Intersection(predicates) => predicates
+                .iter()
+                .map(Self::slowest_filter)
+                .max()
+                .unwrap_or(AnalyzeCost::Fast),
+        }
+    }
+}
+
 impl<'a> Predicate<'a> {
     pub fn parse(
         predicate_expression: ResolvedPredicateExpression,
         reference_map: &'a ReferenceMap,
     ) -> Self {
         match predicate_expression {
+            ResolvedPredicateExpression::Filter(RevsetFilterPredicate::Extension(
+                ref extension,
+            )) if let Some(preserved) = extension.downcast_ref::<PreservedFilter>() => {
+                Self::from_preserved(&preserved.0)
+            }
             ResolvedPredicateExpression::Filter(filter) => Self::Filter(filter),
             ResolvedPredicateExpression::Set(expr) => {
                 Self::Set(Box::new(Expr::parse(*expr, reference_map)))
             }
-            ResolvedPredicateExpression::NotIn(expr) => {
-                Self::NotIn(Box::new(Self::parse(*expr, reference_map)))
-            }
+            ResolvedPredicateExpression::NotIn(expr) => match *expr {
+                // Negating a negated predicate gives the original predicate
+                ResolvedPredicateExpression::NotIn(expr) => Self::parse(*expr, reference_map),
+                expr => Self::NotIn(Box::new(Self::parse(expr, reference_map))),
+            },
             ResolvedPredicateExpression::Union(expr1, expr2) => {
                 let mut result = Vec::new();
                 let mut stack = vec![expr2, expr1];
@@ -108,7 +307,11 @@ impl<'a> Predicate<'a> {
                                 parsed => result.push(Self::Set(Box::new(parsed))),
                             }
                         }
-                        _ => result.push(Self::parse(*next, reference_map)),
+                        // A cancelled double negation may give another union
+                        _ => match Self::parse(*next, reference_map) {
+                            Self::Union(predicates) => result.extend(predicates),
+                            parsed => result.push(parsed),
+                        },
                     }
                 }
                 Self::Union(result)
@@ -121,42 +324,104 @@ impl<'a> Predicate<'a> {
                         stack.push(b);
                         stack.push(a);
                     } else {
-                        result.push(Self::parse(*next, reference_map));
+                        // A cancelled double negation may give another intersection
+                        match Self::parse(*next, reference_map) {
+                            Self::Intersection(predicates) => result.extend(predicates),
+                            parsed => result.push(parsed),
+                        }
                     }
                 }
                 Self::Intersection(result)
             }
         }
     }
+
+    /// Converts a combination of filters which was hidden from the optimizer
+    /// into a predicate, keeping the order it was written in.
+    fn from_preserved(expr: &ResolvedRevsetExpression) -> Self {
+        match expr {
+            RevsetExpression::Filter(filter) => Self::Filter(filter.clone()),
+            RevsetExpression::NotIn(expr) => match expr.as_ref() {
+                RevsetExpression::NotIn(expr) => Self::from_preserved(expr),
+                expr => Self::NotIn(Box::new(Self::from_preserved(expr))),
+            },
+            RevsetExpression::Union(expr1, expr2) => {
+                let mut result = Vec::new();
+                for expr in [expr1, expr2] {
+                    match Self::from_preserved(expr) {
+                        Self::Union(predicates) => result.extend(predicates),
+                        predicate => result.push(predicate),
+                    }
+                }
+                Self::Union(result)
+            }
+            RevsetExpression::Intersection(expr1, expr2) => {
+                let mut result = Vec::new();
+                for expr in [expr1, expr2] {
+                    match Self::from_preserved(expr) {
+                        Self::Intersection(predicates) => result.extend(predicates),
+                        predicate => result.push(predicate),
+                    }
+                }
+                Self::Intersection(result)
+            }
+            RevsetExpression::Difference(expr1, expr2) => Self::Intersection(vec![
+                Self::from_preserved(expr1),
+                Self::NotIn(Box::new(Self::from_preserved(expr2))),
+            ]),
+            _ => unreachable!("only combinations of filters are preserved"),
+        }
+    }
 }
 
 impl AnalyzeTree for Predicate<'_> {
-    fn entry(&self, _context: AnalyzeContext) -> TreeEntry<'_> {
+    fn entry(&self, _context: AnalyzeContext, options: &FormatOptions) -> TreeEntry<'_> {
         match self {
-            Self::Filter(RevsetFilterPredicate::File(FilesetExpression::All)) => TreeEntry {
-                name: "~empty()".to_string().into(),
-                context: AnalyzeContext::Predicate,
-                children: vec![],
-            },
-            Self::Filter(filter) => TreeEntry {
-                name: filter_to_string(filter),
-                context: AnalyzeContext::Predicate,
-                children: vec![],
-            },
-            Self::Set(expr) => expr.entry(AnalyzeContext::Predicate),
-            Self::NotIn(expr) => match expr.as_ref() {
-                Self::Filter(RevsetFilterPredicate::File(FilesetExpression::All)) => TreeEntry {
-                    name: "empty()".to_string().into(),
+            Self::Filter(RevsetFilterPredicate::File(FilesetExpression::All))
+                if !options.raw_names =>
+            {
+                TreeEntry {
+                    name: "~empty()".to_string().into(),
+                    pattern_kinds: vec![],
                     context: AnalyzeContext::Predicate,
                     children: vec![],
-                },
-                Self::Filter(filter) => TreeEntry {
-                    name: format!("~{}", filter_to_string(filter)).into(),
+                }
+            }
+            Self::Filter(filter) => {
+                let (name, pattern_kinds) = format_filter(filter, options);
+                TreeEntry {
+                    name,
+                    pattern_kinds,
                     context: AnalyzeContext::Predicate,
                     children: vec![],
-                },
+                }
+            }
+            Self::Set(expr) => expr.entry(AnalyzeContext::Predicate, options),
+            Self::NotIn(expr) => match expr.as_ref() {
+                Self::Filter(RevsetFilterPredicate::File(FilesetExpression::All))
+                    if !options.raw_names =>
+                {
+                    TreeEntry {
+                        name: "empty()".to_string().into(),
+                        pattern_kinds: vec![],
+                        context: AnalyzeContext::Predicate,
+                        children: vec![],
+                    }
+                }
+                Self::Filter(filter) => {
+                    let (name, pattern_kinds) = format_filter(filter, options);
+                    TreeEntry {
+                        name: format!("~{name}").into(),
+                        pattern_kinds: (pattern_kinds.into_iter())
+                            .map(|kind| kind.start + 1..kind.end + 1)
+                            .collect(),
+                        context: AnalyzeContext::Predicate,
+                        children: vec![],
+                    }
+                }
                 inner => TreeEntry {
                     name: "NotIn".into(),
+                    pattern_kinds: vec![],
                     context: AnalyzeContext::Predicate,
                     children: vec![Child {
                         label: None,
@@ -167,6 +432,7 @@ impl AnalyzeTree for Predicate<'_> {
             },
             Self::Union(exprs) => TreeEntry {
                 name: "Union".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Predicate,
                 children: exprs
                     .iter()
@@ -179,6 +445,7 @@ impl AnalyzeTree for Predicate<'_> {
             },
             Self::Intersection(exprs) => TreeEntry {
                 name: "Intersection".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Predicate,
                 children: exprs
                     .iter()
@@ -192,64 +459,201 @@ impl AnalyzeTree for Predicate<'_> {
         }
     }
 
-    fn cost(&self, _context: AnalyzeContext) -> AnalyzeCost {
-        if let Self::Set(expr) = self {
-            expr.cost(AnalyzeContext::Predicate)
-        } else {
-            AnalyzeCost::Fast
+    fn cost(&self, _context: AnalyzeContext, cache: &CostCache) -> AnalyzeCost {
+        match self {
+            Self::Filter(filter) => filter_cost(filter).0,
+            Self::Set(expr) => cache.cost(expr.as_ref(), AnalyzeContext::Predicate),
+            // Negated filters are displayed as a single node
+            Self::NotIn(expr) => match expr.as_ref() {
+                Self::Filter(filter) => filter_cost(filter).0,
+                _ => AnalyzeCost::Fast,
+            },
+            _ => AnalyzeCost::Fast,
+        }
+    }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        match self {
+            Self::Filter(filter) => filter_cost(filter).1.map(Cow::Borrowed),
+            Self::Set(expr) => expr.cost_reason(AnalyzeContext::Predicate, cache),
+            Self::NotIn(expr) => match expr.as_ref() {
+                Self::Filter(filter) => filter_cost(filter).1.map(Cow::Borrowed),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn cost_score(&self, context: AnalyzeContext, cache: &CostCache) -> u64 {
+        match self {
+            // Sets are displayed as the inner expression, which is scored instead
+            Self::Set(expr) => expr.cost_score(AnalyzeContext::Predicate, cache),
+            _ => self.cost(context, cache).score(),
         }
     }
+
+    fn is_always_empty(&self, cache: &CostCache) -> bool {
+        match self {
+            Self::Filter(RevsetFilterPredicate::ParentCount(range)) => range.is_empty(),
+            Self::Filter(RevsetFilterPredicate::File(FilesetExpression::None)) => true,
+            Self::Filter(_) | Self::NotIn(_) => false,
+            Self::Set(expr) => cache.is_always_empty(expr.as_ref()),
+            Self::Union(predicates) => predicates
+                .iter()
+                .all(|predicate| predicate.never_matches(cache)),
+            Self::Intersection(predicates) => predicates
+                .iter()
+                .any(|predicate| predicate.never_matches(cache)),
+        }
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<Predicate<'static>>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        match self {
+            Self::Filter(_) => "Matches revisions using their metadata or contents.".into(),
+            Self::Set(expr) => expr.description(),
+            Self::NotIn(expr) => match expr.as_ref() {
+                Self::Filter(_) => "Matches revisions using their metadata or contents.".into(),
+                _ => "Matches revisions which don't match the inner predicate.".into(),
+            },
+            Self::Union(_) => "Matches revisions which match any member.".into(),
+            Self::Intersection(_) => "Matches revisions which match every member.".into(),
+        }
+    }
+
+    fn as_predicate(&self) -> Option<&Predicate<'_>> {
+        Some(self)
+    }
 }
 
-fn filter_to_string(filter: &RevsetFilterPredicate) -> Cow<'static, str> {
+fn filter_cost(filter: &RevsetFilterPredicate) -> (AnalyzeCost, Option<&'static str>) {
     match filter {
+        RevsetFilterPredicate::Description(pattern)
+        | RevsetFilterPredicate::Subject(pattern)
+        | RevsetFilterPredicate::AuthorName(pattern)
+        | RevsetFilterPredicate::AuthorEmail(pattern)
+        | RevsetFilterPredicate::CommitterName(pattern)
+        | RevsetFilterPredicate::CommitterEmail(pattern) => match string_expression_cost(pattern) {
+            AnalyzeCost::Fast => (AnalyzeCost::Fast, None),
+            cost => (
+                cost,
+                Some("glob and regex patterns are slower to match than exact patterns"),
+            ),
+        },
+        RevsetFilterPredicate::DiffContains { .. } => (
+            AnalyzeCost::Slow,
+            Some("the diff of every candidate revision must be searched"),
+        ),
+        _ => (AnalyzeCost::Fast, None),
+    }
+}
+
+fn string_expression_cost(expr: &StringExpression) -> AnalyzeCost {
+    match expr {
+        StringExpression::Pattern(pattern) => match pattern.as_ref() {
+            StringPattern::Glob(_)
+            | StringPattern::GlobI(_)
+            | StringPattern::Regex(_)
+            | StringPattern::RegexI(_) => AnalyzeCost::Medium,
+            _ => AnalyzeCost::Fast,
+        },
+        StringExpression::NotIn(inner) => string_expression_cost(inner),
+        StringExpression::Union(a, b) | StringExpression::Intersection(a, b) => {
+            string_expression_cost(a).max(string_expression_cost(b))
+        }
+    }
+}
+
+pub fn filter_to_string(
+    filter: &RevsetFilterPredicate,
+    options: &FormatOptions,
+) -> Cow<'static, str> {
+    format_filter(filter, options).0
+}
+
+/// Formats a filter like [`filter_to_string`], also returning the byte ranges
+/// of the kinds of its string patterns.
+pub fn format_filter(
+    filter: &RevsetFilterPredicate,
+    options: &FormatOptions,
+) -> (Cow<'static, str>, Vec<Range<usize>>) {
+    let name = match filter {
         RevsetFilterPredicate::ParentCount(range) => {
-            if *range == (2..u32::MAX) {
+            if *range == (2..u32::MAX) && !options.raw_names {
                 "merges()".into()
             } else {
                 format!("parent_count({})", format_range(range, PARENTS_RANGE_FULL)).into()
             }
         }
         RevsetFilterPredicate::Description(pattern) => {
-            format!("description({})", format_string_expression(pattern)).into()
+            return string_filter("description", pattern, "", options);
         }
         RevsetFilterPredicate::Subject(pattern) => {
-            format!("subject({})", format_string_expression(pattern)).into()
+            return string_filter("subject", pattern, "", options);
         }
         RevsetFilterPredicate::AuthorName(pattern) => {
-            format!("author_name({})", format_string_expression(pattern)).into()
+            return string_filter("author_name", pattern, "", options);
         }
         RevsetFilterPredicate::AuthorEmail(pattern) => {
-            format!("author_email({})", format_string_expression(pattern)).into()
-        }
-        RevsetFilterPredicate::AuthorDate(date_pattern) => {
-            format!("author_date({})", format_date_pattern(date_pattern)).into()
+            return string_filter("author_email", pattern, "", options);
         }
+        RevsetFilterPredicate::AuthorDate(date_pattern) => format!(
+            "author_date({})",
+            format_date_pattern(date_pattern, options)
+        )
+        .into(),
         RevsetFilterPredicate::CommitterName(pattern) => {
-            format!("committer_name({})", format_string_expression(pattern)).into()
+            return string_filter("committer_name", pattern, "", options);
         }
         RevsetFilterPredicate::CommitterEmail(pattern) => {
-            format!("committer_email({})", format_string_expression(pattern)).into()
-        }
-        RevsetFilterPredicate::CommitterDate(date_pattern) => {
-            format!("committer_date({})", format_date_pattern(date_pattern)).into()
+            return string_filter("committer_email", pattern, "", options);
         }
+        RevsetFilterPredicate::CommitterDate(date_pattern) => format!(
+            "committer_date({})",
+            format_date_pattern(date_pattern, options)
+        )
+        .into(),
         RevsetFilterPredicate::File(files) => {
             format!("files({})", format_fileset_expression(files)).into()
         }
-        RevsetFilterPredicate::DiffContains { text, files } => format!(
-            "diff_contains({}, {})",
-            format_string_expression(text),
-            format_fileset_expression(files)
-        )
-        .into(),
+        RevsetFilterPredicate::DiffContains { text, files } => {
+            let files = format!(", {}", format_fileset_expression(files));
+            return string_filter("diff_contains", text, &files, options);
+        }
         RevsetFilterPredicate::HasConflict => "conflicts()".into(),
         RevsetFilterPredicate::Signed => "signed()".into(),
         RevsetFilterPredicate::Extension(ext) => format!("extension({ext:?})").into(),
-    }
+    };
+    (name, vec![])
 }
 
-#[derive(Debug)]
+fn string_filter(
+    function: &str,
+    expr: &StringExpression,
+    rest: &str,
+    options: &FormatOptions,
+) -> (Cow<'static, str>, Vec<Range<usize>>) {
+    let mut name = format!("{function}(");
+    let mut pattern_kinds = Vec::new();
+    write_string_expression(
+        &mut name,
+        &mut pattern_kinds,
+        expr,
+        options.max_pattern_width,
+    );
+    name.push_str(rest);
+    name.push(')');
+    (name.into(), pattern_kinds)
+}
+
+#[derive(Debug, Hash, PartialEq, Eq)]
 pub enum Expr<'a> {
     None,
     Reference(ResolvedReference<'a>),
@@ -301,13 +705,99 @@ pub enum Expr<'a> {
     Difference(Box<Self>, Box<Self>),
 }
 
+impl Expr<'_> {
+    /// Converts any borrowed references into owned references, so that the
+    /// expression no longer borrows from the `ReferenceMap`.
+    pub fn into_owned(self) -> Expr<'static> {
+        let owned = |expr: Box<Self>| Box::new(expr.into_owned());
+        let owned_all = |exprs: Vec<Self>| exprs.into_iter().map(Self::into_owned).collect();
+        match self {
+            Self::None => Expr::None,
+            Self::Reference(reference) => Expr::Reference(reference.into_owned()),
+            Self::Ancestors {
+                heads,
+                generation,
+                parents_range,
+            } => Expr::Ancestors {
+                heads: owned(heads),
+                generation,
+                parents_range,
+            },
+            Self::Range {
+                roots,
+                heads,
+                generation,
+                parents_range,
+            } => Expr::Range {
+                roots: owned(roots),
+                heads: owned(heads),
+                generation,
+                parents_range,
+            },
+            Self::DagRange {
+                roots,
+                heads,
+                generation_from_roots,
+            } => Expr::DagRange {
+                roots: owned(roots),
+                heads: owned(heads),
+                generation_from_roots,
+            },
+            Self::Reachable { sources, domain } => Expr::Reachable {
+                sources: owned(sources),
+                domain: owned(domain),
+            },
+            Self::Heads(expr) => Expr::Heads(owned(expr)),
+            Self::HeadsRange {
+                roots,
+                heads,
+                parents_range,
+                filter,
+            } => Expr::HeadsRange {
+                roots: owned(roots),
+                heads: owned(heads),
+                parents_range,
+                filter: filter.map(Predicate::into_owned),
+            },
+            Self::Roots(expr) => Expr::Roots(owned(expr)),
+            Self::ForkPoint(expr) => Expr::ForkPoint(owned(expr)),
+            Self::Bisect(expr) => Expr::Bisect(owned(expr)),
+            Self::HasSize { candidates, count } => Expr::HasSize {
+                candidates: owned(candidates),
+                count,
+            },
+            Self::Latest { candidates, count } => Expr::Latest {
+                candidates: owned(candidates),
+                count,
+            },
+            Self::Coalesce(exprs) => Expr::Coalesce(owned_all(exprs)),
+            Self::Union(exprs) => Expr::Union(owned_all(exprs)),
+            Self::FilterWithin {
+                candidates,
+                predicate,
+            } => Expr::FilterWithin {
+                candidates: owned(candidates),
+                predicate: predicate.into_owned(),
+            },
+            Self::Intersection(exprs) => Expr::Intersection(owned_all(exprs)),
+            Self::Difference(expr1, expr2) => Expr::Difference(owned(expr1), owned(expr2)),
+        }
+    }
+}
+
 impl<'a> Expr<'a> {
     pub fn is_none(&self) -> bool {
         matches!(self, Self::None)
     }
 
     pub fn is_root_or_none(&self) -> bool {
-        match self {
+        // Only the candidates of a difference matter, so chains of differences
+        // are followed without recursing
+        let mut expr = self;
+        while let Self::Difference(expr1, _) = expr {
+            expr = expr1;
+        }
+        match expr {
             Self::None => true,
             Self::Reference(reference) if reference == &ResolvedReference::root() => true,
             Self::Coalesce(exprs) => exprs.iter().all(|expr| expr.is_root_or_none()),
@@ -317,98 +807,226 @@ impl<'a> Expr<'a> {
         }
     }
 
+    /// Checks whether the expression can never contain any revisions, including
+    /// if it is `none()`.
+    pub fn is_none_or_empty(&self, cache: &CostCache) -> bool {
+        self.is_none() || cache.is_always_empty(self)
+    }
+
+    /// Checks whether the expression follows every ancestor or descendant with
+    /// no limit on the number of generations, such as `::x` or `x::`.
+    pub fn is_unbounded_traversal(&self) -> bool {
+        match self {
+            Self::Ancestors { generation, .. } => generation.end == u64::MAX,
+            Self::DagRange {
+                heads,
+                generation_from_roots,
+                ..
+            } => generation_from_roots.end == u64::MAX && heads.is_visible_heads_or_referenced(),
+            _ => false,
+        }
+    }
+
+    /// Checks whether the expression is `visible_heads() and referenced
+    /// revisions`, which is used as the heads of `x::`.
+    fn is_visible_heads_or_referenced(&self) -> bool {
+        matches!(self, Self::Reference(reference)
+            if *reference == ResolvedReference::visible_heads_or_referenced())
+    }
+
+    /// Creates a union of the expressions. Since each commit in a list of
+    /// commits becomes its own member, an empty list of commits adds no
+    /// members, so there may be fewer than two members left. In that case, the
+    /// union is replaced with `none()` or its only member.
+    fn union(mut exprs: Vec<Self>) -> Self {
+        match exprs.len() {
+            0 => Self::None,
+            1 => exprs.pop().unwrap(),
+            _ => Self::Union(exprs),
+        }
+    }
+
+    /// Creates an intersection of the expressions, similarly to
+    /// [`Self::union`]. An empty intersection is replaced with `all()`.
+    fn intersection(mut exprs: Vec<Self>) -> Self {
+        match exprs.len() {
+            0 => Self::Ancestors {
+                heads: Box::new(Self::Reference(
+                    ResolvedReference::visible_heads_or_referenced(),
+                )),
+                generation: GENERATION_RANGE_FULL,
+                parents_range: PARENTS_RANGE_FULL,
+            },
+            1 => exprs.pop().unwrap(),
+            _ => Self::Intersection(exprs),
+        }
+    }
+
     pub fn parse(backend_expr: ResolvedExpression, reference_map: &'a ReferenceMap) -> Self {
-        let parse = |expr| Box::new(Self::parse(expr, reference_map));
+        // Children are parsed before their parents are built, so the call stack
+        // doesn't grow with the depth of the revset
+        let mut steps = vec![ParseStep::Parse(backend_expr)];
+        let mut output = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                ParseStep::Parse(expr) => Self::parse_step(expr, reference_map, &mut steps),
+                ParseStep::Done(expr) => output.push(expr),
+                ParseStep::Build(count, build) => {
+                    let children = output.split_off(output.len() - count);
+                    output.push(build(children));
+                }
+            }
+        }
+        output.pop().unwrap()
+    }
+
+    /// Pushes the steps which parse a single expression onto `steps`.
+    fn parse_step(
+        backend_expr: ResolvedExpression,
+        reference_map: &'a ReferenceMap,
+        steps: &mut Vec<ParseStep<'a>>,
+    ) {
+        use ParseStep::Parse;
 
         match backend_expr {
-            ResolvedExpression::Commits(commit_ids) if commit_ids.is_empty() => Self::None,
+            ResolvedExpression::Commits(commit_ids) if commit_ids.is_empty() => {
+                steps.push(ParseStep::Done(Self::None));
+            }
             ResolvedExpression::Commits(commit_ids) if commit_ids.len() == 1 => {
-                Self::Reference(reference_map.get(&commit_ids[0]))
+                steps.push(ParseStep::Done(Self::Reference(
+                    reference_map.get(&commit_ids[0]),
+                )));
             }
             ResolvedExpression::Commits(commit_ids)
-                if commit_ids.iter().any(|commit_id| {
-                    reference_map.get(commit_id) == ResolvedReference::visible_heads()
-                }) =>
+                if !reference_map.expand_visible_heads()
+                    && commit_ids.iter().any(|commit_id| {
+                        reference_map.get(commit_id) == ResolvedReference::visible_heads()
+                    }) =>
             {
-                Self::Reference(ResolvedReference::visible_heads_or_referenced())
+                steps.push(ParseStep::Done(Self::Reference(
+                    ResolvedReference::visible_heads_or_referenced(),
+                )));
+            }
+            ResolvedExpression::Commits(commit_ids) => {
+                steps.push(ParseStep::Done(Self::Union(
+                    commit_ids
+                        .iter()
+                        .map(|commit_id| Self::Reference(reference_map.get(commit_id)))
+                        .collect(),
+                )));
             }
-            ResolvedExpression::Commits(commit_ids) => Self::Union(
-                commit_ids
-                    .iter()
-                    .map(|commit_id| Self::Reference(reference_map.get(commit_id)))
-                    .collect(),
-            ),
             ResolvedExpression::Ancestors {
                 heads,
                 generation,
                 parents_range,
-            } => Self::Ancestors {
-                heads: parse(*heads),
-                generation,
-                parents_range,
-            },
+            } => ParseStep::push(steps, vec![Parse(*heads)], move |children| {
+                let [heads] = boxed(children);
+                Self::Ancestors {
+                    heads,
+                    generation,
+                    parents_range,
+                }
+            }),
             ResolvedExpression::Range {
                 roots,
                 heads,
                 generation,
                 parents_range,
-            } => Self::Range {
-                roots: parse(*roots),
-                heads: parse(*heads),
-                generation,
-                parents_range,
-            },
+            } => ParseStep::push(steps, vec![Parse(*roots), Parse(*heads)], move |children| {
+                let [roots, heads] = boxed(children);
+                Self::Range {
+                    roots,
+                    heads,
+                    generation,
+                    parents_range,
+                }
+            }),
             ResolvedExpression::DagRange {
                 roots,
                 heads,
                 generation_from_roots,
-            } => Self::DagRange {
-                roots: parse(*roots),
-                heads: parse(*heads),
-                generation_from_roots,
-            },
-            ResolvedExpression::Reachable { sources, domain } => Self::Reachable {
-                sources: parse(*sources),
-                domain: parse(*domain),
-            },
-            ResolvedExpression::Heads(expr) => Self::Heads(parse(*expr)),
+            } => ParseStep::push(steps, vec![Parse(*roots), Parse(*heads)], move |children| {
+                let [roots, heads] = boxed(children);
+                Self::DagRange {
+                    roots,
+                    heads,
+                    generation_from_roots,
+                }
+            }),
+            ResolvedExpression::Reachable { sources, domain } => {
+                ParseStep::push(steps, vec![Parse(*sources), Parse(*domain)], |children| {
+                    let [sources, domain] = boxed(children);
+                    Self::Reachable { sources, domain }
+                })
+            }
+            ResolvedExpression::Heads(expr) => {
+                ParseStep::push(steps, vec![Parse(*expr)], |children| {
+                    let [expr] = boxed(children);
+                    Self::Heads(expr)
+                });
+            }
             ResolvedExpression::HeadsRange {
                 roots,
                 heads,
                 parents_range,
                 filter,
-            } => Self::HeadsRange {
-                roots: parse(*roots),
-                heads: parse(*heads),
-                parents_range,
-                filter: filter.map(|predicate| Predicate::parse(predicate, reference_map)),
-            },
-            ResolvedExpression::Roots(expr) => Self::Roots(parse(*expr)),
-            ResolvedExpression::ForkPoint(expr) => Self::ForkPoint(parse(*expr)),
-            ResolvedExpression::Bisect(expr) => Self::Bisect(parse(*expr)),
-            ResolvedExpression::HasSize { candidates, count } => Self::HasSize {
-                candidates: parse(*candidates),
-                count,
-            },
-            ResolvedExpression::Latest { candidates, count } => Self::Latest {
-                candidates: parse(*candidates),
-                count,
-            },
+            } => {
+                let filter = filter.map(|predicate| Predicate::parse(predicate, reference_map));
+                ParseStep::push(steps, vec![Parse(*roots), Parse(*heads)], move |children| {
+                    let [roots, heads] = boxed(children);
+                    Self::HeadsRange {
+                        roots,
+                        heads,
+                        parents_range,
+                        filter,
+                    }
+                });
+            }
+            ResolvedExpression::Roots(expr) => {
+                ParseStep::push(steps, vec![Parse(*expr)], |children| {
+                    let [expr] = boxed(children);
+                    Self::Roots(expr)
+                });
+            }
+            ResolvedExpression::ForkPoint(expr) => {
+                ParseStep::push(steps, vec![Parse(*expr)], |children| {
+                    let [expr] = boxed(children);
+                    Self::ForkPoint(expr)
+                });
+            }
+            ResolvedExpression::Bisect(expr) => {
+                ParseStep::push(steps, vec![Parse(*expr)], |children| {
+                    let [expr] = boxed(children);
+                    Self::Bisect(expr)
+                });
+            }
+            ResolvedExpression::HasSize { candidates, count } => {
+                ParseStep::push(steps, vec![Parse(*candidates)], move |children| {
+                    let [candidates] = boxed(children);
+                    Self::HasSize { candidates, count }
+                });
+            }
+            ResolvedExpression::Latest { candidates, count } => {
+                ParseStep::push(steps, vec![Parse(*candidates)], move |children| {
+                    let [candidates] = boxed(children);
+                    Self::Latest { candidates, count }
+                });
+            }
             ResolvedExpression::Coalesce(expr1, expr2) => {
-                let mut result = Vec::new();
+                let mut members = Vec::new();
                 let mut stack = vec![expr2, expr1];
                 while let Some(next) = stack.pop() {
                     if let ResolvedExpression::Coalesce(a, b) = *next {
                         stack.push(b);
                         stack.push(a);
                     } else {
-                        result.push(Self::parse(*next, reference_map));
+                        members.push(Parse(*next));
                     }
                 }
-                Self::Coalesce(result)
+                ParseStep::push(steps, members, Self::Coalesce);
             }
             ResolvedExpression::Union(expr1, expr2) => {
-                let mut result = Vec::new();
+                let mut members = Vec::new();
                 let mut stack = vec![expr2, expr1];
                 while let Some(next) = stack.pop() {
                     match *next {
@@ -417,64 +1035,124 @@ impl<'a> Expr<'a> {
                             stack.push(a);
                         }
                         ResolvedExpression::Commits(commit_ids)
-                            if !commit_ids.iter().any(|commit_id| {
-                                reference_map.get(commit_id) == ResolvedReference::visible_heads()
-                            }) =>
+                            if reference_map.expand_visible_heads()
+                                || !commit_ids.iter().any(|commit_id| {
+                                    reference_map.get(commit_id)
+                                        == ResolvedReference::visible_heads()
+                                }) =>
                         {
-                            result.extend(
-                                commit_ids
-                                    .iter()
-                                    .map(|commit_id| Self::Reference(reference_map.get(commit_id))),
-                            )
+                            members.extend(commit_ids.iter().map(|commit_id| {
+                                ParseStep::Done(Self::Reference(reference_map.get(commit_id)))
+                            }))
                         }
-                        _ => result.push(Self::parse(*next, reference_map)),
+                        next => members.push(Parse(next)),
                     }
                 }
-                Self::Union(result)
+                ParseStep::push(steps, members, Self::union);
             }
             ResolvedExpression::FilterWithin {
                 candidates,
                 predicate,
-            } => Self::FilterWithin {
-                candidates: parse(*candidates),
-                predicate: Predicate::parse(predicate, reference_map),
-            },
+            } => {
+                let predicate = Predicate::parse(predicate, reference_map);
+                ParseStep::push(steps, vec![Parse(*candidates)], |children| {
+                    let [candidates] = boxed(children);
+                    Self::FilterWithin {
+                        candidates,
+                        predicate,
+                    }
+                });
+            }
             ResolvedExpression::Intersection(expr1, expr2) => {
-                let mut result = Vec::new();
+                let mut members = Vec::new();
                 let mut stack = vec![expr2, expr1];
                 while let Some(next) = stack.pop() {
                     if let ResolvedExpression::Intersection(a, b) = *next {
                         stack.push(b);
                         stack.push(a);
                     } else {
-                        result.push(Self::parse(*next, reference_map));
+                        members.push(Parse(*next));
                     }
                 }
-                Self::Intersection(result)
+                ParseStep::push(steps, members, Self::intersection);
             }
             ResolvedExpression::Difference(expr1, expr2) => {
-                Self::Difference(parse(*expr1), parse(*expr2))
+                ParseStep::push(steps, vec![Parse(*expr1), Parse(*expr2)], |children| {
+                    let [expr1, expr2] = boxed(children);
+                    Self::Difference(expr1, expr2)
+                })
             }
         }
     }
 }
 
+/// A step of [`Expr::parse`], which parses each child before combining the
+/// children into their parent.
+enum ParseStep<'a> {
+    /// Parses a backend expression and pushes the result.
+    Parse(ResolvedExpression),
+    /// Pushes an expression which doesn't need to be parsed.
+    Done(Expr<'a>),
+    /// Pops the given number of expressions and combines them into one.
+    Build(usize, Box<dyn FnOnce(Vec<Expr<'a>>) -> Expr<'a> + 'a>),
+}
+
+impl<'a> ParseStep<'a> {
+    /// Pushes steps which run each of `children` in order and then combine
+    /// their results using `build`.
+    fn push(
+        steps: &mut Vec<Self>,
+        children: Vec<Self>,
+        build: impl FnOnce(Vec<Expr<'a>>) -> Expr<'a> + 'a,
+    ) {
+        steps.push(Self::Build(children.len(), Box::new(build)));
+        steps.extend(children.into_iter().rev());
+    }
+}
+
+fn boxed<const N: usize>(children: Vec<Expr<'_>>) -> [Box<Expr<'_>>; N] {
+    let children: Vec<_> = children.into_iter().map(Box::new).collect();
+    children.try_into().unwrap()
+}
+
+/// Lazily evaluates an expression used as a predicate, unless contexts should
+/// be displayed literally.
+fn predicate_to_lazy(context: AnalyzeContext, options: &FormatOptions) -> AnalyzeContext {
+    if options.literal_contexts {
+        context
+    } else {
+        context.predicate_to_lazy()
+    }
+}
+
+/// Lazily evaluates an expression which only needs to be tested against
+/// another set, unless contexts should be displayed literally.
+fn eager_to_lazy(context: AnalyzeContext, options: &FormatOptions) -> AnalyzeContext {
+    if options.literal_contexts {
+        context
+    } else {
+        context.eager_to_lazy()
+    }
+}
+
 impl AnalyzeTree for Expr<'_> {
-    fn entry(&self, context: AnalyzeContext) -> TreeEntry<'_> {
+    fn entry(&self, context: AnalyzeContext, options: &FormatOptions) -> TreeEntry<'_> {
         match self {
             Self::None => TreeEntry {
                 name: "none()".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Resolved,
                 children: vec![],
             },
-            Self::Reference(reference) => reference.entry(context),
+            Self::Reference(reference) => reference.entry(context, options),
             Self::Ancestors {
                 heads,
                 generation,
                 parents_range,
             } => TreeEntry {
                 name: "Ancestors".into(),
-                context: context.predicate_to_lazy(),
+                pattern_kinds: vec![],
+                context: predicate_to_lazy(context, options),
                 children: only_present(vec![
                     (*generation != GENERATION_RANGE_FULL).then(|| Child {
                         label: Some("generation".into()),
@@ -500,7 +1178,8 @@ impl AnalyzeTree for Expr<'_> {
                 parents_range,
             } => TreeEntry {
                 name: "Range".into(),
-                context: context.predicate_to_lazy(),
+                pattern_kinds: vec![],
+                context: predicate_to_lazy(context, options),
                 children: only_present(vec![
                     (*generation != GENERATION_RANGE_FULL).then(|| Child {
                         label: Some("generation".into()),
@@ -524,14 +1203,43 @@ impl AnalyzeTree for Expr<'_> {
                     }),
                 ]),
             },
+            // The descendants of `x` are lowered to a range ending at the visible
+            // heads, so they are displayed as they were written unless `--no-rename`
+            // was passed
+            Self::DagRange {
+                roots,
+                heads,
+                generation_from_roots,
+            } if heads.is_visible_heads_or_referenced() && !options.raw_names => TreeEntry {
+                name: "Descendants".into(),
+                pattern_kinds: vec![],
+                context: if generation_from_roots == &(1..2) {
+                    predicate_to_lazy(context, options)
+                } else {
+                    AnalyzeContext::Eager
+                },
+                children: only_present(vec![
+                    (*generation_from_roots != GENERATION_RANGE_FULL).then(|| Child {
+                        label: Some("generation".into()),
+                        context,
+                        tree: generation_from_roots,
+                    }),
+                    Some(Child {
+                        label: Some("roots".into()),
+                        context: AnalyzeContext::Eager,
+                        tree: roots.as_ref(),
+                    }),
+                ]),
+            },
             Self::DagRange {
                 roots,
                 heads,
                 generation_from_roots,
             } => TreeEntry {
                 name: "DagRange".into(),
+                pattern_kinds: vec![],
                 context: if generation_from_roots == &(1..2) {
-                    context.predicate_to_lazy()
+                    predicate_to_lazy(context, options)
                 } else {
                     AnalyzeContext::Eager
                 },
@@ -555,6 +1263,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Reachable { sources, domain } => TreeEntry {
                 name: "Reachable".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![
                     Child {
@@ -571,6 +1280,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Heads(expr) => TreeEntry {
                 name: "Heads".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![Child {
                     label: None,
@@ -585,6 +1295,7 @@ impl AnalyzeTree for Expr<'_> {
                 filter,
             } => TreeEntry {
                 name: "HeadsRange".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: only_present(vec![
                     (*parents_range != PARENTS_RANGE_FULL).then(|| Child {
@@ -592,7 +1303,7 @@ impl AnalyzeTree for Expr<'_> {
                         context,
                         tree: parents_range,
                     }),
-                    Some(Child {
+                    is_shown(roots.is_none(), options).then(|| Child {
                         label: Some("roots".into()),
                         context: AnalyzeContext::Eager,
                         tree: roots.as_ref(),
@@ -611,6 +1322,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Roots(expr) => TreeEntry {
                 name: "Roots".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![Child {
                     label: None,
@@ -620,6 +1332,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::ForkPoint(expr) => TreeEntry {
                 name: "ForkPoint".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![Child {
                     label: None,
@@ -629,6 +1342,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Bisect(expr) => TreeEntry {
                 name: "Bisect".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![Child {
                     label: None,
@@ -638,10 +1352,11 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::HasSize { candidates, count } => TreeEntry {
                 name: "HasSize".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
                 children: vec![
                     Child {
-                        label: Some("count".into()),
+                        label: Some(count_label("exactly", options).into()),
                         context: AnalyzeContext::Resolved,
                         tree: count,
                     },
@@ -654,22 +1369,24 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Latest { candidates, count } => TreeEntry {
                 name: "Latest".into(),
+                pattern_kinds: vec![],
                 context: AnalyzeContext::Eager,
-                children: vec![
-                    Child {
-                        label: Some("count".into()),
+                children: only_present(vec![
+                    is_shown(*count == 1, options).then(|| Child {
+                        label: Some(count_label("most_recent", options).into()),
                         context: AnalyzeContext::Resolved,
                         tree: count,
-                    },
-                    Child {
+                    }),
+                    Some(Child {
                         label: Some("candidates".into()),
                         context: AnalyzeContext::Eager,
                         tree: candidates.as_ref(),
-                    },
-                ],
+                    }),
+                ]),
             },
             Self::Coalesce(exprs) => TreeEntry {
                 name: "Coalesce".into(),
+                pattern_kinds: vec![],
                 context,
                 children: exprs
                     .iter()
@@ -682,6 +1399,7 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Union(exprs) => TreeEntry {
                 name: "Union".into(),
+                pattern_kinds: vec![],
                 context,
                 children: exprs
                     .iter()
@@ -697,11 +1415,12 @@ impl AnalyzeTree for Expr<'_> {
                 predicate,
             } => TreeEntry {
                 name: "FilterWithin".into(),
+                pattern_kinds: vec![],
                 context,
                 children: vec![
                     Child {
                         label: Some("candidates".into()),
-                        context,
+                        context: options.filter_context.unwrap_or(context),
                         tree: candidates.as_ref(),
                     },
                     Child {
@@ -713,18 +1432,20 @@ impl AnalyzeTree for Expr<'_> {
             },
             Self::Intersection(exprs) => TreeEntry {
                 name: "Intersection".into(),
+                pattern_kinds: vec![],
                 context,
                 children: exprs
                     .iter()
                     .map(|expr| Child {
                         label: None,
-                        context: context.eager_to_lazy(),
+                        context: eager_to_lazy(context, options),
                         tree: expr,
                     })
                     .collect(),
             },
             Self::Difference(expr1, expr2) => TreeEntry {
                 name: "Difference".into(),
+                pattern_kinds: vec![],
                 context,
                 children: vec![
                     Child {
@@ -734,7 +1455,7 @@ impl AnalyzeTree for Expr<'_> {
                     },
                     Child {
                         label: Some("excluded".into()),
-                        context: context.eager_to_lazy(),
+                        context: eager_to_lazy(context, options),
                         tree: expr2.as_ref(),
                     },
                 ],
@@ -742,7 +1463,214 @@ impl AnalyzeTree for Expr<'_> {
         }
     }
 
-    fn cost(&self, context: AnalyzeContext) -> AnalyzeCost {
+    fn cost(&self, context: AnalyzeContext, cache: &CostCache) -> AnalyzeCost {
+        self.analyze_cost(context, cache).0
+    }
+
+    fn cost_reason(&self, context: AnalyzeContext, cache: &CostCache) -> Option<Cow<'static, str>> {
+        self.analyze_cost(context, cache).1.map(Cow::Borrowed)
+    }
+
+    fn cost_score(&self, context: AnalyzeContext, cache: &CostCache) -> u64 {
+        // Expensive operations are scored by themselves, since the cost of some
+        // operations only reflects the cost of their children
+        if self.analyze_cost(context, cache).0 == AnalyzeCost::Fast {
+            return AnalyzeCost::Fast.score();
+        }
+        match self {
+            Self::Ancestors { generation, .. } | Self::Range { generation, .. } => {
+                range_score(generation)
+            }
+            Self::DagRange {
+                generation_from_roots,
+                ..
+            } => range_score(generation_from_roots),
+            Self::Reachable { .. } | Self::FilterWithin { .. } => AnalyzeCost::Slow.score(),
+            Self::Latest { .. } => AnalyzeCost::Medium.score(),
+            _ => AnalyzeCost::Fast.score(),
+        }
+    }
+
+    fn is_always_empty(&self, cache: &CostCache) -> bool {
+        let empty = |expr: &Expr| expr.is_none_or_empty(cache);
+        match self {
+            // An explicit `none()` isn't a mistake, so it isn't reported
+            Self::None | Self::Reference(_) => false,
+            Self::Ancestors {
+                heads,
+                generation,
+                parents_range,
+            }
+            | Self::Range {
+                heads,
+                generation,
+                parents_range,
+                ..
+            } => empty(heads) || generation.is_empty() || parents_range.is_empty(),
+            Self::DagRange {
+                roots,
+                heads,
+                generation_from_roots,
+            } => empty(roots) || empty(heads) || generation_from_roots.is_empty(),
+            Self::Reachable { sources, domain } => empty(sources) || empty(domain),
+            Self::HeadsRange {
+                heads,
+                parents_range,
+                filter,
+                ..
+            } => {
+                empty(heads)
+                    || parents_range.is_empty()
+                    || filter
+                        .as_ref()
+                        .is_some_and(|filter| filter.never_matches(cache))
+            }
+            Self::Heads(expr) | Self::Roots(expr) | Self::ForkPoint(expr) | Self::Bisect(expr) => {
+                empty(expr)
+            }
+            Self::HasSize { candidates, .. } => empty(candidates),
+            Self::Latest { candidates, count } => *count == 0 || empty(candidates),
+            Self::Coalesce(exprs) | Self::Union(exprs) => exprs.iter().all(empty),
+            Self::FilterWithin {
+                candidates,
+                predicate,
+            } => empty(candidates) || predicate.never_matches(cache),
+            Self::Intersection(exprs) => exprs.iter().any(empty),
+            Self::Difference(expr1, _) => empty(expr1),
+        }
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<Expr<'static>>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        match self {
+            Self::None => "Matches no revisions.",
+            Self::Reference(_) => {
+                "A reference to revisions which is resolved before the revset is evaluated."
+            }
+            Self::Ancestors { .. } => {
+                "Finds the ancestors of `heads`, optionally limited by `generation` and \
+                 `parent_index`."
+            }
+            Self::Range { .. } => {
+                "Finds the ancestors of `heads` which are not ancestors of `roots`."
+            }
+            Self::DagRange { heads, .. } if heads.is_visible_heads_or_referenced() => {
+                "Finds the visible descendants of `roots`."
+            }
+            Self::DagRange { .. } => {
+                "Finds the revisions which are both descendants of `roots` and ancestors of \
+                 `heads`."
+            }
+            Self::Reachable { .. } => {
+                "Finds the revisions in `domain` which are reachable from `sources` by following \
+                 parent and child edges within `domain`."
+            }
+            Self::Heads(_) => "Finds the revisions which are not ancestors of other revisions.",
+            Self::HeadsRange { .. } => {
+                "Finds the heads of the revisions which are ancestors of `heads`, are not \
+                 ancestors of `roots`, and match `filter`."
+            }
+            Self::Roots(_) => "Finds the revisions which are not descendants of other revisions.",
+            Self::ForkPoint(_) => "Finds the best common ancestors of the revisions.",
+            Self::Bisect(_) => "Finds the revisions which would split the set roughly in half.",
+            Self::HasSize { .. } => "Fails unless `candidates` contains exactly `count` revisions.",
+            Self::Latest { .. } => {
+                "Finds the `count` revisions in `candidates` with the latest committer timestamps."
+            }
+            Self::Coalesce(_) => "Evaluates to the first member which is not empty.",
+            Self::Union(_) => "Finds the revisions which are in any member.",
+            Self::FilterWithin { .. } => {
+                "Finds the revisions in `candidates` which match `predicate`."
+            }
+            Self::Intersection(_) => "Finds the revisions which are in every member.",
+            Self::Difference(_, _) => {
+                "Finds the revisions in `candidates` which are not in `excluded`."
+            }
+        }
+        .into()
+    }
+
+    fn alias_origin(&self) -> Option<&str> {
+        match self {
+            Self::Reference(reference) => reference.alias_origin(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns an example of every operation which can appear in the tree, with
+/// `none()` for each child. Leaves like references and filters are excluded.
+pub fn operation_examples() -> Vec<Box<dyn AnalyzeTree>> {
+    let none = || Box::new(Expr::None);
+    let predicate = || Predicate::Set(none());
+    let examples: Vec<Box<dyn AnalyzeTree>> = vec![
+        Box::new(Expr::Ancestors {
+            heads: none(),
+            generation: GENERATION_RANGE_FULL,
+            parents_range: PARENTS_RANGE_FULL,
+        }),
+        Box::new(Expr::Range {
+            roots: none(),
+            heads: none(),
+            generation: GENERATION_RANGE_FULL,
+            parents_range: PARENTS_RANGE_FULL,
+        }),
+        Box::new(Expr::DagRange {
+            roots: none(),
+            heads: Box::new(Expr::Reference(
+                ResolvedReference::visible_heads_or_referenced(),
+            )),
+            generation_from_roots: GENERATION_RANGE_FULL,
+        }),
+        Box::new(Expr::DagRange {
+            roots: none(),
+            heads: none(),
+            generation_from_roots: GENERATION_RANGE_FULL,
+        }),
+        Box::new(Expr::Reachable {
+            sources: none(),
+            domain: none(),
+        }),
+        Box::new(Expr::Heads(none())),
+        Box::new(Expr::HeadsRange {
+            roots: none(),
+            heads: none(),
+            parents_range: PARENTS_RANGE_FULL,
+            filter: Some(predicate()),
+        }),
+        Box::new(Expr::Roots(none())),
+        Box::new(Expr::ForkPoint(none())),
+        Box::new(Expr::Bisect(none())),
+        Box::new(Expr::HasSize {
+            candidates: none(),
+            count: 1,
+        }),
+        Box::new(Expr::Latest {
+            candidates: none(),
+            count: 1,
+        }),
+        Box::new(Expr::Coalesce(vec![Expr::None, Expr::None])),
+        Box::new(Expr::Union(vec![Expr::None, Expr::None])),
+        Box::new(Expr::FilterWithin {
+            candidates: none(),
+            predicate: predicate(),
+        }),
+        Box::new(Expr::Intersection(vec![Expr::None, Expr::None])),
+        Box::new(Expr::Difference(none(), none())),
+        Box::new(Predicate::NotIn(Box::new(predicate()))),
+    ];
+    examples
+}
+
+impl Expr<'_> {
+    fn analyze_cost(
+        &self,
+        context: AnalyzeContext,
+        cache: &CostCache,
+    ) -> (AnalyzeCost, Option<&'static str>) {
         match self {
             Expr::Ancestors {
                 heads,
@@ -752,7 +1680,10 @@ impl AnalyzeTree for Expr<'_> {
                 && !heads.is_root_or_none()
                 && is_large_range(generation) =>
             {
-                AnalyzeCost::Slow
+                (
+                    AnalyzeCost::Slow,
+                    Some("collecting every ancestor of `heads` may visit most of the repo"),
+                )
             }
             Expr::Range {
                 roots,
@@ -764,7 +1695,10 @@ impl AnalyzeTree for Expr<'_> {
                 && !heads.is_root_or_none()
                 && is_large_range(generation) =>
             {
-                AnalyzeCost::Slow
+                (
+                    AnalyzeCost::Slow,
+                    Some("`roots` doesn't exclude any ancestors of `heads`"),
+                )
             }
             Expr::DagRange {
                 roots,
@@ -775,24 +1709,276 @@ impl AnalyzeTree for Expr<'_> {
                 && !heads.is_root_or_none()
                 && is_large_range(generation_from_roots) =>
             {
-                AnalyzeCost::Slow
+                (
+                    AnalyzeCost::Slow,
+                    Some("every descendant of the root revision must be visited"),
+                )
             }
+            Expr::Reachable { domain, .. }
+                if !domain.is_root_or_none()
+                    && cache.cost(domain.as_ref(), AnalyzeContext::Eager) == AnalyzeCost::Slow =>
+            {
+                (
+                    AnalyzeCost::Slow,
+                    Some("`domain` is unbounded, so most of the repo may be visited"),
+                )
+            }
+            // An expensive filter is applied to every revision in the repo
+            // instead of a smaller set of candidates
+            Expr::FilterWithin {
+                candidates,
+                predicate,
+            } if candidates.is_unbounded_traversal()
+                && predicate.slowest_filter() >= AnalyzeCost::Medium =>
+            {
+                (
+                    AnalyzeCost::Slow,
+                    Some(
+                        "an expensive filter is applied to every ancestor, so consider \
+                         intersecting with a smaller set first",
+                    ),
+                )
+            }
+            // Candidates are sorted by date, so a count larger than most sets of
+            // candidates is effectively a full sort
+            Expr::Latest { count, .. } if *count >= 10_000 => (
+                AnalyzeCost::Medium,
+                Some("`count` is so large that every candidate may be sorted"),
+            ),
+            Expr::Latest { candidates, .. } if candidates.is_unbounded_traversal() => (
+                AnalyzeCost::Medium,
+                Some("every candidate must be sorted by committer date"),
+            ),
             Expr::Intersection(exprs)
                 if exprs
                     .iter()
-                    .all(|expr| expr.cost(context) == AnalyzeCost::Slow) =>
+                    .all(|expr| cache.cost(expr, context) == AnalyzeCost::Slow) =>
             {
-                AnalyzeCost::Slow
+                (AnalyzeCost::Slow, Some("every member is expensive"))
             }
-            _ => AnalyzeCost::Fast,
+            // jj merges an intersection of filters into a single predicate, and
+            // since no member is a set which could narrow the candidates, every
+            // filter is checked against every ancestor
+            Expr::FilterWithin {
+                candidates,
+                predicate,
+            } if candidates.is_unbounded_traversal()
+                && let Predicate::Intersection(predicates) = predicate
+                && predicates.len() > 1
+                && predicates.iter().all(Predicate::is_filter_combination) =>
+            {
+                (
+                    AnalyzeCost::Medium,
+                    Some("there is no indexed base set to filter"),
+                )
+            }
+            // jj always evaluates the right side lazily, so this doesn't depend
+            // on `--literal-contexts`
+            Expr::Difference(expr1, expr2)
+                if cache.cost(expr1.as_ref(), context) == AnalyzeCost::Slow
+                    || cache.cost(expr2.as_ref(), context.eager_to_lazy()) == AnalyzeCost::Slow =>
+            {
+                (AnalyzeCost::Slow, Some("one of its operands is expensive"))
+            }
+            _ => (AnalyzeCost::Fast, None),
         }
     }
 }
 
-fn only_present(children: Vec<Option<Child>>) -> Vec<Child> {
-    children.into_iter().flatten().collect()
-}
-
 fn is_large_range(range: &Range<u64>) -> bool {
     range.end.saturating_sub(range.start) >= 10_000
 }
+
+/// Scores a range of generations proportionally to its span, so that a large
+/// range is scored like any other slow operation and unbounded ranges are
+/// scored higher.
+fn range_score(range: &Range<u64>) -> u64 {
+    (range.end.saturating_sub(range.start) / 10).clamp(1, 10 * AnalyzeCost::Slow.score())
+}
+
+#[cfg(test)]
+mod tests {
+    use jj_lib::backend::CommitId;
+
+    use super::*;
+    use crate::test_util::parse_expr;
+
+    fn cost(revset: &str, context: AnalyzeContext) -> AnalyzeCost {
+        CostCache::new().cost(&parse_expr(revset), context)
+    }
+
+    fn predicate(revset: &str) -> Predicate<'static> {
+        match parse_expr(revset) {
+            Expr::FilterWithin { predicate, .. } => predicate,
+            expr => panic!("{expr:?}"),
+        }
+    }
+
+    #[test]
+    fn descendants_are_only_renamed_without_raw_names() {
+        let expr = parse_expr("x::");
+        let options = FormatOptions::default();
+        assert_eq!(
+            expr.entry(AnalyzeContext::Lazy, &options).name,
+            "Descendants"
+        );
+        let options = FormatOptions {
+            raw_names: true,
+            ..options
+        };
+        assert_eq!(expr.entry(AnalyzeContext::Lazy, &options).name, "DagRange");
+    }
+
+    #[test]
+    fn double_negations_are_cancelled() {
+        assert!(matches!(
+            predicate("~~description(x)"),
+            Predicate::Filter(RevsetFilterPredicate::Description(_))
+        ));
+        // The cancelled union is flattened into the outer union
+        let Predicate::Union(members) =
+            predicate("x & (description(a) | ~~(author(b) | merges()))")
+        else {
+            panic!();
+        };
+        assert_eq!(members.len(), 4, "{members:?}");
+        assert!(
+            members
+                .iter()
+                .all(|member| matches!(member, Predicate::Filter(_)))
+        );
+        let Predicate::Intersection(members) =
+            predicate("x & description(a) & ~~(author(b) & merges())")
+        else {
+            panic!();
+        };
+        assert_eq!(members.len(), 3, "{members:?}");
+    }
+
+    #[test]
+    fn empty_children_make_parents_always_empty() {
+        let cache = CostCache::new();
+        let expr = parse_expr("heads(ancestors(x, 0)) | y");
+        let Expr::Union(members) = &expr else {
+            panic!("{expr:?}");
+        };
+        assert!(cache.is_always_empty(&members[0]), "{expr:?}");
+        assert!(!cache.is_always_empty(&members[1]));
+        assert!(!cache.is_always_empty(&expr));
+        let expr = parse_expr("heads(ancestors(x, 0)) | ancestors(y, 0)");
+        assert!(cache.is_always_empty(&expr), "{expr:?}");
+        // An explicit `none()` isn't reported, even though it's empty
+        assert!(!cache.is_always_empty(&parse_expr("none()")));
+    }
+
+    #[test]
+    fn intersection_of_filters_is_costly() {
+        let expr = parse_expr("description(a) & author(b)");
+        assert!(
+            matches!(
+                &expr,
+                Expr::FilterWithin { predicate, .. }
+                    if matches!(predicate, Predicate::Intersection(..))
+            ),
+            "{expr:?}"
+        );
+        assert_eq!(
+            cost("description(a) & author(b)", AnalyzeContext::Lazy),
+            AnalyzeCost::Medium
+        );
+        assert_eq!(
+            cost("description(a) & ~merges()", AnalyzeContext::Lazy),
+            AnalyzeCost::Medium
+        );
+        assert_eq!(
+            cost("description(a)", AnalyzeContext::Lazy),
+            AnalyzeCost::Fast
+        );
+        assert_eq!(
+            cost("x & description(a) & author(b)", AnalyzeContext::Lazy),
+            AnalyzeCost::Fast
+        );
+    }
+
+    #[test]
+    fn union_of_visible_heads_keeps_every_member() {
+        let mut reference_map = ReferenceMap::new();
+        let visible_heads = reference_map.insert(ResolvedReference::visible_heads());
+        let other = reference_map.insert(ResolvedReference::new_static("x"));
+        let commits = |commit_ids: &[&CommitId]| {
+            Box::new(ResolvedExpression::Commits(
+                commit_ids.iter().copied().cloned().collect(),
+            ))
+        };
+        let expr = Expr::parse(
+            ResolvedExpression::Union(
+                commits(&[&visible_heads]),
+                commits(&[&visible_heads, &other]),
+            ),
+            &reference_map,
+        );
+        let Expr::Union(members) = &expr else {
+            panic!("{expr:?}");
+        };
+        assert_eq!(members.len(), 2, "{expr:?}");
+        let expr = Expr::parse(
+            ResolvedExpression::Union(commits(&[]), commits(&[&other])),
+            &reference_map,
+        );
+        assert!(
+            matches!(&expr, Expr::Reference(reference) if reference.to_string() == "x"),
+            "{expr:?}"
+        );
+    }
+
+    #[test]
+    fn difference_from_root_is_root_or_none() {
+        let expr = parse_expr("root() ~ x");
+        assert!(matches!(expr, Expr::Difference(..)), "{expr:?}");
+        assert!(expr.is_root_or_none());
+        assert!(!parse_expr("x ~ root()").is_root_or_none());
+    }
+
+    #[test]
+    fn ancestors_of_difference_from_root_is_fast() {
+        let expr = parse_expr("::(root() ~ x)");
+        assert!(
+            matches!(&expr, Expr::Ancestors { heads, .. } if matches!(**heads, Expr::Difference(..))),
+            "{expr:?}"
+        );
+        assert_eq!(
+            cost("::(root() ~ x)", AnalyzeContext::Eager),
+            AnalyzeCost::Fast
+        );
+        assert_eq!(cost("::(y ~ x)", AnalyzeContext::Eager), AnalyzeCost::Slow);
+    }
+
+    #[test]
+    fn difference_with_expensive_candidates_is_slow() {
+        let expr = parse_expr("::x ~ y");
+        assert!(matches!(expr, Expr::Difference(..)), "{expr:?}");
+        assert_eq!(cost("::x ~ y", AnalyzeContext::Eager), AnalyzeCost::Slow);
+        assert_eq!(cost("::x ~ y", AnalyzeContext::Lazy), AnalyzeCost::Fast);
+    }
+
+    #[test]
+    fn difference_excluding_large_set_is_lazy() {
+        // The excluded set is only tested against the candidates, so `::large`
+        // isn't collected eagerly
+        let expr = parse_expr("x ~ ::large");
+        assert!(matches!(expr, Expr::Difference(..)), "{expr:?}");
+        assert_eq!(
+            cost("x ~ ::large", AnalyzeContext::Eager),
+            AnalyzeCost::Fast
+        );
+    }
+
+    #[test]
+    fn difference_excluding_expensive_filter_is_slow() {
+        let revset = r#"x ~ (::large & description(regex:"a"))"#;
+        let expr = parse_expr(revset);
+        assert!(matches!(expr, Expr::Difference(..)), "{expr:?}");
+        assert_eq!(cost(revset, AnalyzeContext::Eager), AnalyzeCost::Slow);
+        assert_eq!(cost(revset, AnalyzeContext::Lazy), AnalyzeCost::Slow);
+    }
+}
@@ -0,0 +1,694 @@
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io;
+
+use anyhow::Context as _;
+
+use crate::print::PrintOptions;
+use crate::print::pretty_print;
+use crate::tree::AnalyzeContext;
+use crate::tree::AnalyzeCost;
+use crate::tree::AnalyzeTree;
+use crate::tree::CostCache;
+use crate::tree::NodeNumbers;
+use crate::tree::WalkNode;
+use crate::tree::walk;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// An indented tree for reading in a terminal
+    Text,
+    /// Nested S-expressions, with labeled children as keyword arguments
+    Sexpr,
+    /// A self-contained HTML snippet with a nested list for each operation
+    Html,
+    /// A nested Markdown list for pasting into descriptions
+    Markdown,
+    /// A JSON document with a node for each operation
+    Json,
+    /// Comma-separated values with a row for each node
+    Csv,
+    /// A YAML document with a node for each operation
+    #[cfg(feature = "yaml")]
+    Yaml,
+    /// A binary Protocol Buffers message with a node for each operation
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl OutputFormat {
+    /// The extension used for files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Sexpr => "sexp",
+            Self::Html => "html",
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Csv => "csv",
+            #[cfg(feature = "yaml")]
+            Self::Yaml => "yaml",
+            #[cfg(feature = "protobuf")]
+            Self::Protobuf => "pb",
+        }
+    }
+}
+
+/// A node in the tree, in a form which can be serialized for other tools.
+#[derive(Debug, serde::Serialize)]
+pub struct SerializedNode {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<usize>,
+    pub name: String,
+    pub context: AnalyzeContext,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<AnalyzeCost>,
+    /// The reason the node may be expensive, if it was analyzed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost_reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<SerializedNode>,
+}
+
+impl SerializedNode {
+    pub fn new(
+        tree: &dyn AnalyzeTree,
+        context: AnalyzeContext,
+        mut label: Option<String>,
+        options: &PrintOptions,
+        cache: &CostCache,
+        numbers: Option<&NodeNumbers>,
+    ) -> Self {
+        build_nodes(
+            tree,
+            context,
+            options,
+            |node| Self {
+                label: match node.depth {
+                    0 => label.take(),
+                    _ => node.label.as_ref().map(|label| label.to_string()),
+                },
+                number: numbers.and_then(|numbers| numbers.get(node.tree, node.context)),
+                name: node.entry.name.to_string(),
+                context: node.entry.context,
+                cost: options.analyze.then(|| cache.cost(node.tree, node.context)),
+                cost_reason: options
+                    .analyze
+                    .then(|| node.tree.cost_reason(node.context, cache))
+                    .flatten()
+                    .map(|reason| reason.into_owned()),
+                children: vec![],
+            },
+            |node| &mut node.children,
+        )
+    }
+}
+
+/// Builds a node for each node in the tree using `new_node`, without recursing
+/// once per level. `children` returns the children of a node, which each node
+/// is added to once all of its own children have been added.
+fn build_nodes<N>(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    mut new_node: impl FnMut(&WalkNode) -> N,
+    children: fn(&mut N) -> &mut Vec<N>,
+) -> N {
+    // The nodes which are still missing children, where the node at each
+    // index has a depth equal to that index
+    let mut open: Vec<N> = Vec::new();
+    // Adds each node deeper than `depth` to its parent
+    let finish = |open: &mut Vec<N>, depth: usize| {
+        while open.len() > depth {
+            let node = open.pop().unwrap();
+            children(open.last_mut().unwrap()).push(node);
+        }
+    };
+    walk(tree, context, &options.format, &mut |node| {
+        finish(&mut open, node.depth.max(1));
+        open.push(new_node(node));
+    });
+    finish(&mut open, 1);
+    open.pop().unwrap()
+}
+
+/// A node in the tree as a Protocol Buffers message, with the same fields as
+/// [`SerializedNode`]. The schema is:
+///
+/// ```proto
+/// message Node {
+///   optional string label = 1;
+///   optional uint64 number = 2;
+///   string name = 3;
+///   string context = 4;
+///   optional string cost = 5;
+///   optional string cost_reason = 6;
+///   repeated Node children = 7;
+/// }
+/// ```
+#[cfg(feature = "protobuf")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProtobufNode {
+    #[prost(string, optional, tag = "1")]
+    pub label: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub number: Option<u64>,
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(string, tag = "4")]
+    pub context: String,
+    #[prost(string, optional, tag = "5")]
+    pub cost: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub cost_reason: Option<String>,
+    #[prost(message, repeated, tag = "7")]
+    pub children: Vec<ProtobufNode>,
+}
+
+#[cfg(feature = "protobuf")]
+impl ProtobufNode {
+    pub fn new(
+        tree: &dyn AnalyzeTree,
+        context: AnalyzeContext,
+        options: &PrintOptions,
+        cache: &CostCache,
+        numbers: Option<&NodeNumbers>,
+    ) -> Self {
+        build_nodes(
+            tree,
+            context,
+            options,
+            |node| Self {
+                label: node.label.as_ref().map(|label| label.to_string()),
+                number: numbers
+                    .and_then(|numbers| numbers.get(node.tree, node.context))
+                    .map(|number| number as u64),
+                name: node.entry.name.to_string(),
+                context: node.entry.context.to_string(),
+                cost: (options.analyze).then(|| cache.cost(node.tree, node.context).to_string()),
+                cost_reason: options
+                    .analyze
+                    .then(|| node.tree.cost_reason(node.context, cache))
+                    .flatten()
+                    .map(|reason| reason.into_owned()),
+                children: vec![],
+            },
+            |node| &mut node.children,
+        )
+    }
+}
+
+/// Writes the tree to `out` in the given format.
+pub fn print_formatted(
+    out: &mut dyn io::Write,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let numbers = (options.number && format != OutputFormat::Text)
+        .then(|| NodeNumbers::new(tree, context, &options.format));
+    let numbers = numbers.as_ref();
+    match format {
+        OutputFormat::Text => pretty_print(out, tree, context, options)?,
+        OutputFormat::Sexpr => {
+            let mut output = String::new();
+            write_sexpr(&mut output, tree, context, options, numbers);
+            writeln!(out, "{output}")?;
+        }
+        OutputFormat::Html => {
+            let mut output = String::new();
+            write_html(&mut output, tree, context, options, numbers);
+            write!(out, "{output}")?;
+        }
+        OutputFormat::Markdown => {
+            let mut output = String::new();
+            write_markdown(&mut output, tree, context, options, numbers);
+            write!(out, "{output}")?;
+        }
+        OutputFormat::Json => {
+            let cache = CostCache::for_tree(tree, context, &options.format);
+            let node = SerializedNode::new(tree, context, None, options, &cache, numbers);
+            let json = if options.json_pretty {
+                serde_json::to_string_pretty(&node)
+            } else {
+                serde_json::to_string(&node)
+            }
+            .context("Failed to serialize tree as JSON")?;
+            writeln!(out, "{json}")?;
+        }
+        OutputFormat::Csv => {
+            let mut output = String::new();
+            write_csv(&mut output, tree, context, options, numbers);
+            write!(out, "{output}")?;
+        }
+        #[cfg(feature = "yaml")]
+        OutputFormat::Yaml => {
+            let cache = CostCache::for_tree(tree, context, &options.format);
+            let node = SerializedNode::new(tree, context, None, options, &cache, numbers);
+            let yaml = serde_yaml::to_string(&node).context("Failed to serialize tree as YAML")?;
+            write!(out, "{yaml}")?;
+        }
+        #[cfg(feature = "protobuf")]
+        OutputFormat::Protobuf => {
+            use prost::Message as _;
+            let cache = CostCache::for_tree(tree, context, &options.format);
+            let node = ProtobufNode::new(tree, context, options, &cache, numbers);
+            out.write_all(&node.encode_to_vec())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the tree as an S-expression like `(Union (Ancestors :heads "@")
+/// "root()")`. Names of leaves are written as strings, since they may contain
+/// any character. Numbered nodes use the `#3=` datum label syntax.
+fn write_sexpr(
+    output: &mut String,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    numbers: Option<&NodeNumbers>,
+) {
+    // The depth of each node which hasn't been closed yet
+    let mut open = Vec::new();
+    walk(tree, context, &options.format, &mut |node| {
+        while let Some(&depth) = open.last()
+            && depth >= node.depth
+        {
+            open.pop();
+            output.push(')');
+        }
+        if node.depth > 0 {
+            output.push(' ');
+        }
+        if let Some(label) = &node.label {
+            output.push(':');
+            output.push_str(label);
+            output.push(' ');
+        }
+        if let Some(number) = numbers.and_then(|numbers| numbers.get(node.tree, node.context)) {
+            write!(output, "#{number}=").unwrap();
+        }
+        if node.entry.children.is_empty() {
+            write!(output, "\"{}\"", sexpr_escape(&node.entry.name)).unwrap();
+        } else {
+            output.push('(');
+            output.push_str(&node.entry.name);
+            open.push(node.depth);
+        }
+    });
+    output.push_str(&")".repeat(open.len()));
+}
+
+// Styles are inlined by default so that the snippet can be pasted anywhere
+// without any CSS
+const HTML_LIST_STYLE: &str = "list-style: none; padding-left: 2ch; font-family: monospace";
+const HTML_LABEL_STYLE: &str = "color: gray";
+const HTML_EXPENSIVE_STYLE: &str =
+    "color: white; background: #d33; font-weight: bold; padding: 0 0.5ch; border-radius: 3px";
+const HTML_COSTLY_STYLE: &str =
+    "color: black; background: #eb0; font-weight: bold; padding: 0 0.5ch; border-radius: 3px";
+const HTML_OPERATION_STYLE: &str = "font-weight: bold";
+// The color of each class of names, using the same colors as the text output
+const HTML_NAME_COLORS: [(&str, &str); 4] = [
+    ("eager", "#36c"),
+    ("lazy", "#099"),
+    ("predicate", "#b3b"),
+    ("unresolved", "#36c"),
+];
+
+/// Writes a `<style>` block defining the classes used with `--html-classes`.
+fn write_html_style(output: &mut String) {
+    output.push_str("<style>\n");
+    writeln!(output, "  ul.jj-analyze {{ {HTML_LIST_STYLE} }}").unwrap();
+    writeln!(output, "  .jj-analyze .label {{ {HTML_LABEL_STYLE} }}").unwrap();
+    writeln!(
+        output,
+        "  .jj-analyze .expensive {{ {HTML_EXPENSIVE_STYLE} }}"
+    )
+    .unwrap();
+    writeln!(output, "  .jj-analyze .costly {{ {HTML_COSTLY_STYLE} }}").unwrap();
+    writeln!(
+        output,
+        "  .jj-analyze .operation {{ {HTML_OPERATION_STYLE} }}"
+    )
+    .unwrap();
+    for (class, color) in HTML_NAME_COLORS {
+        writeln!(output, "  .jj-analyze .{class} {{ color: {color} }}").unwrap();
+    }
+    output.push_str("</style>\n");
+}
+
+/// Returns the `class` or `style` attribute for an element, depending on
+/// whether `--html-classes` was passed.
+fn html_attribute(options: &PrintOptions, classes: &[&str], style: &str) -> String {
+    if options.html_classes {
+        format!("class=\"{}\"", classes.join(" "))
+    } else {
+        format!("style=\"{style}\"")
+    }
+}
+
+/// Writes the tree as nested `<ul>` elements, using the same colors as the
+/// text output.
+fn write_html(
+    output: &mut String,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    numbers: Option<&NodeNumbers>,
+) {
+    let cache = CostCache::for_tree(tree, context, &options.format);
+    let list = html_attribute(options, &["jj-analyze"], HTML_LIST_STYLE);
+    if options.html_classes {
+        write_html_style(output);
+    }
+    writeln!(output, "<ul {list}>").unwrap();
+    // The depth of each node whose list of children hasn't been closed yet
+    let mut open = Vec::new();
+    walk(tree, context, &options.format, &mut |node| {
+        while let Some(&depth) = open.last()
+            && depth >= node.depth
+        {
+            open.pop();
+            close_html_list(output, depth);
+        }
+        let indent = "    ".repeat(node.depth);
+        write!(output, "{indent}  <li>").unwrap();
+        if let Some(label) = &node.label {
+            write!(
+                output,
+                "<span {}>{}:</span> ",
+                html_attribute(options, &["label"], HTML_LABEL_STYLE),
+                html_escape(label)
+            )
+            .unwrap();
+        }
+        if let Some(number) = numbers.and_then(|numbers| numbers.get(node.tree, node.context)) {
+            write!(
+                output,
+                "<span {}>#{number}</span> ",
+                html_attribute(options, &["label"], HTML_LABEL_STYLE)
+            )
+            .unwrap();
+        }
+        let cost = options.analyze.then(|| cache.cost(node.tree, node.context));
+        if let Some(cost) = cost
+            && let Some(label) = cost.label()
+        {
+            let (class, style) = match cost {
+                AnalyzeCost::Slow => ("expensive", HTML_EXPENSIVE_STYLE),
+                _ => ("costly", HTML_COSTLY_STYLE),
+            };
+            write!(
+                output,
+                "<span {}>{label}</span> ",
+                html_attribute(options, &[class], style)
+            )
+            .unwrap();
+        }
+        let class = if options.structure_only {
+            None
+        } else if options.analyze {
+            match node.entry.context {
+                AnalyzeContext::Eager => Some("eager"),
+                AnalyzeContext::Lazy => Some("lazy"),
+                AnalyzeContext::Predicate => Some("predicate"),
+                AnalyzeContext::Resolved => None,
+            }
+        } else if node.entry.context != AnalyzeContext::Resolved {
+            Some("unresolved")
+        } else {
+            None
+        };
+        let mut classes = Vec::from_iter(class);
+        let mut styles = Vec::from_iter(class.and_then(|class| {
+            HTML_NAME_COLORS
+                .iter()
+                .find(|(name, _)| *name == class)
+                .map(|(_, color)| format!("color: {color}"))
+        }));
+        if !node.entry.children.is_empty() {
+            classes.push("operation");
+            styles.push(HTML_OPERATION_STYLE.to_owned());
+        }
+        if classes.is_empty() {
+            output.push_str(&html_escape(&node.entry.name));
+        } else {
+            write!(
+                output,
+                "<span {}>{}</span>",
+                html_attribute(options, &classes, &styles.join("; ")),
+                html_escape(&node.entry.name)
+            )
+            .unwrap();
+        }
+        if node.entry.children.is_empty() {
+            output.push_str("</li>\n");
+        } else {
+            writeln!(output, "\n{indent}    <ul {list}>").unwrap();
+            open.push(node.depth);
+        }
+    });
+    for depth in open.into_iter().rev() {
+        close_html_list(output, depth);
+    }
+    output.push_str("</ul>\n");
+}
+
+fn close_html_list(output: &mut String, depth: usize) {
+    let indent = "    ".repeat(depth);
+    writeln!(output, "{indent}    </ul>\n{indent}  </li>").unwrap();
+}
+
+/// Writes the tree as a nested Markdown list, with a warning sign before each
+/// expensive node.
+fn write_markdown(
+    output: &mut String,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    numbers: Option<&NodeNumbers>,
+) {
+    let cache = CostCache::for_tree(tree, context, &options.format);
+    walk(tree, context, &options.format, &mut |node| {
+        write!(output, "{}- ", "  ".repeat(node.depth)).unwrap();
+        if let Some(label) = &node.label {
+            write!(output, "**{}:** ", markdown_escape(label)).unwrap();
+        }
+        if let Some(number) = numbers.and_then(|numbers| numbers.get(node.tree, node.context)) {
+            write!(output, "\\#{number} ").unwrap();
+        }
+        if options.analyze
+            && let Some(label) = cache.cost(node.tree, node.context).label()
+        {
+            write!(output, "⚠️ **{label}** ").unwrap();
+        }
+        output.push_str(&markdown_escape(&node.entry.name));
+        output.push('\n');
+    });
+}
+
+/// Writes a header row followed by a row for each node. The path of the root
+/// node is `.`, and the cost is empty if analysis is disabled.
+fn write_csv(
+    output: &mut String,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+    numbers: Option<&NodeNumbers>,
+) {
+    if numbers.is_some() {
+        output.push_str("number,");
+    }
+    output.push_str("depth,path,name,context,cost\n");
+    let cache = CostCache::for_tree(tree, context, &options.format);
+    walk(tree, context, &options.format, &mut |node| {
+        if let Some(numbers) = numbers {
+            let number = numbers.get(node.tree, node.context);
+            write!(
+                output,
+                "{},",
+                number.map(|number| number.to_string()).unwrap_or_default()
+            )
+            .unwrap();
+        }
+        let path = if node.path.is_empty() { "." } else { node.path };
+        let cost = if options.analyze {
+            cache.cost(node.tree, node.context).to_string()
+        } else {
+            String::new()
+        };
+        writeln!(
+            output,
+            "{},{},{},{},{cost}",
+            node.depth,
+            csv_escape(path),
+            csv_escape(&node.entry.name),
+            node.entry.context,
+        )
+        .unwrap();
+    });
+}
+
+/// Quotes a field if it contains a comma, quote, or line break.
+fn csv_escape(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\"")).into()
+    } else {
+        text.into()
+    }
+}
+
+fn sexpr_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn markdown_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|' | '~'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use jj_lib::revset::ResolvedExpression;
+
+    use super::*;
+    use crate::expr::Expr;
+    use crate::parse::ReferenceMap;
+    use crate::test_util::parse_expr;
+
+    fn format(revset: &str, format: OutputFormat) -> String {
+        let mut output = Vec::new();
+        let options = PrintOptions {
+            analyze: true,
+            ..PrintOptions::default()
+        };
+        print_formatted(
+            &mut output,
+            &parse_expr(revset),
+            AnalyzeContext::Lazy,
+            &options,
+            format,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn sexpr_quotes_names() {
+        assert_eq!(
+            format(r#"x & description(exact:'a "b" \ c')"#, OutputFormat::Sexpr),
+            concat!(
+                r#"(FilterWithin :candidates "x" "#,
+                r#":predicate "description(exact:\"a \\\"b\\\" \\\\ c\")")"#,
+                "\n",
+            ),
+        );
+    }
+
+    #[test]
+    fn costly_and_expensive_nodes_are_labeled_in_every_format() {
+        let revset = "description(regex:a) | ::x & description(regex:b)";
+        let markdown = format(revset, OutputFormat::Markdown);
+        assert!(markdown.contains("⚠️ **EXPENSIVE** FilterWithin"));
+        assert!(markdown.contains("⚠️ **COSTLY** description(regex:\"b\")"));
+        let html = format(revset, OutputFormat::Html);
+        assert!(html.contains(">EXPENSIVE</span>"));
+        assert!(html.contains(">COSTLY</span>"));
+    }
+
+    #[test]
+    fn expensive_nodes_have_cost_reason_in_json() {
+        let json = format("all() & description(regex:x)", OutputFormat::Json);
+        let node: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(node["name"], "FilterWithin");
+        assert_eq!(node["cost"], "slow");
+        assert!(
+            node["cost_reason"]
+                .as_str()
+                .unwrap()
+                .contains("every ancestor")
+        );
+        // Nodes which aren't expensive have no reason
+        assert_eq!(node["children"][0]["cost"], "fast");
+        assert!(node["children"][0].get("cost_reason").is_none());
+    }
+
+    #[test]
+    fn deeply_nested_tree_does_not_overflow() {
+        // Run with a small stack, so that recursing once per level would fail
+        std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(|| {
+                let mut backend_expr = ResolvedExpression::Commits(vec![]);
+                for _ in 0..1_000 {
+                    backend_expr = ResolvedExpression::Difference(
+                        Box::new(backend_expr),
+                        Box::new(ResolvedExpression::Commits(vec![])),
+                    );
+                }
+                let reference_map = ReferenceMap::new();
+                let expr = Expr::parse(backend_expr, &reference_map);
+                let options = PrintOptions {
+                    analyze: true,
+                    number: true,
+                    ..PrintOptions::default()
+                };
+                let cache = CostCache::new();
+                assert_eq!(cache.cost(&expr, AnalyzeContext::Eager), AnalyzeCost::Fast);
+                assert!(cache.is_always_empty(&expr));
+                for format in [
+                    OutputFormat::Text,
+                    OutputFormat::Sexpr,
+                    OutputFormat::Html,
+                    OutputFormat::Markdown,
+                    OutputFormat::Csv,
+                ] {
+                    let mut output = Vec::new();
+                    print_formatted(&mut output, &expr, AnalyzeContext::Lazy, &options, format)
+                        .unwrap();
+                }
+                // Serializers like `serde_json` recurse themselves, so only the
+                // conversion into serialized nodes is tested
+                SerializedNode::new(
+                    &expr,
+                    AnalyzeContext::Lazy,
+                    None,
+                    &options,
+                    &CostCache::new(),
+                    None,
+                );
+                #[cfg(feature = "protobuf")]
+                ProtobufNode::new(
+                    &expr,
+                    AnalyzeContext::Lazy,
+                    &options,
+                    &CostCache::new(),
+                    None,
+                );
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
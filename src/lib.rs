@@ -0,0 +1,22 @@
+//! Analyze a revset and build a tree showing how it will be evaluated.
+//!
+//! A revset is parsed into an [`Expr`](expr::Expr) using [`parse::parse`].
+//! Every node in the tree implements [`AnalyzeTree`](tree::AnalyzeTree), which
+//! describes how the node is displayed, how it is evaluated, and how expensive
+//! it may be.
+//!
+//! To compute custom metrics over a tree without reimplementing traversal, use
+//! [`walk`](tree::walk), which visits every node in the same depth-first order
+//! used when printing the tree.
+
+pub mod explain;
+pub mod expr;
+pub mod format;
+pub mod normalize;
+pub mod parse;
+pub mod print;
+pub mod repo;
+#[cfg(test)]
+mod test_util;
+pub mod tree;
+pub mod user_tree;
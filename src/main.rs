@@ -1,16 +1,74 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
+use std::error;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::IsTerminal as _;
+use std::io::Write;
+use std::iter;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
 use chrono::TimeZone as _;
 use clap::CommandFactory;
 use clap::Parser as _;
+use clap::ValueEnum as _;
+use clap::builder::PossibleValuesParser;
 use clap::builder::Styles;
+use clap::builder::TypedValueParser as _;
 use clap::builder::styling::AnsiColor;
+use clap::error::ErrorKind;
 use clap::{self};
 use clap_complete::CompleteEnv;
+use clap_complete::engine::ArgValueCompleter;
+use clap_complete::engine::CompletionCandidate;
+use colored::Colorize as _;
+use itertools::Itertools as _;
+use jj_analyze::explain::CostReport;
+use jj_analyze::explain::describe_operations;
+use jj_analyze::explain::explain_node;
+use jj_analyze::explain::list_references;
+use jj_analyze::explain::print_expensive_nodes;
+use jj_analyze::explain::removed_by_optimization;
+use jj_analyze::expr::Expr;
+use jj_analyze::format::OutputFormat;
+use jj_analyze::format::print_formatted;
+use jj_analyze::normalize::normalize;
+use jj_analyze::parse;
+use jj_analyze::parse::ReferenceMap;
+use jj_analyze::parse::Timings;
+use jj_analyze::print::DateFormat;
+use jj_analyze::print::FormatOptions;
+use jj_analyze::print::IndentStyle;
+use jj_analyze::print::PrintOptions;
+use jj_analyze::print::Theme;
+use jj_analyze::print::strip_ansi;
+use jj_analyze::repo::check_operations;
+use jj_analyze::repo::count_revisions;
+use jj_analyze::repo::has_at_operation;
+use jj_analyze::repo::has_id_prefix;
+use jj_analyze::repo::id_prefix_warnings;
+use jj_analyze::repo::load_repo;
+use jj_analyze::repo::measured_cost;
+use jj_analyze::repo::validate_references;
+use jj_analyze::tree::AnalyzeContext;
+use jj_analyze::tree::AnalyzeCost;
+use jj_analyze::tree::ContextOverrides;
+use jj_analyze::tree::CostCache;
+use jj_analyze::tree::canonical_path;
+use jj_analyze::tree::count_nodes;
+use jj_analyze::tree::resolve_node_number;
+use jj_analyze::tree::total_cost_score;
 use jj_cli::cli_util::find_workspace_dir;
 use jj_cli::config::ConfigEnv;
 use jj_cli::config::config_from_environment;
@@ -18,24 +76,26 @@ use jj_cli::config::default_config_layers;
 use jj_cli::config::default_config_migrations;
 use jj_cli::revset_util;
 use jj_cli::ui::Ui;
+use jj_lib::config::ConfigGetResultExt as _;
+use jj_lib::config::ConfigItem;
+use jj_lib::config::ConfigLayer;
+use jj_lib::config::ConfigSource;
 use jj_lib::ref_name::WorkspaceName;
+use jj_lib::repo::ReadonlyRepo;
 use jj_lib::repo_path::RepoPathUiConverter;
 use jj_lib::revset::RevsetAliasesMap;
-use jj_lib::revset::RevsetExtensions;
+use jj_lib::revset::RevsetDiagnostics;
 use jj_lib::revset::RevsetParseContext;
 use jj_lib::revset::RevsetWorkspaceContext;
 use jj_lib::settings::UserSettings;
 use jj_lib::workspace::DefaultWorkspaceLoaderFactory;
 use jj_lib::workspace::WorkspaceLoaderFactory as _;
-
-use crate::parse::ReferenceMap;
-use crate::print::pretty_print;
-use crate::tree::AnalyzeContext;
-
-mod expr;
-mod parse;
-mod print;
-mod tree;
+use notify::EventKind;
+use notify::RecursiveMode;
+use notify::Watcher as _;
+use regex::Regex;
+use terminal_size::Height;
+use terminal_size::Width;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
 enum ColorMode {
@@ -44,6 +104,42 @@ enum ColorMode {
     Always,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum CostReportFormat {
+    Json,
+}
+
+/// A jj command which evaluates a revset, for `--context-from-command`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+enum JjCommand {
+    /// `jj log`, which streams revisions as they are found
+    Log,
+    /// `jj show`, which only needs a single revision
+    Show,
+    /// `jj new`, which only needs a few revisions
+    New,
+    /// `jj rebase`, which collects every revision before rebasing
+    Rebase,
+    /// `jj abandon`, which collects every revision before abandoning
+    Abandon,
+    /// `jj describe`, which collects every revision before describing
+    Describe,
+    /// `jj duplicate`, which collects every revision before duplicating
+    Duplicate,
+}
+
+impl JjCommand {
+    /// The context which the command evaluates its revset in.
+    fn context(self) -> AnalyzeContext {
+        match self {
+            Self::Log | Self::Show | Self::New => AnalyzeContext::Lazy,
+            Self::Rebase | Self::Abandon | Self::Describe | Self::Duplicate => {
+                AnalyzeContext::Eager
+            }
+        }
+    }
+}
+
 const STYLES: Styles = Styles::styled()
     .header(AnsiColor::Yellow.on_default().bold())
     .usage(AnsiColor::Yellow.on_default().bold())
@@ -52,10 +148,12 @@ const STYLES: Styles = Styles::styled()
 
 /// Analyze a revset and display a tree showing how it will be evaluated
 ///
-/// Potentially expensive operations are indicated with an `(EXPENSIVE)` label.
-/// When color is enabled, operations are also colored based on how they are
+/// Potentially expensive operations are indicated with an `(EXPENSIVE)` label,
+/// or a `(COSTLY)` label if they are only moderately expensive. When color is
+/// enabled, operations are also colored based on how they are
 /// evaluated. Eager evaluation is indicated by blue, lazy evaluation is
-/// indicated by cyan, and predicates are indicated by magenta.
+/// indicated by cyan, and predicates are indicated by magenta. Other palettes
+/// can be selected using `--theme`.
 ///
 /// This tool attempts to match the default index implementation's revset engine
 /// as well as possible. If you use a custom build of `jj` which uses a
@@ -63,16 +161,28 @@ const STYLES: Styles = Styles::styled()
 ///
 /// To make the output easier to read, nested union, intersection, and coalesce
 /// operations are flattened.
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Clone, Debug)]
 #[command(version, about, styles = STYLES)]
-#[command(group(clap::ArgGroup::new("revset").required(true)))]
+#[command(group(clap::ArgGroup::new("revset")))]
 struct Args {
     /// A revset to analyze
-    #[arg(group = "revset", value_name = "REVSET")]
+    ///
+    /// If no revset is provided, `revsets.log` is loaded from config.
+    #[arg(
+        group = "revset",
+        value_name = "REVSET",
+        add = ArgValueCompleter::new(complete_revset)
+    )]
     revset_pos: Option<String>,
 
     // Hidden `-r` flag
-    #[arg(short = 'r', group = "revset", hide = true, value_name = "REVSET")]
+    #[arg(
+        short = 'r',
+        group = "revset",
+        hide = true,
+        value_name = "REVSET",
+        add = ArgValueCompleter::new(complete_revset)
+    )]
     revset_opt: Option<String>,
 
     /// Load a revset from the `[revsets]` config section
@@ -81,25 +191,482 @@ struct Args {
     #[arg(long = "from-config", group = "revset", value_name = "KEY")]
     revset_from_config: Option<String>,
 
+    /// Read the revset from a file
+    ///
+    /// Lines starting with `#` are treated as comments and ignored, so the
+    /// revset can be documented in the file.
+    #[arg(long, group = "revset", value_name = "PATH")]
+    input_file: Option<PathBuf>,
+
+    /// Compare the predicted cost of revsets to how long they actually take
+    ///
+    /// The file should contain one revset per line, and lines starting with
+    /// `#` are ignored. Each revset is evaluated against the repository, and a
+    /// table is printed showing which predictions were wrong. Evaluation under
+    /// 10ms is considered fast, and evaluation over 100ms is considered slow.
+    #[arg(long, group = "revset", value_name = "PATH", conflicts_with = "watch")]
+    bench: Option<PathBuf>,
+
+    /// Analyze the definition of a revset alias
+    ///
+    /// For instance, pass `--alias 'immutable_heads()'` to analyze its
+    /// definition. The alias itself is never collapsed.
+    #[arg(long, group = "revset", value_name = "ALIAS")]
+    alias: Option<String>,
+
     /// Base context for evaluation of revset
     ///
     /// For instance, if the entire revset will be iterated over, using
     /// `--context eager` may give more accurate analysis results. By default,
-    /// lazy evaluation of the base revset is assumed.
-    #[arg(short, long, default_value_t = AnalyzeContext::Lazy)]
+    /// lazy evaluation of the base revset is assumed. Passing `--context
+    /// resolved` styles every node the same way, showing only the structure of
+    /// the revset, while costs are still analyzed in the lazy context.
+    #[arg(
+        short,
+        long,
+        default_value = "lazy",
+        value_parser = PossibleValuesParser::new(["eager", "lazy", "predicate", "resolved"])
+            .map(|context| match context.as_str() {
+                "resolved" => AnalyzeContext::Resolved,
+                context => AnalyzeContext::from_str(context, false).unwrap(),
+            })
+    )]
     context: AnalyzeContext,
 
+    /// Whether `--context resolved` was passed
+    #[arg(skip)]
+    structure_only: bool,
+
+    /// Use the context that a jj command evaluates the revset in
+    ///
+    /// For instance, `jj rebase` collects every revision before rebasing, so
+    /// `--context-from-command rebase` is the same as `--context eager`.
+    #[arg(long, value_name = "COMMAND", conflicts_with = "context")]
+    context_from_command: Option<JjCommand>,
+
+    /// Context for evaluation of the candidates of filters
+    ///
+    /// By default, the candidates of `FilterWithin` use the same context as the
+    /// filter itself. For instance, if only the candidates will be fully
+    /// iterated over, pass `--filter-context eager`.
+    #[arg(long, value_name = "CONTEXT")]
+    filter_context: Option<AnalyzeContext>,
+
+    /// Pass the context of each operation to its children without adjusting it
+    ///
+    /// By default, some operations evaluate their children lazily even if they
+    /// are used eagerly or as a predicate, since that is how jj evaluates them.
+    /// With this flag, the children are colored and analyzed in the context
+    /// exactly as it is propagated instead. The cost of each operation itself
+    /// is still estimated from how jj evaluates its children.
+    #[arg(long)]
+    literal_contexts: bool,
+
+    /// Evaluate the node at a path in a different context
+    ///
+    /// The node is selected using the same paths as `--explain-node`, such as
+    /// `--select-context 0.heads=eager`. The colors and costs of the node and
+    /// its children are analyzed using the new context. This can be repeated
+    /// to override the context of several nodes.
+    #[arg(long, value_name = "PATH=CONTEXT", value_parser = parse_context_override)]
+    select_context: Vec<(String, AnalyzeContext)>,
+
+    /// Display ranges of generations as depths, such as `depth 1`
+    ///
+    /// A depth of 0 is the revision itself, and a depth of 1 is its parents or
+    /// children (for descendants). Ranges of parent indices are also displayed
+    /// as phrases, such as `first parent only`.
+    #[arg(long)]
+    generation_as_depth: bool,
+
+    /// Label counts with phrases, such as `exactly` or `most_recent`
+    ///
+    /// For instance, `latest(x, 5)` is displayed with `most_recent: 5` instead
+    /// of `count: 5`, and `exactly(x, 2)` with `exactly: 2`.
+    #[arg(long)]
+    describe_counts: bool,
+
+    /// Sort the members of unions and intersections by name
+    ///
+    /// The order chosen by the optimizer may change between versions of jj, so
+    /// this keeps the output stable when it is compared against a file.
+    #[arg(long)]
+    sort_members: bool,
+
+    /// Also sort the members of `coalesce()` by name
+    ///
+    /// This changes the meaning of the revset, since the first member which
+    /// isn't empty is used, so a warning is printed.
+    #[arg(long, requires = "sort_members")]
+    sort_coalesce: bool,
+
+    /// Describe a single node instead of printing the tree
+    ///
+    /// The node is selected using a dotted path such as `0.heads.1`, where
+    /// each segment is either the label of a child or the index of a child.
+    /// A node can also be selected by its number from `--number`, such as `#3`.
+    #[arg(long, value_name = "PATH")]
+    explain_node: Option<String>,
+
+    /// Describe every operation which can appear in the tree and exit
+    #[arg(long, alias = "describe-functions")]
+    describe_operations: bool,
+
+    /// Print the raw backend expression instead of the analyzed tree
+    ///
+    /// This shows the expression exactly as it is passed to the revset engine,
+    /// before any flattening or renaming. Commit IDs in the output refer to
+    /// the references listed below it.
+    #[arg(long)]
+    raw: bool,
+
+    /// List every reference alongside `visible_heads()`
+    ///
+    /// By default, `visible_heads()` combined with the revisions referenced by
+    /// the revset is displayed as `visible_heads() and referenced revisions`.
+    /// With this flag, each of the referenced revisions is displayed instead.
+    #[arg(long)]
+    expand_visible_heads: bool,
+
+    /// Annotate references with the alias they were expanded from
+    ///
+    /// For instance, a bookmark from the definition of `trunk()` is displayed
+    /// as `main [from trunk()]`. Only the innermost alias is shown. This only
+    /// affects `--format text`.
+    #[arg(long, conflicts_with = "user_tree")]
+    alias_origins: bool,
+
+    /// Display combinations of filters as they were written
+    ///
+    /// jj's optimizer can't be partially disabled, so filters such as
+    /// `description(x) & ~merges()` are hidden from it and restored afterward.
+    /// Other parts of the revset are still optimized, but filters may be
+    /// evaluated separately instead of as one predicate.
+    #[arg(long, conflicts_with = "no_optimize")]
+    no_optimize_filters: bool,
+
+    /// Print the revset as written instead of the analyzed tree
+    ///
+    /// This shows the revset expression after aliases are expanded, but before
+    /// it is resolved or optimized. Nested operations are not flattened.
+    #[arg(long)]
+    user_tree: bool,
+
+    /// Join chains of operations with a single child onto one line
+    ///
+    /// For instance, `Heads(Roots(...))` is printed as `Heads → Roots → ...`.
+    #[arg(long, overrides_with = "no_compact")]
+    compact: bool,
+
+    /// Don't join chains of operations onto one line
+    ///
+    /// This overrides `compact = true` in the `[jj-analyze]` config table.
+    #[arg(long, overrides_with = "compact")]
+    no_compact: bool,
+
+    /// Hide children which have their default value
+    ///
+    /// For instance, the `count` of `latest(x)` is hidden since it is 1, and
+    /// the `roots` of `HeadsRange` are hidden if they are `none()`.
+    #[arg(long, overrides_with = "no_compact_empty")]
+    compact_empty: bool,
+
+    /// Show children which have their default value
+    ///
+    /// This overrides `compact-empty = true` in the `[jj-analyze]` config
+    /// table.
+    #[arg(long, overrides_with = "compact_empty")]
+    no_compact_empty: bool,
+
+    /// Display dates in the local timezone instead of UTC
+    #[arg(long)]
+    date_local: bool,
+
+    /// Display dates relative to the current time, such as "2 days ago"
+    #[arg(long, conflicts_with = "date_local")]
+    date_relative: bool,
+
+    /// Display long string patterns in full instead of truncating them
+    #[arg(long, overrides_with = "no_full")]
+    full: bool,
+
+    /// Truncate long string patterns
+    ///
+    /// This overrides `full = true` in the `[jj-analyze]` config table.
+    #[arg(long, overrides_with = "full")]
+    no_full: bool,
+
+    /// Wrap names of nodes which are longer than this width
+    ///
+    /// Wrapped names continue on the next line, aligned with the start of the
+    /// name. Defaults to the width of the terminal. Use `--max-width 0` to
+    /// never wrap names.
+    #[arg(long, value_name = "N")]
+    max_width: Option<usize>,
+
+    /// Colors and styles to use for the tree
+    ///
+    /// Defaults to `default`, or to `jj-analyze.theme` from config.
+    #[arg(long, value_name = "THEME", value_enum)]
+    theme: Option<Theme>,
+
+    /// Number of columns to indent each level of the tree by
+    ///
+    /// Defaults to 2. This only affects `--format text`.
+    #[arg(long, value_name = "N")]
+    indent_width: Option<usize>,
+
+    /// Draw a vertical guide in this color at the start of each level
+    ///
+    /// Colors are named like `blue` or `bright black`. This only affects
+    /// `--format text`.
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    indent_guides_color: Option<colored::Color>,
+
+    /// Format to print the tree in
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Use CSS classes instead of inline styles with `--format html`
+    ///
+    /// A `<style>` block defining the classes is printed before the tree.
+    #[arg(long)]
+    html_classes: bool,
+
+    /// Indent JSON output from `--format json` and `--cost-report json`
+    ///
+    /// By default, JSON is printed on a single line so that it can be piped
+    /// into other tools.
+    #[arg(long)]
+    json_pretty: bool,
+
+    /// Number each node in the order it is printed
+    ///
+    /// Numbers are the same in every output format, and they can be passed to
+    /// `--explain-node` to refer to a node, such as `--explain-node '#3'`.
+    #[arg(long, overrides_with = "no_number")]
+    number: bool,
+
+    /// Don't number each node
+    ///
+    /// This overrides `number = true` in the `[jj-analyze]` config table.
+    #[arg(long, overrides_with = "number")]
+    no_number: bool,
+
+    /// Print repeated predicates once and refer back to them
+    ///
+    /// The first time a predicate appears, it is marked with a number like
+    /// `[1]`. Any other identical predicates are printed as `(see [1])` without
+    /// their children. This only affects `--format text`.
+    #[arg(long)]
+    group_predicates: bool,
+
+    /// Pad the labels of children so that their names line up
+    ///
+    /// This only affects `--format text`.
+    #[arg(long, overrides_with = "no_align_labels")]
+    align_labels: bool,
+
+    /// Don't pad the labels of children
+    ///
+    /// This overrides `align-labels = true` in the `[jj-analyze]` config table.
+    #[arg(long, overrides_with = "align_labels")]
+    no_align_labels: bool,
+
+    /// Print at most this many children of each node
+    ///
+    /// Any other children are summarized as `… and N more`, which keeps wide
+    /// unions of many bookmarks readable. Summarized children are still
+    /// included in `--score` and `--count`. This only affects `--format text`.
+    #[arg(long, value_name = "N")]
+    tree_max_children: Option<usize>,
+
+    /// Warn about symbols, bookmarks, and tags which don't exist
+    ///
+    /// This requires loading the repository, so it is slower than analyzing
+    /// the revset alone.
+    #[arg(long)]
+    validate: bool,
+
+    /// Evaluate the revset against the repository and print how many
+    /// revisions it contains
+    ///
+    /// This requires loading the repository, and evaluating the revset may be
+    /// slow for expensive revsets.
+    #[arg(long)]
+    count: bool,
+
+    /// Print how long each step of parsing the revset took
+    ///
+    /// Timings are printed to stderr. This can help to tell whether a revset is
+    /// slow to analyze instead of slow to evaluate.
+    #[arg(long, conflicts_with = "user_tree")]
+    timings: bool,
+
+    /// Warn about references and filters which were removed by optimization
+    ///
+    /// For instance, `x & all()` is optimized to `x`, which removes the
+    /// `visible_heads()` from `all()`. This can help to catch a revset which
+    /// simplifies to something unexpected.
+    #[arg(long, conflicts_with_all = ["user_tree", "raw"])]
+    warn_removed: bool,
+
+    /// Print additional information about how the revset was processed
+    ///
+    /// For instance, this lists which aliases were collapsed.
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Display filters as they are represented by jj
+    ///
+    /// By default, some filters are displayed using shorthands such as
+    /// `merges()` and `empty()`. Operations are always named after the
+    /// `ResolvedExpression` variant which jj uses.
+    #[arg(long)]
+    no_rename: bool,
+
+    /// Highlight nodes whose name matches a regular expression
+    #[arg(long, value_name = "REGEX")]
+    grep: Option<Regex>,
+
+    /// Only print nodes which match `--grep` and their ancestors
+    #[arg(long, requires = "grep")]
+    grep_only: bool,
+
+    /// Print the analyzed revset as a revset string instead of a tree
+    ///
+    /// The revset is printed after resolution and optimization, so it may be
+    /// written differently than the input.
+    #[arg(long, conflicts_with_all = ["explain_node", "raw", "user_tree", "quiet", "alias_origins"])]
+    normalized: bool,
+
+    /// Print a score estimating the total cost of evaluating the revset
+    ///
+    /// Unlike the `(EXPENSIVE)` label, the score is the sum of every node, so
+    /// it can be used to compare several revsets.
+    #[arg(long)]
+    score: bool,
+
+    /// Only print expensive nodes and warnings instead of the whole tree
+    ///
+    /// Each expensive node is printed on its own line with its path (as used by
+    /// `--explain-node`) and the reason it may be expensive. If nothing is
+    /// expensive, nothing is printed.
+    #[arg(short, long, conflicts_with_all = ["verbose", "explain_node", "raw", "user_tree"])]
+    quiet: bool,
+
+    /// Print a report of the cost of every expensive node instead of the tree
+    ///
+    /// The report contains the path, name, cost, and reason for each node which
+    /// may be expensive, along with the highest cost of any node.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        conflicts_with_all = ["explain_node", "raw", "user_tree", "quiet", "normalized", "score", "count"]
+    )]
+    cost_report: Option<CostReportFormat>,
+
+    /// Print the number of nodes in the tree instead of the tree
+    ///
+    /// This can be used as a rough measure of how complex a revset is.
+    #[arg(
+        long,
+        conflicts_with_all = ["explain_node", "raw", "user_tree", "quiet", "normalized", "score", "count", "cost_report"]
+    )]
+    count_nodes: bool,
+
+    /// Print every reference in the revset instead of the tree
+    ///
+    /// References are printed in sorted order, such as bookmarks, tags, and
+    /// change IDs, after any aliases are expanded.
+    #[arg(
+        long,
+        conflicts_with_all = ["explain_node", "raw", "user_tree", "quiet", "normalized", "score", "count", "cost_report", "count_nodes"]
+    )]
+    only_references: bool,
+
+    /// Remove all escape sequences from the output
+    ///
+    /// Unlike `--color never`, this can't be overridden by the environment, so
+    /// it is useful when the output is compared against a file or logged.
+    #[arg(long)]
+    strip_ansi: bool,
+
+    /// Analyze the revset again whenever config, aliases, or revset files
+    /// change
+    #[arg(long)]
+    watch: bool,
+
+    /// Don't send long output to a pager
+    ///
+    /// By default, output which doesn't fit in the terminal is sent to
+    /// `$PAGER`, or `less -R` if it isn't set.
+    #[arg(long)]
+    no_pager: bool,
+
+    /// Write the output to a new file in this directory instead of stdout
+    ///
+    /// Files are numbered in the order they are written, such as `001.txt`,
+    /// so running this for several revsets creates one file for each revset.
+    /// The directory is created if it doesn't exist. Colors are disabled
+    /// unless `--color always` is passed.
+    #[arg(long, value_name = "DIR", conflicts_with = "watch")]
+    output_dir: Option<PathBuf>,
+
+    /// Exit with an error if any warnings were printed
+    ///
+    /// This includes warnings about the revset itself (such as deprecated
+    /// functions), as well as warnings from `--validate` and `--warn-removed`.
+    /// The output is still printed before exiting.
+    #[arg(long, conflicts_with = "watch")]
+    fail_on_warning: bool,
+
+    /// Fail instead of printing warnings about the revset itself
+    ///
+    /// For instance, a deprecated function like `git_head()` is reported as
+    /// an error pointing to where it appears, and nothing is printed.
+    #[arg(long)]
+    strict_parse: bool,
+
+    /// Only check that the revset can be parsed and resolved
+    ///
+    /// Nothing is printed if the revset is valid, unless `--verbose` is
+    /// passed. Otherwise, the error is printed and the exit code is nonzero.
+    /// Warnings aren't printed, but `--strict-parse` can be passed to report
+    /// them as errors. This is useful for scripts which check revsets written
+    /// by users.
+    #[arg(long, conflicts_with = "bench")]
+    dry_run_parse: bool,
+
     #[command(flatten)]
     config_args: ConfigArgs,
 }
 
-#[derive(clap::Args, Debug)]
+#[derive(clap::Args, Clone, Debug)]
 #[command(next_help_heading = "Configuration Options")]
 struct ConfigArgs {
+    /// Load revset aliases from a TOML file
+    ///
+    /// The file should contain a table of `name = "definition"` pairs. These
+    /// aliases override aliases loaded from config.
+    #[arg(long, value_name = "PATH")]
+    aliases_file: Vec<PathBuf>,
+
     /// Collapses the provided revset alias, hiding it from the output
+    ///
+    /// Aliases with parameters can also be collapsed by passing their
+    /// declaration, such as `--collapse 'mine_in(path)'`. The arguments of
+    /// each call are still displayed.
     #[arg(long, value_name = "ALIAS")]
     collapse: Vec<String>,
 
+    /// Expands the provided revset alias, even if it would be collapsed
+    ///
+    /// For example, `--expand-alias 'trunk()'` will show the definition of
+    /// `trunk()` instead of hiding it.
+    #[arg(long, value_name = "ALIAS")]
+    expand_alias: Vec<String>,
+
     /// When to colorize output
     #[arg(long, value_name = "MODE")]
     color: Option<ColorMode>,
@@ -111,6 +678,23 @@ struct ConfigArgs {
     #[arg(short, long)]
     define: Vec<String>,
 
+    /// Define a custom revset alias using the contents of a file
+    ///
+    /// For example, `--define-file 'immutable_heads()=heads.revset'` will
+    /// override `immutable_heads()` to be the revset in `heads.revset`. This
+    /// avoids quoting long or multi-line definitions in the shell.
+    #[arg(long, value_name = "NAME=PATH")]
+    define_file: Vec<String>,
+
+    /// Analyze the revset as if `immutable_heads()` were the provided revset
+    ///
+    /// This is a shorthand for `--define 'immutable_heads()=REVSET'`, and it
+    /// takes precedence over any other definition. Collapsed aliases such as
+    /// `builtin_immutable_heads()` are still collapsed if the revset refers to
+    /// them, and `--collapse 'immutable_heads()'` still hides the definition.
+    #[arg(long, value_name = "REVSET")]
+    assume_immutable: Option<String>,
+
     /// Disable analysis of evaluation and cost
     ///
     /// If you are using a different revset backend, the analysis features may
@@ -137,51 +721,194 @@ struct ConfigArgs {
     /// Path to repository to load revset aliases from
     #[arg(short = 'R', long, value_name = "PATH")]
     repository: Option<PathBuf>,
+
+    /// Load style options from a TOML file
+    ///
+    /// The file has the same keys as the `jj-analyze` table in config, such as
+    /// `theme = "mono"` or `indent-width = 4`, and it overrides that table.
+    /// Flags passed on the command line override both.
+    #[arg(long, value_name = "PATH")]
+    style_file: Option<PathBuf>,
+
+    /// Whether to use glob patterns by default for string patterns
+    ///
+    /// By default, `ui.revsets-use-glob-by-default` is loaded from config.
+    #[arg(long, value_name = "BOOL")]
+    use_glob_by_default: Option<bool>,
+
+    /// Email address to use for `mine()` and similar functions
+    ///
+    /// By default, `user.email` is loaded from config.
+    #[arg(long, value_name = "EMAIL")]
+    user_email: Option<String>,
 }
 
 fn main() -> anyhow::Result<()> {
     CompleteEnv::with_factory(Args::command).complete();
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(command) = args.context_from_command {
+        args.context = command.context();
+    }
+    if args.context == AnalyzeContext::Resolved {
+        args.context = AnalyzeContext::Lazy;
+        args.structure_only = true;
+    }
+    if args.describe_operations {
+        let mut output = Vec::new();
+        describe_operations(&mut output, &FormatOptions::default())?;
+        return write_output(&output, &args);
+    }
+    if args.watch {
+        return watch(&args);
+    }
+    let session = Session::load(&args)?;
+    let mut warnings = 0;
+    run(&args, &session, &mut warnings)?;
+    if args.fail_on_warning && warnings > 0 {
+        let plural = if warnings == 1 { "" } else { "s" };
+        anyhow::bail!("Found {warnings} warning{plural} with `--fail-on-warning`");
+    }
+    Ok(())
+}
 
-    let cwd = env::current_dir()
+/// Completes the name at the end of a partially written revset using the
+/// builtin functions and the aliases from config.
+fn complete_revset(current: &OsStr) -> Vec<CompletionCandidate> {
+    let Some(current) = current.to_str() else {
+        return Vec::new();
+    };
+    let start = current
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !(c.is_alphanumeric() || *c == '_'))
+        .map_or(0, |(index, c)| index + c.len_utf8());
+    let (before, name) = current.split_at(start);
+    // Completion should never fail, so aliases are skipped if config is invalid
+    let aliases = env::current_dir()
         .and_then(dunce::canonicalize)
-        .context("Failed to find current directory")?;
-    let workspace_dir = args
-        .config_args
-        .repository
-        .as_deref()
-        .unwrap_or_else(|| find_workspace_dir(&cwd));
-    let settings = load_settings(workspace_dir, !args.config_args.no_user_config)
-        .context("Failed to load settings")?;
-
-    let input = args
-        .revset_pos
-        .or(args.revset_opt)
-        .or(args
-            .revset_from_config
-            .map(|key| settings.get_string(["revsets", key.as_str()]))
-            .transpose()
-            .context("Failed to find revset from config")?)
-        .context("Revision argument should be provided")?;
-
-    let ui = Ui::with_config(settings.config()).map_err(|err| err.error)?;
-    if let Some(color) = args.config_args.color {
-        // If color argument is provided directly, use it
-        match color {
-            ColorMode::Always => colored::control::set_override(true),
-            ColorMode::Never => colored::control::set_override(false),
-            _ => {}
-        }
+        .ok()
+        .and_then(|cwd| load_settings(find_workspace_dir(&cwd), true).ok())
+        .map(|settings| {
+            settings
+                .table_keys("revset-aliases")
+                .map(|declaration| match declaration.split_once('(') {
+                    Some((function, _)) => format!("{function}("),
+                    None => declaration.to_owned(),
+                })
+                .collect_vec()
+        })
+        .unwrap_or_default();
+    let functions = parse::REVSET_FUNCTIONS
+        .iter()
+        .map(|function| (format!("{function}("), "function"));
+    let aliases = aliases.into_iter().map(|alias| (alias, "alias"));
+    // Aliases come first since they can override builtin functions
+    aliases
+        .chain(functions)
+        .filter(|(candidate, _)| candidate.starts_with(name))
+        .unique_by(|(candidate, _)| candidate.clone())
+        .map(|(candidate, help)| {
+            CompletionCandidate::new(format!("{before}{candidate}")).help(Some(help.into()))
+        })
+        .collect()
+}
+
+/// The settings and aliases for the workspace selected by the arguments, which
+/// are only loaded again by `--watch` if the config changes.
+struct Session {
+    cwd: PathBuf,
+    workspace_dir: PathBuf,
+    settings: UserSettings,
+    /// The revset aliases from config, before any aliases from arguments are
+    /// inserted.
+    aliases_map: RevsetAliasesMap,
+}
+
+impl Session {
+    fn load(args: &Args) -> anyhow::Result<Self> {
+        let cwd = env::current_dir()
+            .and_then(dunce::canonicalize)
+            .context("Failed to find current directory")?;
+        let workspace_dir = match &args.config_args.repository {
+            Some(repository) => repository.clone(),
+            None => find_workspace_dir(&cwd).to_owned(),
+        };
+        let settings = load_settings(&workspace_dir, !args.config_args.no_user_config)
+            .context("Failed to load settings")?;
+        let ui = Ui::with_config(settings.config()).map_err(|err| err.error)?;
+        let aliases_map =
+            revset_util::load_revset_aliases(&ui, settings.config()).map_err(|err| err.error)?;
+        Ok(Self {
+            cwd,
+            workspace_dir,
+            settings,
+            aliases_map,
+        })
+    }
+
+    /// Returns the paths of the config files which the settings were loaded
+    /// from.
+    fn config_paths(&self) -> Vec<PathBuf> {
+        self.settings
+            .config()
+            .layers()
+            .iter()
+            .filter_map(|layer| layer.path.as_ref())
+            .map(|path| dunce::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            .collect()
+    }
+}
+
+/// Analyzes the revset using a session which was already loaded, so that the
+/// settings and aliases can be reused instead of being loaded again. The number
+/// of warnings which were printed is added to `warnings`.
+fn run(args: &Args, session: &Session, warnings: &mut usize) -> anyhow::Result<()> {
+    let settings = &session.settings;
+    let args = &with_style_config(args, settings)?;
+    let cwd = &session.cwd;
+    let workspace_dir = session.workspace_dir.as_path();
+
+    let mut default_revset = false;
+    let mut input = if let Some(input) = args.revset_pos.as_ref().or(args.revset_opt.as_ref()) {
+        input.clone()
+    } else if let Some(key) = &args.revset_from_config {
+        settings
+            .get_string(["revsets", key.as_str()])
+            .context("Failed to find revset from config")?
+    } else if let Some(path) = &args.input_file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read revset file {}", path.display()))?;
+        strip_comments(&contents)
+    } else if args.alias.is_some() || args.bench.is_some() {
+        // The definition is looked up once the aliases are loaded, and
+        // benchmarks read their own revsets
+        String::new()
+    } else if let Ok(input) = settings.get_string("revsets.log") {
+        default_revset = true;
+        input
     } else {
-        // Fall back to `jj` config (we don't support "debug" though)
-        match settings.get("ui.color")? {
-            jj_cli::ui::ColorChoice::Always => colored::control::set_override(true),
-            jj_cli::ui::ColorChoice::Never => colored::control::set_override(false),
-            _ => {}
-        }
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "a revset must be provided since `revsets.log` is not set",
+            )
+            .exit()
     };
 
+    set_color_override(args, settings)?;
+    if default_revset && !args.quiet && !args.dry_run_parse {
+        eprintln!("{}", "Using the default revset from `revsets.log`".dimmed());
+    }
+    if args.sort_coalesce && !args.dry_run_parse {
+        eprintln!(
+            "{} `--sort-coalesce` changes the meaning of `coalesce()`, since the first member \
+             which isn't empty is used",
+            "Warning:".yellow().bold()
+        );
+        *warnings += 1;
+    }
+
     let path_converter = RepoPathUiConverter::Fs {
         cwd: cwd.clone(),
         base: workspace_dir.to_owned(),
@@ -197,12 +924,31 @@ fn main() -> anyhow::Result<()> {
     } else {
         chrono::Local::now()
     };
-    let mut revset_aliases_map =
-        revset_util::load_revset_aliases(&ui, settings.config()).map_err(|err| err.error)?;
-    let collapse = |map: &mut RevsetAliasesMap, function: &str| -> anyhow::Result<()> {
-        if input != function {
-            map.insert(function, format!("{function:?}"))
+    let mut revset_aliases_map = session.aliases_map.clone();
+    let alias_name = |alias: &str| {
+        alias
+            .split('(')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_owned()
+    };
+    let expanded = args
+        .config_args
+        .expand_alias
+        .iter()
+        .chain(&args.alias)
+        .map(|alias| alias_name(alias))
+        .collect_vec();
+    // Collapsed aliases are only used for display, so anything checked against
+    // the real repository uses the aliases as they are defined
+    let mut real_aliases_map = revset_aliases_map.clone();
+    let mut collapsed = Vec::new();
+    let mut collapse = |map: &mut RevsetAliasesMap, function: &str| -> anyhow::Result<()> {
+        if input != function && !expanded.contains(&alias_name(function)) {
+            map.insert(function, parse::collapsed_alias_definition(function))
                 .context("Failed to parse alias name for `--collapse`")?;
+            collapsed.push(function.to_owned());
         }
         Ok(())
     };
@@ -210,35 +956,866 @@ fn main() -> anyhow::Result<()> {
         collapse(&mut revset_aliases_map, "trunk()")?;
         collapse(&mut revset_aliases_map, "builtin_immutable_heads()")?;
     }
-    for definition in args.config_args.define {
-        let (name, value) = definition
-            .split_once('=')
-            .context("Expected a '=' in revset definition")?;
-        revset_aliases_map
-            .insert(name.trim(), value.trim())
-            .context("Failed to insert revset definition")?;
+    for path in &args.config_args.aliases_file {
+        for map in [&mut revset_aliases_map, &mut real_aliases_map] {
+            load_aliases_file(map, path)?;
+        }
+    }
+    // Definitions may refer to each other in any order, since aliases are only
+    // expanded once everything has been inserted
+    let definitions = args
+        .config_args
+        .define
+        .iter()
+        .map(|definition| {
+            let (name, value) = definition
+                .split_once('=')
+                .context("Expected a '=' in revset definition")?;
+            Ok((name.trim(), value.trim().to_owned()))
+        })
+        .chain(args.config_args.define_file.iter().map(|definition| {
+            let (name, path) = definition
+                .split_once('=')
+                .context("Expected a '=' in revset definition file")?;
+            let path = Path::new(path.trim());
+            let value = fs::read_to_string(path).with_context(|| {
+                format!("Failed to read revset definition file {}", path.display())
+            })?;
+            Ok((name.trim(), value.trim().to_owned()))
+        }))
+        .chain(
+            (args.config_args.assume_immutable.iter())
+                .map(|revset| Ok(("immutable_heads()", revset.trim().to_owned()))),
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for (name, value) in &definitions {
+        for map in [&mut revset_aliases_map, &mut real_aliases_map] {
+            map.insert(name, value.as_str())
+                .context("Failed to insert revset definition")?;
+        }
     }
     for function in &args.config_args.collapse {
         collapse(&mut revset_aliases_map, function.as_str())?;
     }
+    for (name, _) in &definitions {
+        parse::check_alias(name, &revset_aliases_map)
+            .with_context(|| format!("Failed to expand revset definition `{name}`"))?;
+    }
+    if let Some(alias) = &args.alias {
+        input = alias_definition(&revset_aliases_map, alias)?;
+    }
+    if args.verbose && !collapsed.is_empty() {
+        eprintln!(
+            "{}",
+            format!("Collapsed aliases: {}", collapsed.join(", ")).dimmed()
+        );
+    }
+    let user_email = match args.config_args.user_email.as_deref() {
+        Some(user_email) => user_email,
+        None if settings.user_email().is_empty() => "<user-email>",
+        None => settings.user_email(),
+    };
+    let use_glob_by_default = match args.config_args.use_glob_by_default {
+        Some(use_glob_by_default) => use_glob_by_default,
+        None => settings
+            .get_bool("ui.revsets-use-glob-by-default")
+            .context("Failed to load `ui.revsets-use-glob-by-default` from config")?,
+    };
     let parse_context = RevsetParseContext {
         aliases_map: &revset_aliases_map,
         local_variables: HashMap::new(),
-        user_email: "<user-email>",
+        user_email,
         date_pattern_context: now.into(),
         default_ignored_remote: None,
-        use_glob_by_default: true,
-        extensions: &RevsetExtensions::new(),
+        use_glob_by_default,
+        extensions: &parse::revset_extensions(),
         workspace: Some(workspace_context),
     };
-    let mut reference_map = ReferenceMap::new();
-    let expr = parse::parse(
-        &input,
-        &parse_context,
+    let real_parse_context = RevsetParseContext {
+        aliases_map: &real_aliases_map,
+        ..parse_context.clone()
+    };
+    if args.dry_run_parse {
+        let mut diagnostics = RevsetDiagnostics::new();
+        let backend = parse::parse_backend(
+            &mut diagnostics,
+            &input,
+            &parse_context,
+            &mut ReferenceMap::new(),
+            !args.config_args.no_optimize,
+            &mut Timings::default(),
+        );
+        // Warnings aren't printed since only errors are reported in this mode,
+        // but `--strict-parse` still turns them into errors
+        if args.strict_parse {
+            report_diagnostics(&diagnostics, args)?;
+        }
+        backend?;
+        if args.verbose {
+            eprintln!("{}", "The revset was parsed successfully".dimmed());
+        }
+        return Ok(());
+    }
+    let mut format_options = FormatOptions {
+        date_format: if args.date_relative {
+            DateFormat::Relative
+        } else if args.date_local {
+            DateFormat::Local
+        } else {
+            DateFormat::Utc
+        },
+        now,
+        max_pattern_width: (!args.full).then_some(FormatOptions::DEFAULT_MAX_PATTERN_WIDTH),
+        raw_names: args.no_rename,
+        filter_context: args.filter_context,
+        literal_contexts: args.literal_contexts,
+        generation_as_depth: args.generation_as_depth,
+        describe_counts: args.describe_counts,
+        sort_members: args.sort_members,
+        sort_coalesce: args.sort_coalesce,
+        context_overrides: ContextOverrides::default(),
+        compact_empty: args.compact_empty,
+    };
+    if let Some(path) = &args.bench {
+        let repo = load_repo(settings, workspace_dir)?;
+        let mut output = Vec::new();
+        print_bench(
+            &mut output,
+            path,
+            &real_parse_context,
+            &repo,
+            &format_options,
+            args,
+        )?;
+        return write_output(&output, args);
+    }
+    let mut timings = Timings::default();
+    let mut diagnostics = RevsetDiagnostics::new();
+    let start = Instant::now();
+    let parsed = if args.alias_origins {
+        parse::parse_user_with_origins(&mut diagnostics, &input, &parse_context)
+    } else {
+        parse::parse_user(&mut diagnostics, &input, &parse_context)
+    };
+    timings.parse = start.elapsed();
+    *warnings += report_diagnostics(&diagnostics, args)?;
+    let parsed = parsed?;
+    // The real repository is only loaded if it is needed, since most analysis
+    // doesn't depend on the contents of the repository
+    let repo = if args.validate || args.count {
+        Some(load_repo(settings, workspace_dir)?)
+    } else if has_at_operation(&parsed) || has_id_prefix(&parsed) {
+        // Without a repository, operations and IDs are displayed as written
+        match load_repo(settings, workspace_dir) {
+            Ok(repo) => Some(repo),
+            Err(err) => {
+                let unresolved = [
+                    (has_at_operation(&parsed), "Operations in `at_operation()`"),
+                    (has_id_prefix(&parsed), "IDs"),
+                ];
+                for (_, name) in unresolved.iter().filter(|(found, _)| *found) {
+                    eprintln!(
+                        "{} {name} can't be resolved: {err:#}",
+                        "Warning:".yellow().bold()
+                    );
+                    *warnings += 1;
+                }
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(repo) = &repo {
+        check_operations(&parsed, repo)?;
+    }
+    for warning in id_prefix_warnings(&parsed, repo.as_deref()) {
+        eprintln!("{} {warning}", "Warning:".yellow().bold());
+        *warnings += 1;
+    }
+    let mut revisions = None;
+    if let Some(repo) = &repo
+        && (args.validate || args.count)
+    {
+        let parsed = parse::parse_user(&mut RevsetDiagnostics::new(), &input, &real_parse_context)?;
+        if args.validate {
+            for warning in validate_references(&parsed, repo, parse_context.extensions) {
+                eprintln!("{} {warning}", "Warning:".yellow().bold());
+                *warnings += 1;
+            }
+        }
+        if args.count {
+            revisions = Some(count_revisions(
+                &parsed,
+                repo,
+                parse_context.extensions,
+                !args.config_args.no_optimize,
+            )?);
+        }
+    }
+    // Output is collected first so it can be sent to a pager if it's too long
+    let mut output = Vec::new();
+    if args.user_tree {
+        let options = print_options(args, format_options, false);
+        print_formatted(
+            &mut output,
+            parsed.as_ref(),
+            AnalyzeContext::Lazy,
+            &options,
+            args.format,
+        )?;
+        print_revision_count(&mut output, revisions)?;
+        return write_output(&output, args);
+    }
+    let mut reference_map = match repo {
+        Some(repo) => ReferenceMap::with_repo(repo),
+        None => ReferenceMap::new(),
+    };
+    reference_map.set_expand_visible_heads(args.expand_visible_heads);
+    reference_map.set_preserve_filters(args.no_optimize_filters);
+    if args.raw {
+        let backend = parse::resolve_backend(
+            &parsed,
+            &mut reference_map,
+            !args.config_args.no_optimize,
+            &mut timings,
+        );
+        if args.timings {
+            print_timings(&timings);
+        }
+        writeln!(output, "{backend:#?}")?;
+        writeln!(output)?;
+        for (commit_id, reference) in reference_map.iter() {
+            writeln!(output, "{}", format!("{commit_id}: {reference}").dimmed())?;
+        }
+        print_revision_count(&mut output, revisions)?;
+        return write_output(&output, args);
+    }
+    // References are resolved before optimization, so the same references can
+    // be used to parse the revset again without optimization
+    let unoptimized_map =
+        (args.warn_removed && !args.config_args.no_optimize).then(|| reference_map.clone());
+    let backend = parse::resolve_backend(
+        &parsed,
         &mut reference_map,
         !args.config_args.no_optimize,
+        &mut timings,
+    );
+    let expr = Expr::parse(backend, &reference_map);
+    if args.timings {
+        print_timings(&timings);
+    }
+    select_contexts(&expr, args, &mut format_options)?;
+    if let Some(mut unoptimized_map) = unoptimized_map {
+        let backend = parse::resolve_backend(
+            &parsed,
+            &mut unoptimized_map,
+            false,
+            &mut Timings::default(),
+        );
+        let unoptimized = Expr::parse(backend, &unoptimized_map);
+        for removed in removed_by_optimization(
+            &unoptimized,
+            &expr,
+            args.context,
+            &unoptimized_map,
+            &format_options,
+        ) {
+            eprintln!(
+                "{} `{removed}` was removed by optimization",
+                "Warning:".yellow().bold()
+            );
+            *warnings += 1;
+        }
+    }
+    if args.context == AnalyzeContext::Eager
+        && !args.quiet
+        && expr.is_unbounded_traversal()
+        && CostCache::for_tree(&expr, args.context, &format_options).cost(&expr, args.context)
+            >= AnalyzeCost::Medium
+    {
+        eprintln!(
+            "{} The entire revset is evaluated eagerly, so every revision it traverses is \
+             collected. Consider `--context lazy` if the revset won't be fully iterated, or limit \
+             the number of generations.",
+            "Hint:".cyan().bold()
+        );
+    }
+    let score = args
+        .score
+        .then(|| total_cost_score(&expr, args.context, &format_options));
+    if args.normalized {
+        writeln!(output, "{}", normalize(&expr, &format_options)?)?;
+    } else if args.only_references {
+        for reference in list_references(&expr, args.context, &reference_map, &format_options) {
+            writeln!(output, "{reference}")?;
+        }
+    } else if args.count_nodes {
+        writeln!(
+            output,
+            "{}",
+            count_nodes(&expr, args.context, &format_options)
+        )?;
+    } else if let Some(format) = args.cost_report {
+        let report = CostReport::new(&expr, args.context, &format_options);
+        match format {
+            CostReportFormat::Json => writeln!(
+                output,
+                "{}",
+                if args.json_pretty {
+                    serde_json::to_string_pretty(&report)
+                } else {
+                    serde_json::to_string(&report)
+                }
+                .context("Failed to serialize cost report as JSON")?
+            )?,
+        }
+    } else if args.quiet {
+        print_expensive_nodes(&mut output, &expr, args.context, &format_options)?;
+    } else if let Some(path) = &args.explain_node {
+        explain_node(
+            &mut output,
+            &expr,
+            args.context,
+            path,
+            !args.config_args.no_analyze,
+            &format_options,
+        )?;
+    } else {
+        let options = print_options(args, format_options, !args.config_args.no_analyze);
+        print_formatted(&mut output, &expr, args.context, &options, args.format)?;
+    }
+    if let Some(score) = score {
+        writeln!(output)?;
+        writeln!(
+            output,
+            "{}",
+            format!("Estimated cost score: {score}").bold()
+        )?;
+    }
+    print_revision_count(&mut output, revisions)?;
+    write_output(&output, args)
+}
+
+fn print_revision_count(
+    out: &mut dyn Write,
+    revisions: Option<(usize, Duration)>,
+) -> io::Result<()> {
+    if let Some((count, duration)) = revisions {
+        let plural = if count == 1 { "" } else { "s" };
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{}",
+            format!("Evaluated to {count} revision{plural} in {duration:.2?}").bold()
+        )?;
+    }
+    Ok(())
+}
+
+/// Parses an override for `--select-context`, such as `0.heads=eager`.
+fn parse_context_override(value: &str) -> Result<(String, AnalyzeContext), String> {
+    let (path, context) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected `PATH=CONTEXT`, found `{value}`"))?;
+    let context = <AnalyzeContext as clap::ValueEnum>::from_str(context, true)?;
+    Ok((path.to_owned(), context))
+}
+
+/// Parses the name of a color for `--indent-guides-color`.
+fn parse_color(name: &str) -> Result<colored::Color, String> {
+    name.parse()
+        .map_err(|()| format!("unknown color `{name}`, expected a name like `blue`"))
+}
+
+/// The name of the table in config which contains [`StyleConfig`].
+const STYLE_CONFIG_TABLE: &str = "jj-analyze";
+
+/// Options for how the tree is printed which can be saved in config, so that
+/// they don't need to be passed every time. Each option corresponds to the flag
+/// of the same name, and flags take precedence over config.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct StyleConfig {
+    theme: Option<Theme>,
+    indent_width: Option<usize>,
+    indent_guides_color: Option<String>,
+    max_width: Option<usize>,
+    tree_max_children: Option<usize>,
+    compact: bool,
+    compact_empty: bool,
+    align_labels: bool,
+    number: bool,
+    full: bool,
+}
+
+/// Returns a copy of the arguments with any options which weren't passed
+/// loaded from the `jj-analyze` table in config and from `--style-file`.
+fn with_style_config(args: &Args, settings: &UserSettings) -> anyhow::Result<Args> {
+    let mut config = settings.config().clone();
+    if let Some(path) = &args.config_args.style_file {
+        let file = ConfigLayer::load_from_file(ConfigSource::CommandArg, path.clone())
+            .with_context(|| format!("Failed to load style file {}", path.display()))?;
+        // The file is a standalone table, so it is nested under the table's
+        // name to be merged with config
+        let mut layer = ConfigLayer::empty(ConfigSource::CommandArg);
+        layer.data.insert(
+            STYLE_CONFIG_TABLE,
+            ConfigItem::Table(file.data.as_table().clone()),
+        );
+        config.add_layer(layer);
+    }
+    let style = config
+        .get::<StyleConfig>(STYLE_CONFIG_TABLE)
+        .optional()
+        .with_context(|| format!("Failed to load `{STYLE_CONFIG_TABLE}` from config"))?
+        .unwrap_or_default();
+    let mut args = args.clone();
+    args.theme = args.theme.or(style.theme);
+    args.indent_width = args.indent_width.or(style.indent_width);
+    if args.indent_guides_color.is_none()
+        && let Some(name) = &style.indent_guides_color
+    {
+        let color = parse_color(name)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| {
+                format!("Failed to load `{STYLE_CONFIG_TABLE}.indent-guides-color` from config")
+            })?;
+        args.indent_guides_color = Some(color);
+    }
+    args.max_width = args.max_width.or(style.max_width);
+    args.tree_max_children = args.tree_max_children.or(style.tree_max_children);
+    args.compact |= style.compact && !args.no_compact;
+    args.compact_empty |= style.compact_empty && !args.no_compact_empty;
+    args.align_labels |= style.align_labels && !args.no_align_labels;
+    args.number |= style.number && !args.no_number;
+    args.full |= style.full && !args.no_full;
+    Ok(args)
+}
+
+/// Returns the width to wrap names at, which is the width of the terminal
+/// unless `--max-width` is specified.
+/// Returns the options for printing a tree, which only includes costs if
+/// `analyze` is true.
+fn print_options(args: &Args, format_options: FormatOptions, analyze: bool) -> PrintOptions {
+    PrintOptions {
+        analyze,
+        compact: args.compact,
+        format: format_options,
+        grep: args.grep.clone(),
+        grep_only: args.grep_only,
+        html_classes: args.html_classes,
+        number: args.number,
+        group_predicates: args.group_predicates,
+        theme: args.theme.unwrap_or_default(),
+        max_width: max_width(args),
+        json_pretty: args.json_pretty,
+        align_labels: args.align_labels,
+        max_children: args.tree_max_children,
+        structure_only: args.structure_only,
+        indent: IndentStyle {
+            width: args.indent_width.unwrap_or(IndentStyle::default().width),
+            guide_color: args.indent_guides_color,
+        },
+    }
+}
+
+fn max_width(args: &Args) -> Option<usize> {
+    match args.max_width {
+        Some(0) => None,
+        Some(max_width) => Some(max_width),
+        None if io::stdout().is_terminal() => {
+            terminal_size::terminal_size().map(|(Width(width), _)| usize::from(width))
+        }
+        None => None,
+    }
+}
+
+/// Writes the output to stdout, or to `$PAGER` if stdout is a terminal and the
+/// output doesn't fit on the screen.
+fn write_output(output: &[u8], args: &Args) -> anyhow::Result<()> {
+    let stripped;
+    let output = if args.strip_ansi {
+        stripped = strip_ansi(output);
+        &stripped
+    } else {
+        output
+    };
+    if let Some(dir) = &args.output_dir {
+        return write_output_file(output, dir, args.format);
+    }
+    if !args.no_pager
+        && !args.watch
+        && io::stdout().is_terminal()
+        && let Some((_, Height(height))) = terminal_size::terminal_size()
+        && output.iter().filter(|&&byte| byte == b'\n').count() >= usize::from(height)
+    {
+        // Colors are already decided by `--color`, so `-R` is needed to keep them
+        let pager = env::var("PAGER")
+            .ok()
+            .filter(|pager| !pager.trim().is_empty())
+            .unwrap_or_else(|| "less -R".into());
+        let mut words = pager.split_whitespace();
+        if let Some(program) = words.next()
+            && let Ok(mut child) = Command::new(program)
+                .args(words)
+                .stdin(Stdio::piped())
+                .spawn()
+        {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The pager may exit before reading everything if it's closed early
+                let _ = stdin.write_all(output);
+            }
+            child.wait().context("Failed to wait for pager")?;
+            return Ok(());
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    stdout
+        .write_all(output)
+        .and_then(|()| stdout.flush())
+        .context("Failed to write output")
+}
+
+/// Writes the output to a file in the directory numbered after every existing
+/// file, such as `001.txt`.
+fn write_output_file(output: &[u8], dir: &Path, format: OutputFormat) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let last = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .path()
+                .file_stem()?
+                .to_str()?
+                .parse::<usize>()
+                .ok()
+        })
+        .max()
+        .unwrap_or(0);
+    for number in last + 1.. {
+        let path = dir.join(format!("{number:03}.{}", format.extension()));
+        let mut file = match fs::File::create_new(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to create {}", path.display()));
+            }
+        };
+        file.write_all(output)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        eprintln!("{}", format!("Wrote {}", path.display()).dimmed());
+        break;
+    }
+    Ok(())
+}
+
+/// Decides whether to use colors based on the arguments and config.
+fn set_color_override(args: &Args, settings: &UserSettings) -> anyhow::Result<()> {
+    if args.strip_ansi {
+        colored::control::set_override(false);
+    } else if let Some(color) = args.config_args.color {
+        // If color argument is provided directly, use it
+        match color {
+            ColorMode::Always => colored::control::set_override(true),
+            ColorMode::Never => colored::control::set_override(false),
+            _ => {}
+        }
+    } else if args.output_dir.is_some() {
+        // Colors from config are meant for the terminal, not for files
+        colored::control::set_override(false);
+    } else {
+        // Fall back to `jj` config (we don't support "debug" though)
+        match settings.get("ui.color")? {
+            jj_cli::ui::ColorChoice::Always => colored::control::set_override(true),
+            jj_cli::ui::ColorChoice::Never => colored::control::set_override(false),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether the screen should be cleared before each run of `--watch`, which is
+/// only done if escape sequences are enabled and written to a terminal.
+fn should_clear_screen(args: &Args) -> bool {
+    !args.strip_ansi
+        && io::stdout().is_terminal()
+        && colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+fn watch(args: &Args) -> anyhow::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(sender).context("Failed to start watching files")?;
+    let mut watched_dirs = HashSet::new();
+    let mut session = Session::load(args)?;
+    let mut loaded = true;
+    loop {
+        if loaded {
+            // Errors from config are reported by `run()` instead
+            if set_color_override(args, &session.settings).is_ok() && should_clear_screen(args) {
+                print!("\x1b[2J\x1b[H");
+            }
+            if let Err(err) = run(args, &session, &mut 0) {
+                eprintln!("{} {err:#}", "Error:".red().bold());
+            }
+        }
+        let _ = io::stdout().flush();
+
+        // Config files may be replaced instead of modified when saved, so the
+        // parent directories are watched instead of the files themselves
+        let config_paths = session.config_paths();
+        let paths = watched_paths(args, &session.cwd)
+            .into_iter()
+            .chain(config_paths.iter().cloned())
+            .collect_vec();
+        for path in &paths {
+            if let Some(dir) = path.parent()
+                && watched_dirs.insert(dir.to_owned())
+            {
+                watcher
+                    .watch(dir, RecursiveMode::NonRecursive)
+                    .with_context(|| format!("Failed to watch {}", dir.display()))?;
+            }
+        }
+        let mut changed = HashSet::new();
+        while changed.is_empty() {
+            let event = receiver
+                .recv()
+                .context("Failed to receive file events")?
+                .context("Failed to watch files")?;
+            collect_changed_paths(event, &paths, &mut changed);
+        }
+        // Wait for any other events caused by the same change
+        thread::sleep(Duration::from_millis(100));
+        while let Ok(event) = receiver.try_recv() {
+            if let Ok(event) = event {
+                collect_changed_paths(event, &paths, &mut changed);
+            }
+        }
+        // The settings and aliases only need to be loaded again if the config
+        // changed, since other files are read by each run
+        loaded = true;
+        if changed.iter().any(|path| config_paths.contains(path)) {
+            match Session::load(args) {
+                Ok(new_session) => session = new_session,
+                // Keep watching the same files until the config can be loaded
+                // again
+                Err(err) => {
+                    eprintln!("{} {err:#}", "Error:".red().bold());
+                    loaded = false;
+                }
+            }
+        }
+    }
+}
+
+/// Adds the watched paths which were changed by the event to `changed`.
+/// Reading the files during a run also produces events, so only events which
+/// change the files are considered.
+fn collect_changed_paths(event: notify::Event, paths: &[PathBuf], changed: &mut HashSet<PathBuf>) {
+    if matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    ) {
+        changed.extend(event.paths.into_iter().filter(|path| paths.contains(path)));
+    }
+}
+
+/// Applies `--select-context` to the nodes of a tree, so that their costs are
+/// analyzed in a different context.
+fn select_contexts(
+    expr: &Expr,
+    args: &Args,
+    format_options: &mut FormatOptions,
+) -> anyhow::Result<()> {
+    for (path, context) in &args.select_context {
+        let path = resolve_node_number(expr, args.context, path, format_options)?;
+        if path.split('.').all(|segment| segment.is_empty()) {
+            anyhow::bail!("Use `--context` to change the context of the entire revset");
+        }
+        let path = canonical_path(expr, args.context, &path, format_options)?;
+        format_options.context_overrides.insert(path, *context);
+    }
+    Ok(())
+}
+
+/// Evaluates each revset in the file, and writes a table comparing the
+/// predicted cost of each revset to the cost measured from evaluating it.
+/// Revsets are analyzed and evaluated using `real_parse_context`, since
+/// collapsed aliases can't be evaluated, and their cost would be hidden.
+fn print_bench(
+    output: &mut dyn Write,
+    path: &Path,
+    real_parse_context: &RevsetParseContext,
+    repo: &Arc<ReadonlyRepo>,
+    format_options: &FormatOptions,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read benchmark file {}", path.display()))?;
+    let optimize = !args.config_args.no_optimize;
+    let mut rows = Vec::new();
+    for revset in contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let parsed = parse::parse_user(&mut RevsetDiagnostics::new(), revset, real_parse_context)
+            .with_context(|| format!("Failed to benchmark `{revset}`"))?;
+        let mut reference_map = ReferenceMap::with_repo(repo.clone());
+        let backend = parse::resolve_backend(
+            &parsed,
+            &mut reference_map,
+            optimize,
+            &mut Timings::default(),
+        );
+        let expr = Expr::parse(backend, &reference_map);
+        let mut format_options = format_options.clone();
+        select_contexts(&expr, args, &mut format_options)
+            .with_context(|| format!("Failed to benchmark `{revset}`"))?;
+        let predicted =
+            CostCache::for_tree(&expr, args.context, &format_options).cost(&expr, args.context);
+        let (count, duration) =
+            count_revisions(&parsed, repo, real_parse_context.extensions, optimize)
+                .with_context(|| format!("Failed to benchmark `{revset}`"))?;
+        rows.push((revset, predicted, measured_cost(duration), duration, count));
+    }
+    writeln!(
+        output,
+        "{}",
+        format!(
+            "{:<9} {:<9} {:>10} {:>10}  REVSET",
+            "PREDICTED", "MEASURED", "TIME", "REVISIONS"
+        )
+        .bold()
+    )?;
+    let mut wrong = 0;
+    for (revset, predicted, measured, duration, count) in &rows {
+        let line = format!(
+            "{:<9} {:<9} {:>10} {count:>10}  {revset}",
+            predicted.to_string(),
+            measured.to_string(),
+            format!("{duration:.2?}"),
+        );
+        if predicted == measured {
+            writeln!(output, "{line}")?;
+        } else {
+            wrong += 1;
+            writeln!(output, "{}", line.yellow())?;
+        }
+    }
+    writeln!(output)?;
+    writeln!(
+        output,
+        "{}",
+        format!("{wrong} of {} predictions were wrong", rows.len()).dimmed()
     )?;
-    pretty_print(&expr, args.context, !args.config_args.no_analyze);
+    Ok(())
+}
+
+/// Removes lines starting with `#` from a revset file, along with any
+/// surrounding whitespace.
+fn strip_comments(contents: &str) -> String {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .join("\n")
+        .trim()
+        .to_owned()
+}
+
+/// Returns the files passed as arguments which should be watched for changes,
+/// such as aliases files. Config files are watched separately, since the
+/// settings must be loaded again if they change.
+fn watched_paths(args: &Args, cwd: &Path) -> Vec<PathBuf> {
+    args.config_args
+        .aliases_file
+        .iter()
+        .chain(&args.input_file)
+        .chain(&args.config_args.style_file)
+        .map(PathBuf::as_path)
+        .chain(
+            args.config_args
+                .define_file
+                .iter()
+                .filter_map(|definition| definition.split_once('='))
+                .map(|(_, path)| Path::new(path.trim())),
+        )
+        .map(|path| dunce::canonicalize(path).unwrap_or_else(|_| cwd.join(path)))
+        .collect()
+}
+
+/// Prints how long each step of parsing took to stderr, so that it doesn't
+/// interfere with the tree.
+fn print_timings(timings: &Timings) {
+    for (step, duration) in timings.steps() {
+        eprintln!("{}", format!("{step}: {duration:.2?}").dimmed());
+    }
+}
+
+/// Prints each diagnostic as a warning, returning the number of warnings. With
+/// `--strict-parse`, the first diagnostic is returned as an error instead.
+fn report_diagnostics(diagnostics: &RevsetDiagnostics, args: &Args) -> anyhow::Result<usize> {
+    if args.strict_parse
+        && let Some(diagnostic) = diagnostics.iter().next()
+    {
+        let message =
+            iter::successors(Some(diagnostic as &dyn error::Error), |err| err.source()).join("\n");
+        return Err(anyhow::anyhow!(message))
+            .context("Failed to parse revset with `--strict-parse`");
+    }
+    Ok(print_diagnostics(diagnostics))
+}
+
+/// Prints each diagnostic as a warning, returning the number of warnings.
+fn print_diagnostics(diagnostics: &RevsetDiagnostics) -> usize {
+    for diagnostic in diagnostics {
+        eprintln!("{} In revset expression", "Warning:".yellow().bold());
+        for err in iter::successors(Some(diagnostic as &dyn error::Error), |err| err.source()) {
+            eprintln!("{err}");
+        }
+    }
+    diagnostics.len()
+}
+
+/// Looks up the definition of an alias without parameters, such as `trunk()`.
+fn alias_definition(aliases_map: &RevsetAliasesMap, alias: &str) -> anyhow::Result<String> {
+    let (name, params) = match alias.split_once('(') {
+        Some((name, params)) => (name.trim(), Some(params.trim_end_matches(')').trim())),
+        None => (alias.trim(), None),
+    };
+    if params.is_some_and(|params| !params.is_empty()) {
+        anyhow::bail!("Cannot analyze revset alias `{alias}` since it has parameters");
+    }
+    let definition = if params.is_none()
+        && let Some((_, definition)) = aliases_map.get_symbol(name)
+    {
+        definition
+    } else if let Some((_, _, definition)) = aliases_map.get_function(name, 0) {
+        definition
+    } else if aliases_map.function_names().contains(&name) {
+        anyhow::bail!("Cannot analyze revset alias `{name}()` since it has parameters");
+    } else {
+        anyhow::bail!("Revset alias `{alias}` is not defined");
+    };
+    Ok(definition.clone())
+}
+
+fn load_aliases_file(map: &mut RevsetAliasesMap, path: &Path) -> anyhow::Result<()> {
+    let layer = ConfigLayer::load_from_file(ConfigSource::CommandArg, path.to_owned())
+        .with_context(|| format!("Failed to load aliases file {}", path.display()))?;
+    for (name, item) in layer.data.iter() {
+        let value = item.as_str().with_context(|| {
+            format!(
+                "Expected a string for alias `{name}`, but is {}",
+                item.type_name()
+            )
+        })?;
+        map.insert(name, value)
+            .with_context(|| format!("Failed to insert alias `{name}` from aliases file"))?;
+    }
     Ok(())
 }
 
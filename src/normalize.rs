@@ -0,0 +1,399 @@
+use std::ops::Range;
+
+use anyhow::bail;
+use itertools::Itertools as _;
+use jj_lib::fileset::FilesetExpression;
+use jj_lib::revset::GENERATION_RANGE_FULL;
+use jj_lib::revset::PARENTS_RANGE_FULL;
+use jj_lib::revset::RevsetFilterPredicate;
+use jj_lib::time_util::DatePattern;
+
+use crate::expr::Expr;
+use crate::expr::Predicate;
+use crate::expr::ResolvedReference;
+use crate::expr::filter_to_string;
+use crate::print::DateFormat;
+use crate::print::FormatOptions;
+
+// Precedence of each kind of expression, from loosest to tightest binding
+const UNION: u8 = 1;
+const INTERSECTION: u8 = 2;
+const NEGATION: u8 = 3;
+const RANGE: u8 = 4;
+const PRIMARY: u8 = 5;
+
+/// Converts an expression back into a revset string using jj's operator
+/// syntax, such as `::@ & ~empty()`. Some expressions can't be written as a
+/// revset (such as a range of parent indices other than the first parent), in
+/// which case an error is returned.
+pub fn normalize(expr: &Expr, options: &FormatOptions) -> anyhow::Result<String> {
+    // Patterns must never be truncated and dates must be absolute
+    let options = FormatOptions {
+        date_format: DateFormat::Utc,
+        max_pattern_width: None,
+        raw_names: false,
+        ..options.clone()
+    };
+    Ok(write_expr(expr, &options)?.1)
+}
+
+/// Wraps the revset in parentheses if it binds looser than `precedence`.
+fn at_least(precedence: u8, (own, revset): (u8, String)) -> String {
+    if own < precedence {
+        format!("({revset})")
+    } else {
+        revset
+    }
+}
+
+fn write_expr(expr: &Expr, options: &FormatOptions) -> anyhow::Result<(u8, String)> {
+    let primary = |expr: &Expr| write_expr(expr, options).map(|result| at_least(PRIMARY, result));
+    let argument = |expr: &Expr| write_expr(expr, options).map(|result| result.1);
+    Ok(match expr {
+        Expr::None => (PRIMARY, "none()".into()),
+        Expr::Reference(reference) => (PRIMARY, write_reference(reference)),
+        Expr::Ancestors {
+            heads,
+            generation,
+            parents_range,
+        } if matches!(heads.as_ref(), Expr::Reference(reference)
+            if *reference == ResolvedReference::visible_heads()
+                || *reference == ResolvedReference::visible_heads_or_referenced())
+            && *generation == GENERATION_RANGE_FULL
+            && *parents_range == PARENTS_RANGE_FULL =>
+        {
+            (PRIMARY, "all()".into())
+        }
+        Expr::Ancestors {
+            heads,
+            generation,
+            parents_range,
+        } => write_ancestors(&primary(heads)?, generation, parents_range)?,
+        Expr::Range {
+            roots,
+            heads,
+            generation,
+            parents_range,
+        } => {
+            if *generation == GENERATION_RANGE_FULL && *parents_range == PARENTS_RANGE_FULL {
+                (RANGE, format!("{}..{}", primary(roots)?, primary(heads)?))
+            } else {
+                let ancestors = write_ancestors(&primary(heads)?, generation, parents_range)?;
+                (
+                    INTERSECTION,
+                    format!(
+                        "{} ~ ::{}",
+                        at_least(INTERSECTION, ancestors),
+                        primary(roots)?
+                    ),
+                )
+            }
+        }
+        Expr::DagRange {
+            roots,
+            heads,
+            generation_from_roots,
+        } => {
+            let to_visible_heads = matches!(heads.as_ref(), Expr::Reference(reference)
+                if *reference == ResolvedReference::visible_heads_or_referenced());
+            let roots = primary(roots)?;
+            match (
+                *generation_from_roots == GENERATION_RANGE_FULL,
+                to_visible_heads,
+            ) {
+                (true, true) => (RANGE, format!("{roots}::")),
+                (true, false) => (RANGE, format!("{roots}::{}", primary(heads)?)),
+                (false, true) => write_descendants(&roots, generation_from_roots),
+                (false, false) => (
+                    INTERSECTION,
+                    format!(
+                        "{} & ::{}",
+                        at_least(
+                            INTERSECTION,
+                            write_descendants(&roots, generation_from_roots)
+                        ),
+                        primary(heads)?
+                    ),
+                ),
+            }
+        }
+        Expr::Reachable { sources, domain } => (
+            PRIMARY,
+            format!("reachable({}, {})", argument(sources)?, argument(domain)?),
+        ),
+        Expr::Heads(expr) => (PRIMARY, format!("heads({})", argument(expr)?)),
+        Expr::HeadsRange {
+            roots,
+            heads,
+            parents_range,
+            filter,
+        } => {
+            let mut candidates =
+                write_ancestors(&primary(heads)?, &GENERATION_RANGE_FULL, parents_range)?;
+            if !roots.is_none() {
+                candidates = (
+                    INTERSECTION,
+                    format!(
+                        "{} ~ ::{}",
+                        at_least(INTERSECTION, candidates),
+                        primary(roots)?
+                    ),
+                );
+            }
+            if let Some(filter) = filter {
+                candidates = (
+                    INTERSECTION,
+                    format!(
+                        "{} & {}",
+                        at_least(INTERSECTION, candidates),
+                        at_least(NEGATION, write_predicate(filter, options)?)
+                    ),
+                );
+            }
+            (PRIMARY, format!("heads({})", candidates.1))
+        }
+        Expr::Roots(expr) => (PRIMARY, format!("roots({})", argument(expr)?)),
+        Expr::ForkPoint(expr) => (PRIMARY, format!("fork_point({})", argument(expr)?)),
+        Expr::Bisect(expr) => (PRIMARY, format!("bisect({})", argument(expr)?)),
+        Expr::HasSize { candidates, count } => (
+            PRIMARY,
+            format!("exactly({}, {count})", argument(candidates)?),
+        ),
+        Expr::Latest { candidates, count } => (
+            PRIMARY,
+            format!("latest({}, {count})", argument(candidates)?),
+        ),
+        Expr::Coalesce(exprs) => (
+            PRIMARY,
+            format!(
+                "coalesce({})",
+                exprs
+                    .iter()
+                    .map(argument)
+                    .try_collect::<_, Vec<_>, _>()?
+                    .join(", ")
+            ),
+        ),
+        Expr::Union(exprs) => (
+            UNION,
+            exprs
+                .iter()
+                .map(|expr| write_expr(expr, options).map(|result| at_least(UNION, result)))
+                .try_collect::<_, Vec<_>, _>()?
+                .join(" | "),
+        ),
+        Expr::FilterWithin {
+            candidates,
+            predicate,
+        } => (
+            INTERSECTION,
+            format!(
+                "{} & {}",
+                at_least(INTERSECTION, write_expr(candidates, options)?),
+                at_least(NEGATION, write_predicate(predicate, options)?)
+            ),
+        ),
+        Expr::Intersection(exprs) => (
+            INTERSECTION,
+            exprs
+                .iter()
+                .map(|expr| write_expr(expr, options).map(|result| at_least(NEGATION, result)))
+                .try_collect::<_, Vec<_>, _>()?
+                .join(" & "),
+        ),
+        Expr::Difference(expr1, expr2) => (
+            INTERSECTION,
+            format!(
+                "{} ~ {}",
+                at_least(INTERSECTION, write_expr(expr1, options)?),
+                at_least(NEGATION, write_expr(expr2, options)?)
+            ),
+        ),
+    })
+}
+
+fn write_reference(reference: &ResolvedReference) -> String {
+    // Problems with ID prefixes are only displayed, so the prefix is written
+    // as it was in the revset
+    let name = if *reference == ResolvedReference::visible_heads_or_referenced() {
+        ResolvedReference::visible_heads().name
+    } else {
+        reference.name.clone()
+    };
+    match &reference.operation {
+        Some(operation) => format!("at_operation({operation}, {name})"),
+        None => name.into_owned(),
+    }
+}
+
+/// Writes the ancestors of `heads` in the range of generations. Parents are
+/// followed first to reach the start of the range, since jj combines nested
+/// ancestors into a single range of generations.
+fn write_ancestors(
+    heads: &str,
+    generation: &Range<u64>,
+    parents_range: &Range<u32>,
+) -> anyhow::Result<(u8, String)> {
+    let first_parent = if *parents_range == PARENTS_RANGE_FULL {
+        false
+    } else if *parents_range == (0..1) {
+        true
+    } else {
+        bail!("Only the first parent can be selected in a revset");
+    };
+    let mut start = heads.to_owned();
+    for _ in 0..generation.start {
+        start = if first_parent {
+            format!("first_parent({start})")
+        } else {
+            format!("{start}-")
+        };
+    }
+    let depth = generation.end.saturating_sub(generation.start);
+    Ok(match (generation.end == u64::MAX, first_parent) {
+        _ if depth == 1 => (PRIMARY, start),
+        (true, false) => (RANGE, format!("::{start}")),
+        (true, true) => (PRIMARY, format!("first_ancestors({start})")),
+        (false, false) => (PRIMARY, format!("ancestors({start}, {depth})")),
+        (false, true) => (PRIMARY, format!("first_ancestors({start}, {depth})")),
+    })
+}
+
+/// Writes the descendants of `roots` in the range of generations, similarly to
+/// [`write_ancestors`].
+fn write_descendants(roots: &str, generation: &Range<u64>) -> (u8, String) {
+    let start = format!("{roots}{}", "+".repeat(generation.start as usize));
+    let depth = generation.end.saturating_sub(generation.start);
+    if depth == 1 {
+        (PRIMARY, start)
+    } else if generation.end == u64::MAX {
+        (RANGE, format!("{start}::"))
+    } else {
+        (PRIMARY, format!("descendants({start}, {depth})"))
+    }
+}
+
+fn write_predicate(predicate: &Predicate, options: &FormatOptions) -> anyhow::Result<(u8, String)> {
+    Ok(match predicate {
+        Predicate::Filter(RevsetFilterPredicate::File(FilesetExpression::All)) => {
+            (NEGATION, "~empty()".into())
+        }
+        Predicate::NotIn(predicate)
+            if matches!(
+                predicate.as_ref(),
+                Predicate::Filter(RevsetFilterPredicate::File(FilesetExpression::All))
+            ) =>
+        {
+            (PRIMARY, "empty()".into())
+        }
+        Predicate::Filter(filter) => (PRIMARY, write_filter(filter, options)?),
+        Predicate::Set(expr) => write_expr(expr, options)?,
+        Predicate::NotIn(predicate) => (
+            NEGATION,
+            format!(
+                "~{}",
+                at_least(NEGATION, write_predicate(predicate, options)?)
+            ),
+        ),
+        Predicate::Union(predicates) => (
+            UNION,
+            predicates
+                .iter()
+                .map(|predicate| {
+                    write_predicate(predicate, options).map(|result| at_least(UNION, result))
+                })
+                .try_collect::<_, Vec<_>, _>()?
+                .join(" | "),
+        ),
+        Predicate::Intersection(predicates) => (
+            INTERSECTION,
+            predicates
+                .iter()
+                .map(|predicate| {
+                    write_predicate(predicate, options).map(|result| at_least(NEGATION, result))
+                })
+                .try_collect::<_, Vec<_>, _>()?
+                .join(" & "),
+        ),
+    })
+}
+
+fn write_filter(filter: &RevsetFilterPredicate, options: &FormatOptions) -> anyhow::Result<String> {
+    Ok(match filter {
+        RevsetFilterPredicate::ParentCount(range) if *range != (2..u32::MAX) => {
+            bail!("Only `merges()` can filter by the number of parents in a revset");
+        }
+        RevsetFilterPredicate::AuthorDate(pattern) => {
+            format!("author_date({})", write_date_pattern(pattern))
+        }
+        RevsetFilterPredicate::CommitterDate(pattern) => {
+            format!("committer_date({})", write_date_pattern(pattern))
+        }
+        RevsetFilterPredicate::Extension(_) => {
+            bail!("Filters from extensions can't be written as a revset");
+        }
+        _ => filter_to_string(filter, options).into_owned(),
+    })
+}
+
+fn write_date_pattern(pattern: &DatePattern) -> String {
+    // The timestamp contains `:` and `+`, so it must be quoted
+    let (kind, millis_since_epoch) = match pattern {
+        DatePattern::AtOrAfter(millis_since_epoch) => ("after", millis_since_epoch),
+        DatePattern::Before(millis_since_epoch) => ("before", millis_since_epoch),
+    };
+    let date_time =
+        chrono::DateTime::from_timestamp_millis(millis_since_epoch.0).expect("valid date-time");
+    format!("{kind}:{:?}", date_time.to_rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::parse_expr;
+
+    /// Normalizes a revset, checking that normalizing the result again gives
+    /// the same revset.
+    fn round_trip(input: &str) -> String {
+        let options = FormatOptions::default();
+        let normalized = normalize(&parse_expr(input), &options).unwrap();
+        assert_eq!(
+            normalize(&parse_expr(&normalized), &options).unwrap(),
+            normalized,
+            "{input}"
+        );
+        normalized
+    }
+
+    #[test]
+    fn operators_are_parenthesized_by_precedence() {
+        assert_eq!(round_trip("~(a | b)"), "all() ~ (a | b)");
+        assert_eq!(round_trip("a ~ (b ~ c)"), "a ~ (b ~ c)");
+        assert_eq!(round_trip("x.. & y"), "y ~ ::x");
+        assert_eq!(round_trip("(a | b) & c"), "(a | b) & c");
+        assert_eq!(round_trip("a | b & c"), "a | b & c");
+        assert_eq!(round_trip("(x | y)::"), "(x | y)::");
+    }
+
+    #[test]
+    fn functions_are_written_with_their_arguments() {
+        assert_eq!(round_trip("first_ancestors(x, 3)"), "first_ancestors(x, 3)");
+        assert_eq!(round_trip("ancestors(x, 3)"), "ancestors(x, 3)");
+        assert_eq!(round_trip("all()"), "all()");
+        assert_eq!(round_trip("x & ~empty()"), "x & ~empty()");
+    }
+
+    #[test]
+    fn references_at_an_operation_are_written_as_revsets() {
+        let options = FormatOptions::default();
+        let expr = Expr::Reference(ResolvedReference {
+            operation: Some("abc".into()),
+            problem: Some("ambiguous"),
+            ..ResolvedReference::new_static("commit_id(ab)")
+        });
+        assert_eq!(
+            normalize(&expr, &options).unwrap(),
+            "at_operation(abc, commit_id(ab))"
+        );
+    }
+}
@@ -1,12 +1,18 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context as _;
 use async_trait::async_trait;
 use futures::stream::BoxStream;
 use indexmap::IndexSet;
+use itertools::Itertools as _;
 use jj_lib::backend::Backend;
 use jj_lib::backend::BackendResult;
 use jj_lib::backend::ChangeId;
@@ -20,6 +26,8 @@ use jj_lib::backend::SigningFn;
 use jj_lib::backend::SymlinkId;
 use jj_lib::backend::Tree;
 use jj_lib::backend::TreeId;
+use jj_lib::dsl_util;
+use jj_lib::dsl_util::AliasId;
 use jj_lib::index::Index;
 use jj_lib::index::IndexResult;
 use jj_lib::index::ResolvedChangeTargets;
@@ -34,11 +42,21 @@ use jj_lib::repo::ReadonlyRepo;
 use jj_lib::repo::Repo;
 use jj_lib::repo_path::RepoPath;
 use jj_lib::repo_path::RepoPathBuf;
+use jj_lib::revset::ExpressionKind;
+use jj_lib::revset::ExpressionNode;
+use jj_lib::revset::FunctionCallNode;
+use jj_lib::revset::LoweringContext;
+use jj_lib::revset::ResolvedExpression;
 use jj_lib::revset::ResolvedRevsetExpression;
+use jj_lib::revset::RevsetAliasesMap;
 use jj_lib::revset::RevsetCommitRef;
 use jj_lib::revset::RevsetDiagnostics;
 use jj_lib::revset::RevsetExpression;
+use jj_lib::revset::RevsetExtensions;
+use jj_lib::revset::RevsetFilterExtension;
+use jj_lib::revset::RevsetFilterPredicate;
 use jj_lib::revset::RevsetParseContext;
+use jj_lib::revset::RevsetParseError;
 use jj_lib::revset::UserRevsetExpression;
 use jj_lib::revset::{self};
 use jj_lib::signing::Signer;
@@ -53,13 +71,83 @@ use tokio::io::AsyncRead;
 use crate::expr::Expr;
 use crate::expr::ResolvedReference;
 use crate::print::format_string_expression;
+use crate::repo::resolve_id_prefix;
+use crate::repo::resolve_operation;
+
+/// How long each step of parsing a revset took.
+#[derive(Clone, Debug, Default)]
+pub struct Timings {
+    /// Parsing the revset and expanding aliases.
+    pub parse: Duration,
+    /// Resolving references to symbols, bookmarks, and tags.
+    pub resolve: Duration,
+    /// Optimizing the resolved expression.
+    pub optimize: Duration,
+    /// Converting the expression into a backend expression.
+    pub lower: Duration,
+}
+
+impl Timings {
+    /// Returns the name and duration of each step in the order they run.
+    pub fn steps(&self) -> [(&'static str, Duration); 4] {
+        [
+            ("parse", self.parse),
+            ("resolve", self.resolve),
+            ("optimize", self.optimize),
+            ("to_backend_expression", self.lower),
+        ]
+    }
+}
 
 pub fn parse<'a>(
+    diagnostics: &mut RevsetDiagnostics,
     input: &str,
     context: &RevsetParseContext,
     reference_map: &'a mut ReferenceMap,
     optimize: bool,
+    timings: &mut Timings,
 ) -> anyhow::Result<Expr<'a>> {
+    let backend = parse_backend(
+        diagnostics,
+        input,
+        context,
+        reference_map,
+        optimize,
+        timings,
+    )?;
+    Ok(Expr::parse(backend, reference_map))
+}
+
+pub fn parse_user(
+    diagnostics: &mut RevsetDiagnostics,
+    input: &str,
+    context: &RevsetParseContext,
+) -> anyhow::Result<Arc<UserRevsetExpression>> {
+    revset::parse(diagnostics, input, context).context("Failed to parse revset")
+}
+
+pub fn parse_backend(
+    diagnostics: &mut RevsetDiagnostics,
+    input: &str,
+    context: &RevsetParseContext,
+    reference_map: &mut ReferenceMap,
+    optimize: bool,
+    timings: &mut Timings,
+) -> anyhow::Result<ResolvedExpression> {
+    let start = Instant::now();
+    let parsed = parse_user(diagnostics, input, context)?;
+    timings.parse = start.elapsed();
+    Ok(resolve_backend(&parsed, reference_map, optimize, timings))
+}
+
+/// Resolves a revset which was already parsed by [`parse_user`], so that the
+/// same parse can be reused.
+pub fn resolve_backend(
+    parsed: &UserRevsetExpression,
+    reference_map: &mut ReferenceMap,
+    optimize: bool,
+    timings: &mut Timings,
+) -> ResolvedExpression {
     let dummy_backend: Box<dyn Backend> = Box::new(DummyBackend {
         root_commit_id: reference_map.insert(ResolvedReference::root()),
     });
@@ -85,112 +173,109 @@ pub fn parse<'a>(
         }),
     };
 
-    let mut diagnostics = RevsetDiagnostics::new();
-    let parsed =
-        revset::parse(&mut diagnostics, input, context).context("Failed to parse revset")?;
-    let mut resolved = resolve_user_expressions(&parsed, None, reference_map);
+    let start = Instant::now();
+    let mut resolved = resolve_user_expressions(parsed, ReferenceScope::default(), reference_map);
+    timings.resolve = start.elapsed();
     if optimize {
+        let start = Instant::now();
         resolved = revset::optimize(resolved);
+        timings.optimize = start.elapsed();
     }
+    let start = Instant::now();
     let backend = resolved.to_backend_expression(&dummy_repo);
-    Ok(Expr::parse(backend, reference_map))
+    timings.lower = start.elapsed();
+    backend
+}
+
+/// Information about where a reference appears which affects how it's
+/// displayed, since it is lost once the reference is resolved.
+#[derive(Clone, Copy, Debug, Default)]
+struct ReferenceScope<'a> {
+    /// The operation from an enclosing `at_operation()`.
+    operation: Option<&'a str>,
+    /// Whether the reference is inside of `present()`, so it's allowed to be
+    /// missing.
+    present: bool,
+    /// The innermost alias which the reference was expanded from.
+    alias: Option<&'a str>,
+    /// Whether the expression is inside of a [`PreservedFilter`].
+    preserved: bool,
+}
+
+/// A combination of filters which is hidden from the optimizer, so that it is
+/// displayed as it was written. jj doesn't allow disabling only some of its
+/// optimizations, so the filters are wrapped in a filter from an extension
+/// instead, which the optimizer can move but can't look inside of.
+#[derive(Debug)]
+pub struct PreservedFilter(pub Arc<ResolvedRevsetExpression>);
+
+impl RevsetFilterExtension for PreservedFilter {
+    fn matches_commit(&self, _commit: &jj_lib::commit::Commit) -> bool {
+        unreachable!("preserved filters are only used for display and are never evaluated")
+    }
+}
+
+/// Checks whether the expression only combines filters, such as
+/// `description(x) & ~merges()`.
+fn is_filter_combination(expr: &UserRevsetExpression) -> bool {
+    match expr {
+        RevsetExpression::Filter(_) => true,
+        RevsetExpression::NotIn(expr) => is_filter_combination(expr),
+        RevsetExpression::Union(expr1, expr2)
+        | RevsetExpression::Intersection(expr1, expr2)
+        | RevsetExpression::Difference(expr1, expr2) => {
+            is_filter_combination(expr1) && is_filter_combination(expr2)
+        }
+        _ => false,
+    }
 }
 
 fn resolve_user_expressions(
     expr: &UserRevsetExpression,
-    operation: Option<&str>,
+    scope: ReferenceScope,
     reference_map: &mut ReferenceMap,
 ) -> Arc<ResolvedRevsetExpression> {
+    // A single filter is never changed by the optimizer, so it isn't wrapped
+    if reference_map.preserve_filters
+        && !scope.preserved
+        && !matches!(expr, RevsetExpression::Filter(_))
+        && is_filter_combination(expr)
+    {
+        let scope = ReferenceScope {
+            preserved: true,
+            ..scope
+        };
+        let filter = resolve_user_expressions(expr, scope, reference_map);
+        return Arc::new(RevsetExpression::Filter(RevsetFilterPredicate::Extension(
+            Arc::new(PreservedFilter(filter)),
+        )));
+    }
     let mapped = match expr {
         RevsetExpression::None => RevsetExpression::None,
         RevsetExpression::All => RevsetExpression::All,
         RevsetExpression::VisibleHeads => RevsetExpression::VisibleHeads,
         RevsetExpression::VisibleHeadsOrReferenced => RevsetExpression::VisibleHeadsOrReferenced,
+        // The root is only displayed as a reference if it needs an annotation,
+        // and it's the same in every operation
+        RevsetExpression::Root if scope.alias.is_some() => {
+            let scope = ReferenceScope {
+                alias: scope.alias,
+                ..ReferenceScope::default()
+            };
+            insert_reference(ResolvedReference::root(), scope, reference_map)
+        }
         RevsetExpression::Root => RevsetExpression::Root,
         RevsetExpression::Commits(commit_ids) => RevsetExpression::Commits(commit_ids.clone()),
         RevsetExpression::CommitRef(reference) => {
-            let resolved = match reference {
-                RevsetCommitRef::WorkingCopy(workspace) if workspace == WorkspaceName::DEFAULT => {
-                    ResolvedReference::working_copy()
-                }
-                RevsetCommitRef::WorkingCopy(workspace) => {
-                    ResolvedReference::new_owned(format!("{}@", workspace.as_str()))
-                }
-                RevsetCommitRef::WorkingCopies => ResolvedReference::new_static("working_copies()"),
-                RevsetCommitRef::Symbol(symbol) => ResolvedReference::new_owned(symbol.clone()),
-                RevsetCommitRef::RemoteSymbol(symbol) => {
-                    ResolvedReference::new_owned(symbol.to_string())
-                }
-                RevsetCommitRef::ChangeId(hex_prefix) => {
-                    ResolvedReference::new_owned(format!("change_id({})", hex_prefix.reverse_hex()))
-                }
-                RevsetCommitRef::CommitId(hex_prefix) => {
-                    ResolvedReference::new_owned(format!("commit_id({})", hex_prefix.hex()))
-                }
-                RevsetCommitRef::Bookmarks(StringExpression::Pattern(p)) if is_all_pattern(p) => {
-                    ResolvedReference::new_static("bookmarks()")
-                }
-                RevsetCommitRef::Bookmarks(bookmark) => ResolvedReference::new_owned(format!(
-                    "bookmarks({})",
-                    format_string_expression(bookmark)
-                )),
-                RevsetCommitRef::RemoteBookmarks {
-                    bookmark: StringExpression::Pattern(b),
-                    remote: StringExpression::Pattern(r),
-                    remote_ref_state,
-                } if is_all_pattern(b) && is_all_pattern(r) => match remote_ref_state {
-                    None => ResolvedReference::new_static("remote_bookmarks()"),
-                    Some(RemoteRefState::New) => {
-                        ResolvedReference::new_static("untracked_remote_bookmarks()")
-                    }
-                    Some(RemoteRefState::Tracked) => {
-                        ResolvedReference::new_static("tracked_remote_bookmarks()")
-                    }
-                },
-                RevsetCommitRef::RemoteBookmarks {
-                    bookmark,
-                    remote,
-                    remote_ref_state,
-                } => match remote_ref_state {
-                    None => ResolvedReference::new_owned(format!(
-                        "remote_bookmarks({}, remote={})",
-                        format_string_expression(bookmark),
-                        format_string_expression(remote)
-                    )),
-                    Some(RemoteRefState::New) => ResolvedReference::new_owned(format!(
-                        "untracked_remote_bookmarks({}, remote={})",
-                        format_string_expression(bookmark),
-                        format_string_expression(remote)
-                    )),
-                    Some(RemoteRefState::Tracked) => ResolvedReference::new_owned(format!(
-                        "tracked_remote_bookmarks({}, remote={})",
-                        format_string_expression(bookmark),
-                        format_string_expression(remote)
-                    )),
-                },
-                RevsetCommitRef::Tags(StringExpression::Pattern(p)) if is_all_pattern(p) => {
-                    ResolvedReference::new_static("tags()")
-                }
-                RevsetCommitRef::Tags(tag) => {
-                    ResolvedReference::new_owned(format!("tags({})", format_string_expression(tag)))
-                }
-                RevsetCommitRef::GitRefs => ResolvedReference::new_static("git_refs()"),
-                RevsetCommitRef::GitHead => ResolvedReference::new_static("git_head()"),
-            };
-            if let Some(operation) = operation {
-                let with_operation =
-                    ResolvedReference::new_owned(format!("{resolved} at operation {operation}"));
-                RevsetExpression::Commits(vec![reference_map.insert(with_operation)])
-            } else {
-                RevsetExpression::Commits(vec![reference_map.insert(resolved)])
-            }
+            let resolved = reference_map.resolve_commit_ref(reference);
+            insert_reference(resolved, scope, reference_map)
         }
         RevsetExpression::Ancestors {
             heads,
             generation,
             parents_range,
         } => {
-            let heads = resolve_user_expressions(heads, operation, reference_map);
+            let heads = resolve_user_expressions(heads, scope, reference_map);
             let generation = generation.clone();
             let parents_range = parents_range.clone();
             RevsetExpression::Ancestors {
@@ -200,7 +285,7 @@ fn resolve_user_expressions(
             }
         }
         RevsetExpression::Descendants { roots, generation } => {
-            let roots = resolve_user_expressions(roots, operation, reference_map);
+            let roots = resolve_user_expressions(roots, scope, reference_map);
             let generation = generation.clone();
             RevsetExpression::Descendants { roots, generation }
         }
@@ -210,8 +295,8 @@ fn resolve_user_expressions(
             generation,
             parents_range,
         } => {
-            let roots = resolve_user_expressions(roots, operation, reference_map);
-            let heads = resolve_user_expressions(heads, operation, reference_map);
+            let roots = resolve_user_expressions(roots, scope, reference_map);
+            let heads = resolve_user_expressions(heads, scope, reference_map);
             let generation = generation.clone();
             let parents_range = parents_range.clone();
             RevsetExpression::Range {
@@ -222,17 +307,17 @@ fn resolve_user_expressions(
             }
         }
         RevsetExpression::DagRange { roots, heads } => {
-            let roots = resolve_user_expressions(roots, operation, reference_map);
-            let heads = resolve_user_expressions(heads, operation, reference_map);
+            let roots = resolve_user_expressions(roots, scope, reference_map);
+            let heads = resolve_user_expressions(heads, scope, reference_map);
             RevsetExpression::DagRange { roots, heads }
         }
         RevsetExpression::Reachable { sources, domain } => {
-            let sources = resolve_user_expressions(sources, operation, reference_map);
-            let domain = resolve_user_expressions(domain, operation, reference_map);
+            let sources = resolve_user_expressions(sources, scope, reference_map);
+            let domain = resolve_user_expressions(domain, scope, reference_map);
             RevsetExpression::Reachable { sources, domain }
         }
         RevsetExpression::Heads(heads) => {
-            let heads = resolve_user_expressions(heads, operation, reference_map);
+            let heads = resolve_user_expressions(heads, scope, reference_map);
             RevsetExpression::Heads(heads)
         }
         RevsetExpression::HeadsRange {
@@ -241,10 +326,10 @@ fn resolve_user_expressions(
             parents_range,
             filter,
         } => {
-            let roots = resolve_user_expressions(roots, operation, reference_map);
-            let heads = resolve_user_expressions(heads, operation, reference_map);
+            let roots = resolve_user_expressions(roots, scope, reference_map);
+            let heads = resolve_user_expressions(heads, scope, reference_map);
             let parents_range = parents_range.clone();
-            let filter = resolve_user_expressions(filter, operation, reference_map);
+            let filter = resolve_user_expressions(filter, scope, reference_map);
             RevsetExpression::HeadsRange {
                 roots,
                 heads,
@@ -253,42 +338,48 @@ fn resolve_user_expressions(
             }
         }
         RevsetExpression::Roots(roots) => {
-            let roots = resolve_user_expressions(roots, operation, reference_map);
+            let roots = resolve_user_expressions(roots, scope, reference_map);
             RevsetExpression::Roots(roots)
         }
         RevsetExpression::ForkPoint(expression) => {
-            let expression = resolve_user_expressions(expression, operation, reference_map);
+            let expression = resolve_user_expressions(expression, scope, reference_map);
             RevsetExpression::ForkPoint(expression)
         }
         RevsetExpression::Bisect(expression) => {
-            let expression = resolve_user_expressions(expression, operation, reference_map);
+            let expression = resolve_user_expressions(expression, scope, reference_map);
             RevsetExpression::Bisect(expression)
         }
         RevsetExpression::HasSize { candidates, count } => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             RevsetExpression::HasSize {
                 candidates,
                 count: *count,
             }
         }
         RevsetExpression::Latest { candidates, count } => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             let count = *count;
             RevsetExpression::Latest { candidates, count }
         }
         RevsetExpression::Filter(predicate) => RevsetExpression::Filter(predicate.clone()),
         RevsetExpression::AsFilter(candidates) => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             RevsetExpression::AsFilter(candidates)
         }
         RevsetExpression::AtOperation {
             candidates,
             operation,
         } => {
-            let candidates = resolve_user_expressions(candidates, Some(operation), reference_map);
-            let visible_heads = vec![reference_map.insert(ResolvedReference(
-                format!("visible_heads() at operation {operation}").into(),
-            ))];
+            let operation = reference_map.resolve_operation(operation);
+            let scope = ReferenceScope {
+                operation: Some(&operation),
+                ..scope
+            };
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
+            let visible_heads = vec![reference_map.insert(ResolvedReference {
+                operation: Some(operation.clone().into()),
+                ..ResolvedReference::visible_heads()
+            })];
             RevsetExpression::WithinVisibility {
                 candidates,
                 visible_heads,
@@ -298,7 +389,7 @@ fn resolve_user_expressions(
             candidates,
             commits,
         } => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             let commits = commits.clone();
             RevsetExpression::WithinReference {
                 candidates,
@@ -309,45 +400,420 @@ fn resolve_user_expressions(
             candidates,
             visible_heads,
         } => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             let visible_heads = visible_heads.clone();
             RevsetExpression::WithinVisibility {
                 candidates,
                 visible_heads,
             }
         }
+        RevsetExpression::Coalesce(marker, definition)
+            if let Some(alias) = alias_origin(marker) =>
+        {
+            let scope = ReferenceScope { alias, ..scope };
+            return resolve_user_expressions(definition, scope, reference_map);
+        }
         RevsetExpression::Coalesce(expression1, expression2) => {
-            let expression1 = resolve_user_expressions(expression1, operation, reference_map);
-            let expression2 = resolve_user_expressions(expression2, operation, reference_map);
+            let expression1 = resolve_user_expressions(expression1, scope, reference_map);
+            let expression2 = resolve_user_expressions(expression2, scope, reference_map);
             RevsetExpression::Coalesce(expression1, expression2)
         }
         RevsetExpression::Present(candidates) => {
-            let candidates = resolve_user_expressions(candidates, operation, reference_map);
+            let scope = ReferenceScope {
+                present: true,
+                ..scope
+            };
+            let candidates = resolve_user_expressions(candidates, scope, reference_map);
             RevsetExpression::Present(candidates)
         }
         RevsetExpression::NotIn(complement) => {
-            let complement = resolve_user_expressions(complement, operation, reference_map);
+            let complement = resolve_user_expressions(complement, scope, reference_map);
             RevsetExpression::NotIn(complement)
         }
         RevsetExpression::Union(expression1, expression2) => {
-            let expression1 = resolve_user_expressions(expression1, operation, reference_map);
-            let expression2 = resolve_user_expressions(expression2, operation, reference_map);
+            let expression1 = resolve_user_expressions(expression1, scope, reference_map);
+            let expression2 = resolve_user_expressions(expression2, scope, reference_map);
             RevsetExpression::Union(expression1, expression2)
         }
         RevsetExpression::Intersection(expression1, expression2) => {
-            let expression1 = resolve_user_expressions(expression1, operation, reference_map);
-            let expression2 = resolve_user_expressions(expression2, operation, reference_map);
+            let expression1 = resolve_user_expressions(expression1, scope, reference_map);
+            let expression2 = resolve_user_expressions(expression2, scope, reference_map);
             RevsetExpression::Intersection(expression1, expression2)
         }
         RevsetExpression::Difference(expression1, expression2) => {
-            let expression1 = resolve_user_expressions(expression1, operation, reference_map);
-            let expression2 = resolve_user_expressions(expression2, operation, reference_map);
+            let expression1 = resolve_user_expressions(expression1, scope, reference_map);
+            let expression2 = resolve_user_expressions(expression2, scope, reference_map);
             RevsetExpression::Difference(expression1, expression2)
         }
     };
     Arc::new(mapped)
 }
 
+fn insert_reference(
+    mut resolved: ResolvedReference<'static>,
+    scope: ReferenceScope,
+    reference_map: &mut ReferenceMap,
+) -> ResolvedRevsetExpression {
+    if scope.present {
+        resolved.name = format!("present({})", resolved.name).into();
+    }
+    resolved.operation = scope.operation.map(|operation| operation.to_owned().into());
+    resolved.alias_origin = scope.alias.map(|alias| alias.to_owned().into());
+    RevsetExpression::Commits(vec![reference_map.insert(resolved)])
+}
+
+/// The names of jj's builtin revset functions, which are offered as
+/// completions. The internal `__jj_analyze_*` functions from
+/// [`revset_extensions`] are left out since they can't be written by users.
+pub const REVSET_FUNCTIONS: [&str; 46] = [
+    "all",
+    "ancestors",
+    "at_operation",
+    "author",
+    "author_date",
+    "author_email",
+    "author_name",
+    "bisect",
+    "bookmarks",
+    "change_id",
+    "children",
+    "coalesce",
+    "commit_id",
+    "committer",
+    "committer_date",
+    "committer_email",
+    "committer_name",
+    "conflicts",
+    "connected",
+    "descendants",
+    "description",
+    "diff_contains",
+    "empty",
+    "exactly",
+    "files",
+    "first_ancestors",
+    "first_parent",
+    "fork_point",
+    "git_head",
+    "git_refs",
+    "heads",
+    "latest",
+    "merges",
+    "mine",
+    "none",
+    "parents",
+    "present",
+    "reachable",
+    "remote_bookmarks",
+    "root",
+    "roots",
+    "signed",
+    "subject",
+    "tags",
+    "visible_heads",
+    "working_copies",
+];
+
+/// Prefix of the symbol used to mark a collapsed alias which takes arguments.
+/// A NUL character is used so that it can't conflict with a real symbol.
+pub const COLLAPSED_ALIAS_PREFIX: &str = "\0collapsed:";
+
+/// Name of the function which replaces a collapsed alias with parameters.
+const COLLAPSED_ALIAS_FUNCTION: &str = "__jj_analyze_collapsed";
+
+/// Returns the extensions to use when parsing revsets, which define the
+/// functions used by collapsed aliases and [`parse_user_with_origins`].
+pub fn revset_extensions() -> RevsetExtensions {
+    let mut extensions = RevsetExtensions::new();
+    extensions.add_custom_function(COLLAPSED_ALIAS_FUNCTION, lower_collapsed_alias);
+    extensions.add_custom_function(ALIAS_ORIGINS_FUNCTION, lower_alias_origins);
+    extensions
+}
+
+/// Returns the definition to use for a collapsed alias. An alias without
+/// parameters is replaced with a symbol containing its name. An alias with
+/// parameters is replaced with a call to [`COLLAPSED_ALIAS_FUNCTION`], which
+/// displays the arguments as they were written, so they don't need to be
+/// revsets.
+pub fn collapsed_alias_definition(declaration: &str) -> String {
+    let Some((name, params)) = declaration
+        .strip_suffix(')')
+        .and_then(|declaration| declaration.split_once('('))
+    else {
+        return format!("{declaration:?}");
+    };
+    if params.trim().is_empty() {
+        return format!("{declaration:?}");
+    }
+    format!("{COLLAPSED_ALIAS_FUNCTION}({:?}, {params})", name.trim())
+}
+
+/// Lowers a call to [`COLLAPSED_ALIAS_FUNCTION`] into a symbol starting with
+/// [`COLLAPSED_ALIAS_PREFIX`], followed by the call to display, such as
+/// `mine_in(src)`.
+fn lower_collapsed_alias(
+    _diagnostics: &mut RevsetDiagnostics,
+    function: &FunctionCallNode,
+    _context: &LoweringContext,
+) -> Result<Arc<UserRevsetExpression>, RevsetParseError> {
+    let ([name], arguments) = function.expect_some_arguments()?;
+    let name: String = revset::expect_literal("string", name)?;
+    let arguments = arguments
+        .iter()
+        .map(|mut argument| {
+            // Each argument is displayed as written by the caller
+            while let ExpressionKind::AliasExpanded(AliasId::Parameter(_), expanded) =
+                &argument.kind
+            {
+                argument = expanded;
+            }
+            argument.span.as_str()
+        })
+        .join(", ");
+    Ok(RevsetExpression::symbol(format!(
+        "{COLLAPSED_ALIAS_PREFIX}{name}({arguments})"
+    )))
+}
+
+/// Prefix of the symbol used to mark where an expanded alias came from.
+pub const ALIAS_ORIGIN_PREFIX: &str = "\0origin:";
+
+/// Name of the function which wraps the revset to annotate references with the
+/// alias they were expanded from.
+const ALIAS_ORIGINS_FUNCTION: &str = "__jj_analyze_origins";
+
+/// Parses a revset like [`parse_user`], but wraps the expansion of every alias
+/// in `coalesce()` with a marker symbol, which is recognized by
+/// [`alias_origin`] so that references can be annotated with the alias they
+/// came from. Arguments passed to an alias keep the origin of the caller.
+pub fn parse_user_with_origins(
+    diagnostics: &mut RevsetDiagnostics,
+    input: &str,
+    context: &RevsetParseContext,
+) -> anyhow::Result<Arc<UserRevsetExpression>> {
+    // The revset is parsed as written first, so that any errors and warnings
+    // point to the text that was written
+    parse_user(diagnostics, input, context)?;
+    parse_user(
+        &mut RevsetDiagnostics::new(),
+        &format!("{ALIAS_ORIGINS_FUNCTION}(\n{input}\n)"),
+        context,
+    )
+}
+
+/// Lowers a call to [`ALIAS_ORIGINS_FUNCTION`], which receives its argument
+/// after aliases are expanded.
+fn lower_alias_origins(
+    diagnostics: &mut RevsetDiagnostics,
+    function: &FunctionCallNode,
+    context: &LoweringContext,
+) -> Result<Arc<UserRevsetExpression>, RevsetParseError> {
+    let [input] = function.expect_exact_arguments()?;
+    let marked = mark_alias_origins(input.clone(), &mut Vec::new());
+    revset::lower_expression(diagnostics, &marked, context)
+}
+
+/// Marks the expansion of every alias in a revset position with its origin.
+/// `aliases` is the stack of aliases being expanded, since the arguments of an
+/// alias were written outside of it. Collapsed aliases are left unchanged.
+fn mark_alias_origins<'i>(
+    node: ExpressionNode<'i>,
+    aliases: &mut Vec<String>,
+) -> ExpressionNode<'i> {
+    let kind = match node.kind {
+        ExpressionKind::AliasExpanded(id, body) if is_collapsed_alias(id, &body) => {
+            ExpressionKind::AliasExpanded(id, body)
+        }
+        ExpressionKind::AliasExpanded(id, body) => {
+            let body = match id {
+                AliasId::Symbol(name) | AliasId::Function(name, _) => {
+                    aliases.push(match id {
+                        AliasId::Function(..) => format!("{name}()"),
+                        _ => name.to_owned(),
+                    });
+                    let body = mark_alias_origins(*body, aliases);
+                    let alias = aliases.pop().unwrap_or_default();
+                    with_alias_origin(body, &alias)
+                }
+                AliasId::Parameter(_) => {
+                    let alias = aliases.pop();
+                    let body = mark_alias_origins(*body, aliases);
+                    let caller = aliases.last().cloned().unwrap_or_default();
+                    aliases.extend(alias);
+                    with_alias_origin(body, &caller)
+                }
+            };
+            ExpressionKind::AliasExpanded(id, Box::new(body))
+        }
+        ExpressionKind::Unary(op, arg) => {
+            ExpressionKind::Unary(op, Box::new(mark_alias_origins(*arg, aliases)))
+        }
+        ExpressionKind::Binary(op, lhs, rhs) => ExpressionKind::Binary(
+            op,
+            Box::new(mark_alias_origins(*lhs, aliases)),
+            Box::new(mark_alias_origins(*rhs, aliases)),
+        ),
+        ExpressionKind::UnionAll(nodes) => ExpressionKind::UnionAll(
+            nodes
+                .into_iter()
+                .map(|node| mark_alias_origins(node, aliases))
+                .collect(),
+        ),
+        ExpressionKind::FunctionCall(mut function) => {
+            let name = function.name;
+            function.args = (function.args.into_iter().enumerate())
+                .map(|(index, arg)| {
+                    if is_revset_argument(name, index) {
+                        mark_alias_origins(arg, aliases)
+                    } else {
+                        arg
+                    }
+                })
+                .collect();
+            ExpressionKind::FunctionCall(function)
+        }
+        kind => kind,
+    };
+    ExpressionNode::new(kind, node.span)
+}
+
+/// Checks whether an alias was replaced by [`collapsed_alias_definition`].
+fn is_collapsed_alias(id: AliasId, body: &ExpressionNode) -> bool {
+    match (&body.kind, id) {
+        (ExpressionKind::FunctionCall(function), AliasId::Function(..)) => {
+            function.name == COLLAPSED_ALIAS_FUNCTION
+        }
+        (ExpressionKind::String(symbol), AliasId::Symbol(name) | AliasId::Function(name, _)) => {
+            symbol.strip_suffix("()").unwrap_or(symbol) == name
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether an argument of a built-in function is a revset, as opposed to
+/// a string pattern or a number.
+fn is_revset_argument(function: &str, index: usize) -> bool {
+    match function {
+        "coalesce" => true,
+        "reachable" => index < 2,
+        "at_operation" => index == 1,
+        "parents" | "children" | "ancestors" | "descendants" | "first_parent"
+        | "first_ancestors" | "connected" | "heads" | "roots" | "latest" | "fork_point"
+        | "bisect" | "exactly" | "present" => index == 0,
+        _ => false,
+    }
+}
+
+/// Wraps a node in `coalesce()` with a marker for `alias`, or with an empty
+/// marker if the node wasn't written in an alias.
+fn with_alias_origin<'i>(body: ExpressionNode<'i>, alias: &str) -> ExpressionNode<'i> {
+    let span = body.span;
+    let marker = ExpressionNode::new(
+        ExpressionKind::String(format!("{ALIAS_ORIGIN_PREFIX}{alias}")),
+        span,
+    );
+    let function = FunctionCallNode {
+        name: "coalesce",
+        name_span: span,
+        args: vec![marker, body],
+        keyword_args: vec![],
+        args_span: span,
+    };
+    ExpressionNode::new(ExpressionKind::FunctionCall(Box::new(function)), span)
+}
+
+/// If the expression is a marker from [`parse_user_with_origins`], returns the
+/// name of the alias, such as `trunk()`, or `None` if the expression wasn't
+/// written in an alias.
+fn alias_origin(expr: &UserRevsetExpression) -> Option<Option<&str>> {
+    let alias = expr.as_symbol()?.strip_prefix(ALIAS_ORIGIN_PREFIX)?;
+    Some((!alias.is_empty()).then_some(alias))
+}
+
+/// Expands an alias using its declaration, such as `mine_in(path)`, so that
+/// errors like recursive definitions are reported even if the alias is unused.
+/// The parameters are left as symbols, since they can't refer to aliases.
+pub fn check_alias(
+    declaration: &str,
+    aliases_map: &RevsetAliasesMap,
+) -> Result<(), RevsetParseError> {
+    let node = revset::parse_program(declaration)?;
+    dsl_util::expand_aliases(node, aliases_map)?;
+    Ok(())
+}
+
+pub fn resolve_commit_ref(reference: &RevsetCommitRef) -> ResolvedReference<'static> {
+    match reference {
+        RevsetCommitRef::WorkingCopy(workspace) if workspace == WorkspaceName::DEFAULT => {
+            ResolvedReference::working_copy()
+        }
+        RevsetCommitRef::WorkingCopy(workspace) => {
+            ResolvedReference::new_owned(format!("{}@", workspace.as_str()))
+        }
+        RevsetCommitRef::WorkingCopies => ResolvedReference::new_static("working_copies()"),
+        RevsetCommitRef::Symbol(symbol) => match symbol.strip_prefix(COLLAPSED_ALIAS_PREFIX) {
+            Some(call) => ResolvedReference::new_owned(call.to_owned()),
+            None => ResolvedReference::new_owned(symbol.clone()),
+        },
+        RevsetCommitRef::RemoteSymbol(symbol) => ResolvedReference::new_owned(symbol.to_string()),
+        RevsetCommitRef::ChangeId(hex_prefix) => {
+            ResolvedReference::new_owned(format!("change_id({})", hex_prefix.reverse_hex()))
+        }
+        RevsetCommitRef::CommitId(hex_prefix) => {
+            ResolvedReference::new_owned(format!("commit_id({})", hex_prefix.hex()))
+        }
+        RevsetCommitRef::Bookmarks(StringExpression::Pattern(p)) if is_all_pattern(p) => {
+            ResolvedReference::new_static("bookmarks()")
+        }
+        RevsetCommitRef::Bookmarks(bookmark) => ResolvedReference::new_owned(format!(
+            "bookmarks({})",
+            format_string_expression(bookmark, None)
+        )),
+        RevsetCommitRef::RemoteBookmarks {
+            bookmark: StringExpression::Pattern(b),
+            remote: StringExpression::Pattern(r),
+            remote_ref_state,
+        } if is_all_pattern(b) && is_all_pattern(r) => match remote_ref_state {
+            None => ResolvedReference::new_static("remote_bookmarks()"),
+            Some(RemoteRefState::New) => {
+                ResolvedReference::new_static("untracked_remote_bookmarks()")
+            }
+            Some(RemoteRefState::Tracked) => {
+                ResolvedReference::new_static("tracked_remote_bookmarks()")
+            }
+        },
+        RevsetCommitRef::RemoteBookmarks {
+            bookmark,
+            remote,
+            remote_ref_state,
+        } => match remote_ref_state {
+            None => ResolvedReference::new_owned(format!(
+                "remote_bookmarks({}, remote={})",
+                format_string_expression(bookmark, None),
+                format_string_expression(remote, None)
+            )),
+            Some(RemoteRefState::New) => ResolvedReference::new_owned(format!(
+                "untracked_remote_bookmarks({}, remote={})",
+                format_string_expression(bookmark, None),
+                format_string_expression(remote, None)
+            )),
+            Some(RemoteRefState::Tracked) => ResolvedReference::new_owned(format!(
+                "tracked_remote_bookmarks({}, remote={})",
+                format_string_expression(bookmark, None),
+                format_string_expression(remote, None)
+            )),
+        },
+        RevsetCommitRef::Tags(StringExpression::Pattern(p)) if is_all_pattern(p) => {
+            ResolvedReference::new_static("tags()")
+        }
+        RevsetCommitRef::Tags(tag) => {
+            ResolvedReference::new_owned(format!("tags({})", format_string_expression(tag, None)))
+        }
+        RevsetCommitRef::GitRefs => ResolvedReference::new_static("git_refs()"),
+        RevsetCommitRef::GitHead => ResolvedReference::new_static("git_head()"),
+    }
+}
+
 fn is_all_pattern(pattern: &StringPattern) -> bool {
     matches!(pattern, StringPattern::Substring(s) if s.is_empty())
 }
@@ -364,7 +830,7 @@ impl Backend for DummyBackend {
     }
 
     fn commit_id_length(&self) -> usize {
-        (usize::BITS / 8) as usize
+        REFERENCE_ID_LENGTH
     }
 
     fn change_id_length(&self) -> usize {
@@ -503,38 +969,305 @@ impl Repo for DummyRepo {
     }
 }
 
-#[derive(Debug)]
+/// The length of the placeholder commit IDs which stand in for references. Each
+/// ID encodes the index of a reference in a `ReferenceMap`. Only the dummy
+/// backend ever sees these IDs, so they don't depend on the length of the
+/// commit IDs in a real repository.
+const REFERENCE_ID_LENGTH: usize = size_of::<u64>();
+
+/// Maps each reference in a revset to a placeholder commit ID, so that the
+/// revset can be resolved without a real repository.
+#[derive(Clone, Debug, Default)]
 pub struct ReferenceMap {
-    references: IndexSet<ResolvedReference<'static>>,
+    references: IndexSet<StoredReference>,
+    repo: Option<Arc<ReadonlyRepo>>,
+    expand_visible_heads: bool,
+    preserve_filters: bool,
 }
 
 impl ReferenceMap {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a map which uses a real repository to resolve change ID and
+    /// commit ID prefixes to full IDs.
+    pub fn with_repo(repo: Arc<ReadonlyRepo>) -> Self {
         Self {
             references: IndexSet::new(),
+            repo: Some(repo),
+            expand_visible_heads: false,
+            preserve_filters: false,
         }
     }
 
+    /// Whether sets of revisions containing `visible_heads()` should list
+    /// every reference, instead of being displayed as `visible_heads() and
+    /// referenced revisions`.
+    pub fn expand_visible_heads(&self) -> bool {
+        self.expand_visible_heads
+    }
+
+    pub fn set_expand_visible_heads(&mut self, expand_visible_heads: bool) {
+        self.expand_visible_heads = expand_visible_heads;
+    }
+
+    /// Whether combinations of filters should be hidden from the optimizer
+    /// using [`PreservedFilter`], so that they are displayed as written.
+    pub fn set_preserve_filters(&mut self, preserve_filters: bool) {
+        self.preserve_filters = preserve_filters;
+    }
+
+    fn resolve_commit_ref(&self, reference: &RevsetCommitRef) -> ResolvedReference<'static> {
+        self.repo
+            .as_ref()
+            .and_then(|repo| resolve_id_prefix(reference, repo))
+            .unwrap_or_else(|| resolve_commit_ref(reference))
+    }
+
+    /// Resolves an operation using the real repository if there is one, so
+    /// that operation ID prefixes are displayed as full IDs. Operations which
+    /// can't be resolved should already have been reported by
+    /// [`check_operations`](crate::repo::check_operations).
+    fn resolve_operation(&self, operation: &str) -> String {
+        self.repo
+            .as_ref()
+            .and_then(|repo| resolve_operation(operation, repo).ok())
+            .unwrap_or_else(|| operation.to_owned())
+    }
+
     pub fn insert(&mut self, reference: ResolvedReference<'static>) -> CommitId {
+        let reference = StoredReference(reference);
         let index = if let Some(index) = self.references.get_index_of(&reference) {
             index
         } else {
             self.references.insert_full(reference).0
         };
-        CommitId::from_bytes(&index.to_le_bytes())
+        reference_id(index)
     }
 
+    /// Returns the reference for a placeholder commit ID. Any other commit ID
+    /// is displayed as a `commit_id()` reference instead.
     pub fn get(&self, commit_id: &CommitId) -> ResolvedReference<'_> {
-        let index = usize::from_le_bytes(
-            commit_id
-                .as_bytes()
-                .try_into()
-                .expect("should have correct number of bytes"),
+        let reference = <[u8; REFERENCE_ID_LENGTH]>::try_from(commit_id.as_bytes())
+            .ok()
+            .and_then(|bytes| usize::try_from(u64::from_le_bytes(bytes)).ok())
+            .and_then(|index| self.references.get_index(index));
+        match reference {
+            Some(StoredReference(reference)) => ResolvedReference {
+                name: reference.name.as_ref().into(),
+                operation: reference.operation.as_deref().map(Cow::Borrowed),
+                problem: reference.problem,
+                alias_origin: reference.alias_origin.as_deref().map(Cow::Borrowed),
+            },
+            None => ResolvedReference::new_owned(format!("commit_id({})", commit_id.hex())),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (CommitId, &ResolvedReference<'static>)> {
+        self.references
+            .iter()
+            .enumerate()
+            .map(|(index, reference)| (reference_id(index), &reference.0))
+    }
+}
+
+/// A reference in a [`ReferenceMap`]. References are stored separately for
+/// each alias they were expanded from, so that every occurrence keeps its own
+/// origin even though the references compare equal.
+#[derive(Clone, Debug)]
+struct StoredReference(ResolvedReference<'static>);
+
+impl PartialEq for StoredReference {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0 && self.0.alias_origin == other.0.alias_origin
+    }
+}
+
+impl Eq for StoredReference {}
+
+impl Hash for StoredReference {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+        self.0.alias_origin.hash(state);
+    }
+}
+
+fn reference_id(index: usize) -> CommitId {
+    CommitId::from_bytes(&(index as u64).to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use jj_lib::revset::RevsetParseErrorKind;
+
+    use super::*;
+    use crate::print::PrintOptions;
+    use crate::print::TreeDisplay;
+    use crate::test_util::with_parse_context;
+    use crate::tree::AnalyzeContext;
+
+    #[test]
+    fn revset_functions_exist_in_jj_lib() {
+        with_parse_context(&RevsetAliasesMap::new(), |context| {
+            for name in REVSET_FUNCTIONS {
+                assert!(!name.starts_with("__jj_analyze"), "`{name}` is internal");
+                // Functions which require arguments fail with a different error
+                let result =
+                    revset::parse(&mut RevsetDiagnostics::new(), &format!("{name}()"), context);
+                if let Err(err) = result {
+                    assert!(
+                        matches!(
+                            err.kind(),
+                            RevsetParseErrorKind::InvalidFunctionArguments { .. }
+                        ),
+                        "`{name}()` failed to parse: {err}"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Parses a revset with an alias collapsed, returning the references.
+    fn collapsed_references(declaration: &str, definition: &str, input: &str) -> Vec<String> {
+        let mut aliases_map = RevsetAliasesMap::new();
+        aliases_map.insert(declaration, definition).unwrap();
+        aliases_map
+            .insert(declaration, collapsed_alias_definition(declaration))
+            .unwrap();
+        let mut reference_map = ReferenceMap::new();
+        with_parse_context(&aliases_map, |context| {
+            parse(
+                &mut RevsetDiagnostics::new(),
+                input,
+                context,
+                &mut reference_map,
+                true,
+                &mut Timings::default(),
+            )
+            .unwrap();
+        });
+        reference_map
+            .iter()
+            .map(|(_, reference)| reference.to_string())
+            .collect()
+    }
+
+    /// Parses a revset with `--alias-origins`, returning the references.
+    fn references_with_origins(aliases: &[(&str, &str)], input: &str) -> Vec<String> {
+        let mut aliases_map = RevsetAliasesMap::new();
+        for (declaration, definition) in aliases {
+            aliases_map.insert(declaration, *definition).unwrap();
+        }
+        let mut reference_map = ReferenceMap::new();
+        with_parse_context(&aliases_map, |context| {
+            let parsed =
+                parse_user_with_origins(&mut RevsetDiagnostics::new(), input, context).unwrap();
+            resolve_backend(&parsed, &mut reference_map, true, &mut Timings::default());
+        });
+        reference_map
+            .iter()
+            .map(|(_, reference)| match &reference.alias_origin {
+                Some(alias) => format!("{reference} [from {alias}]"),
+                None => reference.to_string(),
+            })
+            // The root and visible heads are always inserted
+            .filter(|reference| reference != "root()" && !reference.starts_with("visible_heads()"))
+            .collect()
+    }
+
+    #[test]
+    fn alias_arguments_keep_the_origin_of_the_caller() {
+        assert_eq!(
+            references_with_origins(&[("f(x)", "x & main")], "f(foo)"),
+            ["foo", "main [from f()]"]
+        );
+        assert_eq!(
+            references_with_origins(&[("f(x)", "x & main"), ("g", "f(bar)")], "g | baz"),
+            ["bar [from g]", "main [from f()]", "baz"]
+        );
+    }
+
+    #[test]
+    fn root_is_annotated_with_alias_origin() {
+        assert_eq!(
+            references_with_origins(&[("r", "root() | main")], "r"),
+            ["root() [from r]", "main [from r]"]
+        );
+    }
+
+    #[test]
+    fn alias_origins_are_displayed_separately_from_the_name() {
+        let mut aliases_map = RevsetAliasesMap::new();
+        aliases_map.insert("r", "root() | main").unwrap();
+        let mut reference_map = ReferenceMap::new();
+        let backend = with_parse_context(&aliases_map, |context| {
+            let parsed =
+                parse_user_with_origins(&mut RevsetDiagnostics::new(), "r | main", context)
+                    .unwrap();
+            resolve_backend(&parsed, &mut reference_map, true, &mut Timings::default())
+        });
+        let expr = Expr::parse(backend, &reference_map);
+        let Expr::Union(members) = &expr else {
+            panic!("expected a union: {expr:?}");
+        };
+        assert!(matches!(&members[0], Expr::Reference(reference)
+            if *reference == ResolvedReference::root()
+                && reference.alias_origin.as_deref() == Some("r")));
+        let tree =
+            TreeDisplay::new(&expr, AnalyzeContext::Lazy, &PrintOptions::default()).to_string();
+        assert_eq!(
+            tree,
+            "Union [\n  root() [from r]\n  main [from r]\n  main\n]\n"
+        );
+    }
+
+    #[test]
+    fn many_references_round_trip() {
+        // More references than fit in a 16-bit index
+        let mut reference_map = ReferenceMap::new();
+        let ids = (0..70_000)
+            .map(|index| reference_map.insert(ResolvedReference::new_owned(format!("r{index}"))))
+            .collect_vec();
+        for (index, id) in ids.iter().enumerate() {
+            assert_eq!(reference_map.get(id).to_string(), format!("r{index}"));
+        }
+        assert_eq!(
+            reference_map.insert(ResolvedReference::new_owned("r65536".to_owned())),
+            ids[65536]
+        );
+        let unknown = CommitId::from_hex("0123");
+        assert_eq!(reference_map.get(&unknown).to_string(), "commit_id(0123)");
+    }
+
+    #[test]
+    fn revset_with_many_references_is_parsed() {
+        let input = (0..300).map(|index| format!("r{index}")).join(" | ");
+        let mut reference_map = ReferenceMap::new();
+        let expr = crate::test_util::parse_with(&input, &mut reference_map, true);
+        let Expr::Union(members) = &expr else {
+            panic!("{expr:?}");
+        };
+        assert_eq!(members.len(), 300);
+        assert!(
+            matches!(&members[299], Expr::Reference(reference) if reference.to_string() == "r299"),
+            "{expr:?}"
+        );
+    }
+
+    #[test]
+    fn collapsed_alias_arguments_are_displayed_as_written() {
+        let references =
+            collapsed_references("mine_in(path)", "files(path)", r#"mine_in(glob:"*.rs")"#);
+        assert!(
+            references.contains(&r#"mine_in(glob:"*.rs")"#.to_owned()),
+            "{references:?}"
+        );
+        let references = collapsed_references("f(x, y)", "x | y", "f(a & b, c)");
+        assert!(
+            references.contains(&"f(a & b, c)".to_owned()),
+            "{references:?}"
         );
-        let reference = self
-            .references
-            .get_index(index)
-            .expect("commit ID should be present");
-        ResolvedReference(reference.0.as_ref().into())
+        assert!(references.iter().all(|reference| !reference.contains('\0')));
     }
 }
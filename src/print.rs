@@ -1,77 +1,688 @@
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt;
+use std::io;
+use std::io::Write;
 use std::ops;
 use std::ops::Range;
 
+use colored::ColoredString;
 use colored::Colorize;
+use globset::Glob;
+use globset::GlobBuilder;
+use indexmap::IndexMap;
+use indexmap::IndexSet;
 use itertools::Itertools as _;
+use jj_lib::backend::MillisSinceEpoch;
 use jj_lib::fileset::FilePattern;
 use jj_lib::fileset::FilesetExpression;
+use jj_lib::repo_path::RepoPath;
 use jj_lib::str_util::StringExpression;
 use jj_lib::str_util::StringPattern;
 use jj_lib::time_util::DatePattern;
+use regex::Regex;
 
+use crate::expr::Predicate;
 use crate::tree::AnalyzeContext;
 use crate::tree::AnalyzeCost;
 use crate::tree::AnalyzeTree;
+use crate::tree::ContextOverrides;
+use crate::tree::CostCache;
+use crate::tree::NodeKey;
+use crate::tree::NodeNumbers;
+use crate::tree::TreeEntry;
+use crate::tree::node_entry;
+use crate::tree::node_key;
+use crate::tree::push_path_segment;
+use crate::tree::walk;
 
-pub fn pretty_print(tree: &dyn AnalyzeTree, context: AnalyzeContext, analyze: bool) {
-    print_helper(tree, context, 0, analyze);
+enum Frame<'a> {
+    Node {
+        tree: &'a dyn AnalyzeTree,
+        context: AnalyzeContext,
+        depth: usize,
+        label: Option<Cow<'a, str>>,
+        /// The width to pad the label to, so that names of siblings line up.
+        label_width: usize,
+        /// The dotted path to the node, as in [`WalkNode::path`].
+        path: String,
+    },
+    Elided {
+        depth: usize,
+        count: usize,
+    },
+    Close {
+        depth: usize,
+        end: &'static str,
+    },
 }
 
-fn print_helper(tree: &dyn AnalyzeTree, context: AnalyzeContext, depth: usize, analyze: bool) {
-    let entry = tree.entry(context);
-    if analyze {
-        let cost = tree.cost(context);
-        if cost == AnalyzeCost::Slow {
-            print!("{} ", "(EXPENSIVE)".bright_red().bold())
+/// Options which control how a tree is printed.
+#[derive(Clone, Debug, Default)]
+pub struct PrintOptions {
+    /// Whether to show the results of analysis (cost and evaluation context).
+    pub analyze: bool,
+    /// Whether to join chains of single-child operations onto one line.
+    pub compact: bool,
+    /// Options for formatting the name of each node.
+    pub format: FormatOptions,
+    /// A pattern to highlight in the names of nodes.
+    pub grep: Option<Regex>,
+    /// Whether to only print nodes which match `grep` and their ancestors.
+    pub grep_only: bool,
+    /// Whether to use CSS classes instead of inline styles for HTML output.
+    pub html_classes: bool,
+    /// Whether to print the number of each node from [`NodeNumbers`].
+    pub number: bool,
+    /// Whether to print repeated predicates once and refer back to them.
+    pub group_predicates: bool,
+    /// The colors and styles used for the text output.
+    pub theme: Theme,
+    /// The width to wrap long names at, or `None` to never wrap names.
+    pub max_width: Option<usize>,
+    /// Whether to indent JSON output instead of printing it on one line.
+    pub json_pretty: bool,
+    /// Whether to pad the labels of siblings to the same width so that their
+    /// names line up.
+    pub align_labels: bool,
+    /// The maximum number of children to print for each node, or `None` to
+    /// print every child.
+    pub max_children: Option<usize>,
+    /// Whether to style every node as if it were resolved, hiding how each
+    /// node is evaluated while still showing costs.
+    pub structure_only: bool,
+    /// How each level of the text output is indented.
+    pub indent: IndentStyle,
+}
+
+/// How each level of the text output is indented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndentStyle {
+    /// The number of columns to indent each level by.
+    pub width: usize,
+    /// The color of a vertical guide drawn at the start of each level, or
+    /// `None` to indent with spaces only.
+    pub guide_color: Option<colored::Color>,
+}
+
+impl Default for IndentStyle {
+    fn default() -> Self {
+        Self {
+            width: 2,
+            guide_color: None,
+        }
+    }
+}
+
+/// The colors and styles used for the text output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    /// Blue for eager, cyan for lazy, and magenta for predicates
+    #[default]
+    Default,
+    /// Blue for eager, yellow for lazy, and underlined for predicates
+    Colorblind,
+    /// No colors, using underlined for eager and dimmed for predicates
+    Mono,
+}
+
+impl Theme {
+    /// Styles the name of a node based on how it is evaluated.
+    pub fn context_name(self, name: &str, context: AnalyzeContext) -> ColoredString {
+        match (self, context) {
+            (_, AnalyzeContext::Resolved) => name.normal(),
+            (Self::Default, AnalyzeContext::Eager) => name.bright_blue(),
+            (Self::Default, AnalyzeContext::Lazy) => name.bright_cyan(),
+            (Self::Default, AnalyzeContext::Predicate) => name.bright_magenta(),
+            (Self::Colorblind, AnalyzeContext::Eager) => name.bright_blue(),
+            (Self::Colorblind, AnalyzeContext::Lazy) => name.bright_yellow(),
+            (Self::Colorblind, AnalyzeContext::Predicate) => name.underline(),
+            (Self::Mono, AnalyzeContext::Eager) => name.underline(),
+            (Self::Mono, AnalyzeContext::Lazy) => name.normal(),
+            (Self::Mono, AnalyzeContext::Predicate) => name.dimmed(),
+        }
+    }
+
+    /// Styles the name of a node which isn't resolved when analysis is
+    /// disabled.
+    pub fn unresolved_name(self, name: &str) -> ColoredString {
+        match self {
+            Self::Default => name.blue(),
+            Self::Colorblind => name.bright_blue(),
+            Self::Mono => name.normal(),
+        }
+    }
+
+    /// Returns the label for a node with the given cost, or `None` if the node
+    /// isn't expected to be expensive.
+    pub fn cost_label(self, cost: AnalyzeCost) -> Option<ColoredString> {
+        let label = format!("({})", cost.label()?);
+        let color = match cost {
+            AnalyzeCost::Slow => colored::Color::BrightRed,
+            _ => colored::Color::Yellow,
+        };
+        Some(match self {
+            Self::Default | Self::Colorblind => label.color(color).bold(),
+            Self::Mono => label.bold(),
+        })
+    }
+
+    /// Styles the kind of a string pattern, such as `regex`, based on how
+    /// expensive it is to match. Returns `None` if it should be styled like
+    /// the rest of the name.
+    pub fn pattern_kind(self, kind: &str) -> Option<ColoredString> {
+        let color = match kind.trim_end_matches("-i") {
+            "exact" | "substring" => colored::Color::Green,
+            "glob" => colored::Color::Yellow,
+            "regex" => colored::Color::BrightRed,
+            _ => return None,
+        };
+        match (self, color) {
+            (Self::Default, _) => Some(kind.color(color)),
+            (Self::Colorblind, colored::Color::Green) => Some(kind.bright_blue()),
+            (Self::Colorblind, _) => Some(kind.color(color)),
+            (Self::Mono, _) => None,
         }
     }
-    let name = if analyze {
-        match entry.context {
-            AnalyzeContext::Eager => entry.name.bright_blue(),
-            AnalyzeContext::Lazy => entry.name.bright_cyan(),
-            AnalyzeContext::Predicate => entry.name.bright_magenta(),
-            AnalyzeContext::Resolved => entry.name.normal(),
+
+    /// Highlights a name which matches `--grep`.
+    pub fn highlight(self, name: ColoredString) -> ColoredString {
+        match self {
+            Self::Default => name.on_bright_yellow(),
+            Self::Colorblind | Self::Mono => name.reversed(),
         }
-    } else if entry.context != AnalyzeContext::Resolved {
-        entry.name.blue()
+    }
+}
+
+/// The position in the current line of the tree, so that wrapped names can be
+/// continued after the indentation for their depth.
+struct Cursor {
+    depth: usize,
+    column: usize,
+}
+
+/// State which is shared while printing every node of the tree.
+struct TextState {
+    cache: CostCache,
+    numbers: Option<NodeNumbers>,
+}
+
+/// Options which control how the names of nodes are formatted.
+#[derive(Clone, Debug, Default)]
+pub struct FormatOptions {
+    /// How to display the bounds of date patterns.
+    pub date_format: DateFormat,
+    /// The current time, used for relative dates.
+    pub now: chrono::DateTime<chrono::Local>,
+    /// The maximum width of a string pattern before it is truncated, or `None`
+    /// to never truncate patterns.
+    pub max_pattern_width: Option<usize>,
+    /// Whether to display filters as they are represented by jj instead of
+    /// using shorthands like `merges()` and `empty()`.
+    pub raw_names: bool,
+    /// The context to use for the candidates of `FilterWithin`, instead of the
+    /// context of the `FilterWithin` itself.
+    pub filter_context: Option<AnalyzeContext>,
+    /// Whether to pass the context of each operation to its children as-is,
+    /// instead of adjusting it to match how jj evaluates the children. This
+    /// doesn't affect the cost estimated for the operation itself.
+    pub literal_contexts: bool,
+    /// Whether to display ranges of generations as phrases like `depth 1`, and
+    /// ranges of parent indices as phrases like `first parent only`.
+    pub generation_as_depth: bool,
+    /// Contexts which replace the usual context of specific nodes.
+    pub context_overrides: ContextOverrides,
+    /// Whether to hide children which have a default value, such as `roots:
+    /// none()`.
+    pub compact_empty: bool,
+    /// Whether to label counts with phrases like `most_recent`, instead of
+    /// `count`.
+    pub describe_counts: bool,
+    /// Whether to sort the members of unions and intersections by name.
+    pub sort_members: bool,
+    /// Whether to sort the members of `coalesce()` by name, even though it
+    /// changes the meaning of the revset.
+    pub sort_coalesce: bool,
+}
+
+impl FormatOptions {
+    pub const DEFAULT_MAX_PATTERN_WIDTH: usize = 60;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DateFormat {
+    /// RFC 3339 in UTC, which doesn't depend on the local timezone.
+    #[default]
+    Utc,
+    /// RFC 3339 in the local timezone.
+    Local,
+    /// A duration relative to the current time, such as "2 days ago".
+    Relative,
+}
+
+pub fn pretty_print(
+    out: &mut dyn Write,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+) -> io::Result<()> {
+    // Use an explicit stack instead of recursion to avoid overflowing the stack
+    // for deeply nested revsets
+    let state = TextState {
+        cache: CostCache::for_tree(tree, context, &options.format),
+        numbers: options
+            .number
+            .then(|| NodeNumbers::new(tree, context, &options.format)),
+    };
+    let groups = if options.group_predicates {
+        repeated_predicates(tree, context, &options.format)
     } else {
-        entry.name.normal()
+        IndexSet::new()
+    };
+    let mut printed_groups = HashSet::new();
+    let matches = options
+        .grep_only
+        .then(|| matching_subtrees(tree, context, options))
+        .flatten();
+    if let Some(matches) = &matches
+        && !matches.contains(&node_key(tree, context))
+    {
+        return Ok(());
+    }
+    let mut stack = vec![Frame::Node {
+        tree,
+        context,
+        depth: 0,
+        label: None,
+        label_width: 0,
+        path: String::new(),
+    }];
+    while let Some(frame) = stack.pop() {
+        match frame {
+            Frame::Node {
+                mut tree,
+                mut context,
+                depth,
+                label,
+                label_width,
+                mut path,
+            } => {
+                indent(out, depth, options.indent)?;
+                let mut cursor = Cursor {
+                    depth,
+                    column: depth * options.indent.width,
+                };
+                if let Some(label) = label {
+                    let padding = label_width.saturating_sub(label.chars().count());
+                    write!(out, "{}{:padding$} ", format!("{label}:").dimmed(), "")?;
+                    cursor.column += label.chars().count() + padding + 2;
+                }
+                let mut entry = node_entry(tree, context, &path, &options.format);
+                let group = groupable_predicate(tree, &entry)
+                    .and_then(|predicate| groups.get_index_of(predicate))
+                    .map(|index| index + 1);
+                if let Some(group) = group {
+                    if !printed_groups.insert(group) {
+                        print_name(out, tree, context, &entry, &state, options, &mut cursor)?;
+                        writeln!(out, " {}", format!("(see [{group}])").dimmed())?;
+                        continue;
+                    }
+                    let marker = format!("[{group}]");
+                    write!(out, "{} ", marker.dimmed())?;
+                    cursor.column += marker.len() + 1;
+                }
+                let mut indices = retain_matching_children(&mut entry, matches.as_ref());
+                if options.compact {
+                    // Join wrappers with a single unlabeled child onto one line
+                    while let [child] = entry.children.as_slice()
+                        && child.label.is_none()
+                    {
+                        let (child_tree, child_context) = (child.tree, child.context);
+                        print_name(out, tree, context, &entry, &state, options, &mut cursor)?;
+                        write!(out, "{}", " → ".dimmed())?;
+                        cursor.column += 3;
+                        tree = child_tree;
+                        context = child_context;
+                        push_path_segment(&mut path, None, indices[0]);
+                        entry = node_entry(tree, context, &path, &options.format);
+                        indices = retain_matching_children(&mut entry, matches.as_ref());
+                    }
+                }
+                print_name(out, tree, context, &entry, &state, options, &mut cursor)?;
+                if entry.children.is_empty() {
+                    writeln!(out)?;
+                    continue;
+                }
+                let (start, end) = if entry.children.iter().any(|child| child.label.is_some()) {
+                    (" {", "}")
+                } else if entry.children.len() == 1 {
+                    ("(", ")")
+                } else {
+                    (" [", "]")
+                };
+                writeln!(out, "{}", start.dimmed())?;
+                stack.push(Frame::Close { depth, end });
+                if let Some(max_children) = options.max_children
+                    && entry.children.len() > max_children
+                {
+                    stack.push(Frame::Elided {
+                        depth: depth + 1,
+                        count: entry.children.len() - max_children,
+                    });
+                    entry.children.truncate(max_children);
+                }
+                let label_width = if options.align_labels {
+                    entry
+                        .children
+                        .iter()
+                        .filter_map(|child| child.label.as_ref())
+                        .map(|label| label.chars().count())
+                        .max()
+                        .unwrap_or_default()
+                } else {
+                    0
+                };
+                for (index, child) in indices.into_iter().zip(entry.children).rev() {
+                    let mut child_path = path.clone();
+                    push_path_segment(&mut child_path, child.label.as_deref(), index);
+                    stack.push(Frame::Node {
+                        tree: child.tree,
+                        context: child.context,
+                        depth: depth + 1,
+                        label: child.label,
+                        label_width,
+                        path: child_path,
+                    });
+                }
+            }
+            Frame::Elided { depth, count } => {
+                indent(out, depth, options.indent)?;
+                writeln!(out, "{}", format!("… and {count} more").dimmed())?;
+            }
+            Frame::Close { depth, end } => {
+                indent(out, depth, options.indent)?;
+                writeln!(out, "{}", end.dimmed())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the name of a node starting at the cursor, which is updated to the
+/// column after the name. Names which don't fit within `--max-width` are
+/// wrapped onto continuation lines aligned with the start of the name.
+fn print_name(
+    out: &mut dyn Write,
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    entry: &TreeEntry,
+    state: &TextState,
+    options: &PrintOptions,
+    cursor: &mut Cursor,
+) -> io::Result<()> {
+    if let Some(number) = (state.numbers.as_ref()).and_then(|numbers| numbers.get(tree, context)) {
+        let number = format!("#{number}");
+        write!(out, "{} ", number.dimmed())?;
+        cursor.column += number.len() + 1;
+    }
+    if options.analyze
+        && let Some(label) = options.theme.cost_label(state.cache.cost(tree, context))
+    {
+        write!(out, "{label} ")?;
+        cursor.column += label.chars().count() + 1;
+    }
+    let highlight = (options.grep.as_ref()).is_some_and(|grep| grep.is_match(&entry.name));
+    let style = |text: &str, is_kind: bool| {
+        let name = if options.structure_only {
+            text.normal()
+        } else if let Some(name) = is_kind.then(|| options.theme.pattern_kind(text)).flatten() {
+            name
+        } else if options.analyze {
+            options.theme.context_name(text, entry.context)
+        } else if entry.context != AnalyzeContext::Resolved {
+            options.theme.unresolved_name(text)
+        } else {
+            text.normal()
+        };
+        let name = if highlight {
+            options.theme.highlight(name)
+        } else {
+            name
+        };
+        if entry.children.is_empty() {
+            name
+        } else {
+            name.bold()
+        }
     };
+    let lines = wrap_name(&entry.name, cursor.column, options.max_width);
+    for (index, line) in lines.iter().enumerate() {
+        if index > 0 {
+            writeln!(out)?;
+            indent(out, cursor.depth, options.indent)?;
+            let padding = cursor.column - cursor.depth * options.indent.width;
+            write!(out, "{: >padding$}", "")?;
+        }
+        let mut segments = Vec::new();
+        let mut start = line.start;
+        for kind in &entry.pattern_kinds {
+            if kind.start < start || kind.end > line.end {
+                continue;
+            }
+            segments.push((start..kind.start, false));
+            segments.push((kind.clone(), true));
+            start = kind.end;
+        }
+        segments.push((start..line.end, false));
+        for (range, is_kind) in segments {
+            if !range.is_empty() {
+                write!(out, "{}", style(&entry.name[range], is_kind))?;
+            }
+        }
+    }
+    cursor.column += lines
+        .last()
+        .map_or(0, |line| entry.name[line.clone()].chars().count());
+    if let Some(alias) = tree.alias_origin() {
+        let origin = format!("[from {alias}]");
+        write!(out, " {}", origin.dimmed())?;
+        cursor.column += origin.chars().count() + 1;
+    }
+    if options.analyze && state.cache.is_always_empty(tree) {
+        write!(out, " {}", "(always empty)".dimmed())?;
+        cursor.column += 15;
+    }
+    Ok(())
+}
+
+/// Splits a name into the byte ranges of lines which fit between `column` and
+/// `max_width`, breaking at spaces where possible. Names aren't wrapped if
+/// there is too little space left, since very narrow lines would be harder to
+/// read.
+fn wrap_name(name: &str, column: usize, max_width: Option<usize>) -> Vec<Range<usize>> {
+    const MIN_WIDTH: usize = 20;
+    let mut lines = Vec::new();
+    let mut start = 0;
+    if let Some(width) = max_width
+        .and_then(|max_width| max_width.checked_sub(column))
+        .filter(|&width| width >= MIN_WIDTH)
+    {
+        while let Some((end, _)) = name[start..].char_indices().nth(width) {
+            let rest = &name[start..];
+            let split = match rest[..end].rfind(' ') {
+                Some(space) if space > 0 => space + 1,
+                _ => end,
+            };
+            lines.push(start..start + rest[..split].trim_end().len());
+            start += split;
+        }
+    }
+    lines.push(start..name.len());
+    lines
+}
+
+/// Checks whether a node is a predicate which can be grouped with identical
+/// predicates. Leaves aren't grouped since they are already short.
+/// Returns the node if it is a predicate with children, since printing a
+/// reference to a predicate without children wouldn't be any shorter.
+fn groupable_predicate<'a>(
+    tree: &'a dyn AnalyzeTree,
+    entry: &TreeEntry,
+) -> Option<&'a Predicate<'a>> {
     if entry.children.is_empty() {
-        print!("{}", name);
-    } else {
-        print!("{}", name.bold());
+        return None;
     }
-    let (start, end) = if entry.children.iter().any(|child| child.label.is_some()) {
-        (" {", "}")
-    } else if entry.children.len() == 1 {
-        ("(", ")")
-    } else {
-        (" [", "]")
+    tree.as_predicate()
+}
+
+/// Finds the predicates which appear more than once in the tree, in the order
+/// they first appear. Repeats inside of a repeated predicate aren't counted,
+/// since they are only printed once.
+fn repeated_predicates<'a>(
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &FormatOptions,
+) -> IndexSet<&'a Predicate<'a>> {
+    let mut counts: IndexMap<&Predicate, usize> = IndexMap::new();
+    // The depth of a repeated predicate whose descendants are being skipped
+    let mut skipped_depth = None;
+    walk(tree, context, options, &mut |node| {
+        if let Some(depth) = skipped_depth {
+            if node.depth > depth {
+                return;
+            }
+            skipped_depth = None;
+        }
+        if let Some(predicate) = groupable_predicate(node.tree, node.entry) {
+            let count = counts.entry(predicate).or_default();
+            *count += 1;
+            if *count > 1 {
+                skipped_depth = Some(node.depth);
+            }
+        }
+    });
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(predicate, _)| predicate)
+        .collect()
+}
+
+/// Removes children which don't match `--grep` if only matching nodes should be
+/// printed. Returns the original index of each remaining child, since paths
+/// refer to children by their original index.
+fn retain_matching_children(
+    entry: &mut TreeEntry,
+    matches: Option<&HashSet<NodeKey>>,
+) -> Vec<usize> {
+    let mut indices = (0..entry.children.len()).collect::<Vec<_>>();
+    if let Some(matches) = matches {
+        let retained = (entry.children.iter())
+            .map(|child| matches.contains(&node_key(child.tree, child.context)))
+            .collect::<Vec<_>>();
+        indices.retain(|index| retained[*index]);
+        let mut retained = retained.into_iter();
+        entry.children.retain(|_| retained.next().unwrap());
+    }
+    indices
+}
+
+/// Finds the nodes which match `--grep` or have a descendant which matches,
+/// identified by [`node_key`]. Returns `None` if there is no `--grep`.
+fn matching_subtrees(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &PrintOptions,
+) -> Option<HashSet<NodeKey>> {
+    let grep = options.grep.as_ref()?;
+    let mut matches = HashSet::new();
+    // Nodes are visited before their descendants, so each ancestor of the
+    // current node is kept along with whether its subtree matched so far, and
+    // it's recorded once every descendant has been visited
+    let mut ancestors: Vec<(_, bool)> = Vec::new();
+    let mut finish = |ancestors: &mut Vec<(_, bool)>, depth: usize| {
+        while ancestors.len() > depth {
+            let (key, matched) = ancestors.pop().unwrap();
+            if matched {
+                matches.insert(key);
+                if let Some((_, parent_matched)) = ancestors.last_mut() {
+                    *parent_matched = true;
+                }
+            }
+        }
     };
-    if !entry.children.is_empty() {
-        print!("{}", start.dimmed());
-    }
-    println!();
-    for child in &entry.children {
-        indent(depth + 1);
-        if let Some(label) = &child.label {
-            print!("{} ", format!("{label}:").dimmed());
-            print_helper(child.tree, child.context, depth + 1, analyze);
-        } else {
-            print_helper(child.tree, child.context, depth + 1, analyze);
+    walk(tree, context, &options.format, &mut |node| {
+        finish(&mut ancestors, node.depth);
+        ancestors.push((
+            node_key(node.tree, node.context),
+            grep.is_match(&node.entry.name),
+        ));
+    });
+    finish(&mut ancestors, 0);
+    Some(matches)
+}
+
+/// Displays a tree as it would be printed by [`pretty_print`], but without any
+/// colors, so that it can be formatted into a string.
+pub struct TreeDisplay<'a> {
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &'a PrintOptions,
+}
+
+impl<'a> TreeDisplay<'a> {
+    pub fn new(
+        tree: &'a dyn AnalyzeTree,
+        context: AnalyzeContext,
+        options: &'a PrintOptions,
+    ) -> Self {
+        Self {
+            tree,
+            context,
+            options,
         }
     }
-    if !entry.children.is_empty() {
-        indent(depth);
-        println!("{}", end.dimmed());
+}
+
+impl fmt::Display for TreeDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut output = Vec::new();
+        pretty_print(&mut output, self.tree, self.context, self.options).map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&strip_ansi(&output)))
     }
 }
 
-fn indent(depth: usize) {
-    print!("{: >depth$}", "", depth = depth * 2)
+/// Removes all ANSI escape sequences, such as colors, from the output.
+pub fn strip_ansi(output: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(output.len());
+    let mut bytes = output.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\x1b' {
+            stripped.push(byte);
+            continue;
+        }
+        // Control sequences end with a byte in the range `@` to `~`, and other
+        // escape sequences only have a single byte after the escape
+        if bytes.next() == Some(b'[') {
+            for byte in bytes.by_ref() {
+                if (b'@'..=b'~').contains(&byte) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+fn indent(out: &mut dyn Write, depth: usize, style: IndentStyle) -> io::Result<()> {
+    match style.guide_color {
+        Some(color) if style.width > 0 && depth > 0 => {
+            let guide = format!("│{: >width$}", "", width = style.width - 1);
+            write!(out, "{}", guide.repeat(depth).color(color))
+        }
+        _ => write!(out, "{: >width$}", "", width = depth * style.width),
+    }
 }
 
 pub fn string_pattern_kind(pattern: &StringPattern) -> &'static str {
@@ -87,39 +698,97 @@ pub fn string_pattern_kind(pattern: &StringPattern) -> &'static str {
     }
 }
 
-pub fn format_string_expression(expr: &StringExpression) -> String {
+/// Formats a string expression. If `max_width` is provided, any pattern which
+/// is longer than it is truncated with an ellipsis.
+pub fn format_string_expression(expr: &StringExpression, max_width: Option<usize>) -> String {
+    let mut out = String::new();
+    write_string_expression(&mut out, &mut Vec::new(), expr, max_width);
+    out
+}
+
+/// Appends a string expression to `out` like [`format_string_expression`],
+/// recording the byte range of the kind of each pattern in `pattern_kinds`.
+pub fn write_string_expression(
+    out: &mut String,
+    pattern_kinds: &mut Vec<Range<usize>>,
+    expr: &StringExpression,
+    max_width: Option<usize>,
+) {
     match expr {
         StringExpression::Pattern(pattern) => {
-            format!("{}:{:?}", string_pattern_kind(pattern), pattern.as_str())
-        }
-        StringExpression::NotIn(inner) => format!("~{}", format_string_expression(inner)),
-        StringExpression::Union(a, b) => format!(
-            "({} | {})",
-            format_string_expression(a.as_ref()),
-            format_string_expression(b.as_ref())
-        ),
-        StringExpression::Intersection(a, b) => format!(
-            "({} & {})",
-            format_string_expression(a.as_ref()),
-            format_string_expression(b.as_ref())
-        ),
+            let kind = string_pattern_kind(pattern);
+            pattern_kinds.push(out.len()..out.len() + kind.len());
+            let quoted = format!("{:?}", pattern.as_str());
+            out.push_str(kind);
+            out.push(':');
+            out.push_str(&truncate_quoted(&quoted, max_width));
+        }
+        StringExpression::NotIn(inner) => {
+            out.push('~');
+            write_string_expression(out, pattern_kinds, inner, max_width);
+        }
+        StringExpression::Union(a, b) | StringExpression::Intersection(a, b) => {
+            let operator = match expr {
+                StringExpression::Union(..) => " | ",
+                _ => " & ",
+            };
+            out.push('(');
+            write_string_expression(out, pattern_kinds, a, max_width);
+            out.push_str(operator);
+            write_string_expression(out, pattern_kinds, b, max_width);
+            out.push(')');
+        }
+    }
+}
+
+fn truncate_quoted(quoted: &str, max_width: Option<usize>) -> Cow<'_, str> {
+    match max_width {
+        Some(max_width) if quoted.chars().count() > max_width => {
+            // Keep the closing quote so that the pattern still looks like a string
+            let kept: String = quoted.chars().take(max_width.saturating_sub(2)).collect();
+            format!("{kept}…\"").into()
+        }
+        _ => quoted.into(),
     }
 }
 
 pub fn format_file_pattern(pattern: &FilePattern) -> String {
+    // Paths are stored relative to the workspace root, so they are displayed
+    // that way too, since the revset may be used from any directory
     match pattern {
-        FilePattern::FilePath(path) => format!("file:{:?}", path.as_internal_file_string()),
-        FilePattern::PrefixPath(path) => format!("{:?}", path.as_internal_file_string().to_owned()),
-        FilePattern::FileGlob { dir, pattern } => {
-            format!("glob:{:?}", dir.to_internal_dir_string() + pattern.glob())
-        }
-        FilePattern::PrefixGlob { dir, pattern } => format!(
-            "prefix-glob:{:?}",
-            dir.to_internal_dir_string() + pattern.glob()
-        ),
+        FilePattern::FilePath(path) => format!("root-file:{:?}", path.as_internal_file_string()),
+        FilePattern::PrefixPath(path) => format!("root:{:?}", path.as_internal_file_string()),
+        FilePattern::FileGlob { dir, pattern } => format_glob("root-glob", dir, pattern),
+        FilePattern::PrefixGlob { dir, pattern } => format_glob("root-prefix-glob", dir, pattern),
     }
 }
 
+fn format_glob(kind: &str, dir: &RepoPath, pattern: &Glob) -> String {
+    // The directory never contains glob metacharacters (they are split off into
+    // the pattern), and it always ends with a `/` unless it is empty, so it can
+    // be joined with the pattern without escaping
+    let suffix = if is_case_insensitive(pattern) {
+        "-i"
+    } else {
+        ""
+    };
+    format!(
+        "{kind}{suffix}:{:?}",
+        dir.to_internal_dir_string() + pattern.glob()
+    )
+}
+
+/// Checks whether a glob matches case-insensitively. Globs compare equal only
+/// if they were built with the same options, so the glob is compared with the
+/// case-insensitive glob jj would build from the same pattern.
+fn is_case_insensitive(pattern: &Glob) -> bool {
+    GlobBuilder::new(pattern.glob())
+        .literal_separator(true)
+        .case_insensitive(true)
+        .build()
+        .is_ok_and(|glob| glob == *pattern)
+}
+
 pub fn format_fileset_expression(expr: &FilesetExpression) -> Cow<'static, str> {
     match expr {
         FilesetExpression::None => "none()".into(),
@@ -145,27 +814,58 @@ pub fn format_fileset_expression(expr: &FilesetExpression) -> Cow<'static, str>
     }
 }
 
-pub fn format_date_pattern(pattern: &DatePattern) -> String {
+pub fn format_date_pattern(pattern: &DatePattern, options: &FormatOptions) -> String {
     match pattern {
         DatePattern::AtOrAfter(millis_since_epoch) => {
-            format!(
-                "after:{}",
-                chrono::DateTime::from_timestamp_millis(millis_since_epoch.0)
-                    .expect("valid date-time")
-                    .to_rfc3339()
-            )
+            format!("after:{}", format_timestamp(millis_since_epoch, options))
         }
         DatePattern::Before(millis_since_epoch) => {
+            format!("before:{}", format_timestamp(millis_since_epoch, options))
+        }
+    }
+}
+
+fn format_timestamp(millis_since_epoch: &MillisSinceEpoch, options: &FormatOptions) -> String {
+    let date_time =
+        chrono::DateTime::from_timestamp_millis(millis_since_epoch.0).expect("valid date-time");
+    match options.date_format {
+        DateFormat::Utc => date_time.to_rfc3339(),
+        DateFormat::Local => date_time.with_timezone(&chrono::Local).to_rfc3339(),
+        DateFormat::Relative => {
+            // Quote the duration so that it can be parsed as a date pattern again
             format!(
-                "before:{}",
-                chrono::DateTime::from_timestamp_millis(millis_since_epoch.0)
-                    .expect("valid date-time")
-                    .to_rfc3339()
+                "{:?}",
+                format_relative(date_time.signed_duration_since(options.now))
             )
         }
     }
 }
 
+fn format_relative(duration: chrono::TimeDelta) -> String {
+    const UNITS: [(&str, i64); 6] = [
+        ("year", 365 * 24 * 60 * 60),
+        ("week", 7 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let seconds = duration.num_seconds();
+    let Some((unit, count)) = UNITS
+        .iter()
+        .map(|(unit, size)| (unit, seconds.abs() / size))
+        .find(|(_, count)| *count > 0)
+    else {
+        return "now".to_owned();
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    if seconds < 0 {
+        format!("{count} {unit}{plural} ago")
+    } else {
+        format!("in {count} {unit}{plural}")
+    }
+}
+
 pub fn format_range<T>(range: &Range<T>, full_range: Range<T>) -> String
 where
     T: Copy + Eq + From<u32> + ops::Sub<Output = T> + fmt::Display,
@@ -178,7 +878,166 @@ where
         range.start.to_string()
     } else if range.end == full_range.end {
         format!("{}..", range.start)
+    } else if range.start == full_range.start {
+        format!("..{}", range.end)
     } else {
         format!("{}..{}", range.start, range.end)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use jj_lib::fileset::FilesetDiagnostics;
+    use jj_lib::fileset::{self};
+    use jj_lib::repo_path::RepoPathUiConverter;
+
+    use super::*;
+    use crate::test_util::parse_expr;
+    use crate::tree::canonical_path;
+
+    fn format_fileset(text: &str, cwd: &str) -> String {
+        let path_converter = RepoPathUiConverter::Fs {
+            cwd: PathBuf::from(cwd),
+            base: PathBuf::from("/repo"),
+        };
+        let expr = fileset::parse(&mut FilesetDiagnostics::new(), text, &path_converter).unwrap();
+        format_fileset_expression(&expr).into_owned()
+    }
+
+    #[test]
+    fn nested_globs_are_formatted_from_root() {
+        assert_eq!(
+            format_fileset(r#"glob:"foo/bar/*.rs""#, "/repo"),
+            r#"root-glob:"foo/bar/*.rs""#
+        );
+        assert_eq!(
+            format_fileset(r#"prefix-glob:"foo/bar/*.rs""#, "/repo"),
+            r#"root-prefix-glob:"foo/bar/*.rs""#
+        );
+        assert_eq!(
+            format_fileset(r#"glob:"bar/*.rs""#, "/repo/foo"),
+            r#"root-glob:"foo/bar/*.rs""#
+        );
+        assert_eq!(
+            format_fileset(r#"glob-i:"foo/BAR/*.rs""#, "/repo"),
+            r#"root-glob-i:"foo/BAR/*.rs""#
+        );
+    }
+
+    #[test]
+    fn file_patterns_round_trip() {
+        for (text, cwd) in [
+            (r#"glob:"foo/bar/*.rs""#, "/repo"),
+            (r#"glob:"bar/*.rs""#, "/repo/foo"),
+            (r#"prefix-glob-i:"bar/**/*.RS""#, "/repo/foo"),
+            (r#"file:"bar/baz.rs""#, "/repo/foo"),
+            (r#""bar""#, "/repo/foo"),
+            (r#"glob:"(?i)*.rs""#, "/repo"),
+        ] {
+            let formatted = format_fileset(text, cwd);
+            assert_eq!(
+                format_fileset(&formatted, "/repo/other"),
+                formatted,
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn strip_ansi_removes_every_escape() {
+        let output =
+            b"\x1b[2J\x1b[H\x1b[1;31mUnion\x1b[0m [\n\x1b[38;5;8m\xe2\x94\x82\x1b[0m x\x1b7\n]";
+        let stripped = strip_ansi(output);
+        assert!(!stripped.contains(&b'\x1b'), "{stripped:?}");
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            "Union [\n\u{2502} x\n]"
+        );
+    }
+
+    #[test]
+    fn grep_only_prints_matching_nodes_and_their_ancestors() {
+        let expr = parse_expr("a | (b & description(c)) | ::d");
+        let options = PrintOptions {
+            grep: Some(Regex::new("^d$").unwrap()),
+            grep_only: true,
+            ..PrintOptions::default()
+        };
+        let tree = TreeDisplay::new(&expr, AnalyzeContext::Lazy, &options).to_string();
+        assert_eq!(tree, "Union(\n  Ancestors {\n    heads: d\n  }\n)\n");
+        let options = PrintOptions {
+            grep: Some(Regex::new("missing").unwrap()),
+            ..options
+        };
+        let tree = TreeDisplay::new(&expr, AnalyzeContext::Lazy, &options).to_string();
+        assert_eq!(tree, "");
+    }
+
+    #[test]
+    fn repeated_predicates_are_printed_once() {
+        let expr =
+            parse_expr("(x & ~(description(a) | merges())) | (y & ~(description(a) | merges()))");
+        let options = PrintOptions {
+            group_predicates: true,
+            ..PrintOptions::default()
+        };
+        let tree = TreeDisplay::new(&expr, AnalyzeContext::Lazy, &options).to_string();
+        assert_eq!(tree.matches("description").count(), 1);
+        assert!(tree.contains("predicate: [1] NotIn("));
+        assert!(tree.contains("predicate: NotIn (see [1])"));
+    }
+
+    #[test]
+    fn selected_contexts_apply_to_the_node_at_a_path() {
+        let expr = parse_expr("x ~ ::y");
+        let mut options = PrintOptions {
+            analyze: true,
+            ..PrintOptions::default()
+        };
+        let path = canonical_path(&expr, AnalyzeContext::Lazy, "1", &options.format).unwrap();
+        assert_eq!(path, "excluded");
+        (options.format.context_overrides).insert(path, AnalyzeContext::Eager);
+        let tree = TreeDisplay::new(&expr, AnalyzeContext::Lazy, &options).to_string();
+        // The difference is expensive since the excluded ancestors are eager
+        assert!(tree.starts_with("(EXPENSIVE) Difference"));
+        assert!(tree.contains("excluded: (EXPENSIVE) Ancestors"));
+        // The same options can be used for another tree without that path
+        let other = parse_expr("x | y");
+        let tree = TreeDisplay::new(&other, AnalyzeContext::Lazy, &options).to_string();
+        assert_eq!(tree, "Union [\n  x\n  y\n]\n");
+    }
+
+    #[test]
+    fn pattern_kinds_are_recorded_when_names_are_built() {
+        let expr = parse_expr(r#"x & ~description(regex:"a" | ~glob-i:"exact:b")"#);
+        let options = FormatOptions::default();
+        let mut kinds = Vec::new();
+        crate::tree::walk(&expr, AnalyzeContext::Lazy, &options, &mut |node| {
+            for kind in &node.entry.pattern_kinds {
+                kinds.push(node.entry.name[kind.clone()].to_owned());
+            }
+        });
+        assert_eq!(kinds, ["regex", "glob-i"]);
+    }
+
+    #[test]
+    fn ranges_from_the_lower_bound_omit_it() {
+        let full = 0..u64::MAX;
+        assert_eq!(format_range(&(0..5), full.clone()), "..5");
+        assert_eq!(format_range(&(2..5), full.clone()), "2..5");
+        assert_eq!(format_range(&(2..u64::MAX), full.clone()), "2..");
+        assert_eq!(format_range(&(3..4), full.clone()), "3");
+        assert_eq!(format_range(&(0..u64::MAX), full), "");
+    }
+
+    #[test]
+    fn generations_from_zero_are_displayed_without_lower_bound() {
+        let expr = parse_expr("ancestors(x, 5)");
+        let tree =
+            TreeDisplay::new(&expr, AnalyzeContext::Lazy, &PrintOptions::default()).to_string();
+        assert!(tree.contains("generation: ..5"), "{tree}");
+        assert!(!tree.contains("0..5"), "{tree}");
+    }
+}
@@ -0,0 +1,349 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context as _;
+use itertools::Itertools as _;
+use jj_lib::object_id::ObjectId as _;
+use jj_lib::object_id::PrefixResolution;
+use jj_lib::op_walk;
+use jj_lib::op_walk::OpsetEvaluationError;
+use jj_lib::repo::ReadonlyRepo;
+use jj_lib::repo::Repo as _;
+use jj_lib::repo::StoreFactories;
+use jj_lib::revset::RevsetCommitRef;
+use jj_lib::revset::RevsetExpression;
+use jj_lib::revset::RevsetExtensions;
+use jj_lib::revset::RevsetResolutionError;
+use jj_lib::revset::SymbolResolver;
+use jj_lib::revset::UserRevsetExpression;
+use jj_lib::settings::UserSettings;
+use jj_lib::workspace::DefaultWorkspaceLoaderFactory;
+use jj_lib::workspace::WorkspaceLoaderFactory as _;
+use jj_lib::workspace::default_working_copy_factories;
+
+use crate::expr::ResolvedReference;
+use crate::parse::resolve_commit_ref;
+use crate::tree::AnalyzeCost;
+
+/// Loads the real repository at its current operation. Most of the analysis
+/// uses a dummy repository instead, so this is only needed for features which
+/// must look at actual revisions.
+pub fn load_repo(
+    settings: &UserSettings,
+    workspace_dir: &Path,
+) -> anyhow::Result<Arc<ReadonlyRepo>> {
+    let loader = DefaultWorkspaceLoaderFactory
+        .create(workspace_dir)
+        .with_context(|| format!("Failed to find repository at {}", workspace_dir.display()))?;
+    let workspace = loader
+        .load(
+            settings,
+            &StoreFactories::default(),
+            &default_working_copy_factories(),
+        )
+        .context("Failed to load workspace")?;
+    workspace
+        .repo_loader()
+        .load_at_head()
+        .context("Failed to load repository")
+}
+
+/// Evaluates the revset against the repository, returning the number of
+/// revisions it contains and how long evaluation took.
+pub fn count_revisions(
+    expr: &Arc<UserRevsetExpression>,
+    repo: &ReadonlyRepo,
+    extensions: &RevsetExtensions,
+    optimize: bool,
+) -> anyhow::Result<(usize, Duration)> {
+    let symbol_resolver = SymbolResolver::new(repo, extensions.symbol_resolvers());
+    let resolved = expr
+        .resolve_user_expression(repo, &symbol_resolver)
+        .context("Failed to resolve revset")?;
+    let start = Instant::now();
+    let revset = if optimize {
+        resolved.evaluate(repo)
+    } else {
+        resolved.evaluate_unoptimized(repo)
+    }
+    .context("Failed to evaluate revset")?;
+    let count = revset
+        .iter()
+        .process_results(|commit_ids| commit_ids.count())
+        .context("Failed to evaluate revset")?;
+    Ok((count, start.elapsed()))
+}
+
+/// Returns the cost which best describes how long evaluation actually took, so
+/// that it can be compared to the predicted cost.
+pub fn measured_cost(duration: Duration) -> AnalyzeCost {
+    if duration < Duration::from_millis(10) {
+        AnalyzeCost::Fast
+    } else if duration < Duration::from_millis(100) {
+        AnalyzeCost::Medium
+    } else {
+        AnalyzeCost::Slow
+    }
+}
+
+/// Resolves a change ID or commit ID prefix to the full ID, or indicates that
+/// the prefix is ambiguous or doesn't match any revisions. Returns `None` for
+/// other references, or if the index can't be searched.
+pub fn resolve_id_prefix(
+    reference: &RevsetCommitRef,
+    repo: &ReadonlyRepo,
+) -> Option<ResolvedReference<'static>> {
+    let (function, prefix, resolution) = id_prefix_resolution(reference, repo)?;
+    let (id, problem) = match resolution {
+        PrefixResolution::SingleMatch(id) => (id, None),
+        PrefixResolution::AmbiguousMatch => (prefix, Some("ambiguous")),
+        PrefixResolution::NoMatch => (prefix, Some("no match")),
+    };
+    Some(ResolvedReference {
+        problem,
+        ..ResolvedReference::new_owned(format!("{function}({id})"))
+    })
+}
+
+/// Returns the function and prefix of a change ID or commit ID prefix, along
+/// with the full ID it resolves to.
+fn id_prefix_resolution(
+    reference: &RevsetCommitRef,
+    repo: &ReadonlyRepo,
+) -> Option<(&'static str, String, PrefixResolution<String>)> {
+    Some(match reference {
+        RevsetCommitRef::ChangeId(prefix) => {
+            let resolution = match repo.resolve_change_id_prefix(prefix).ok()? {
+                PrefixResolution::SingleMatch(targets) => {
+                    let (commit_id, _) = targets.targets.first()?;
+                    let commit = repo.store().get_commit(commit_id).ok()?;
+                    PrefixResolution::SingleMatch(commit.change_id().reverse_hex())
+                }
+                PrefixResolution::AmbiguousMatch => PrefixResolution::AmbiguousMatch,
+                PrefixResolution::NoMatch => PrefixResolution::NoMatch,
+            };
+            ("change_id", prefix.reverse_hex(), resolution)
+        }
+        RevsetCommitRef::CommitId(prefix) => {
+            let resolution = repo
+                .index()
+                .resolve_commit_id_prefix(prefix)
+                .ok()?
+                .map(|commit_id| commit_id.hex());
+            ("commit_id", prefix.hex(), resolution)
+        }
+        _ => return None,
+    })
+}
+
+/// ID prefixes with fewer hex digits than this are likely to be ambiguous in
+/// all but the smallest repositories.
+const MIN_PREFIX_LENGTH: usize = 4;
+
+/// Returns a warning for each change ID or commit ID prefix which is ambiguous
+/// in the repository. Without a repository, prefixes can't be checked, so a
+/// warning is returned for each prefix which is short enough that it is likely
+/// to be ambiguous instead.
+pub fn id_prefix_warnings(expr: &UserRevsetExpression, repo: Option<&ReadonlyRepo>) -> Vec<String> {
+    let mut references = Vec::new();
+    collect_references(expr, true, &mut references, &mut Vec::new());
+    references
+        .iter()
+        .filter_map(|reference| {
+            let (function, prefix) = match reference {
+                RevsetCommitRef::ChangeId(prefix) => ("change_id", prefix.reverse_hex()),
+                RevsetCommitRef::CommitId(prefix) => ("commit_id", prefix.hex()),
+                _ => return None,
+            };
+            match repo {
+                Some(repo) => matches!(
+                    id_prefix_resolution(reference, repo)?.2,
+                    PrefixResolution::AmbiguousMatch
+                )
+                .then(|| format!("`{function}({prefix})` is ambiguous in the repository")),
+                None => (prefix.len() < MIN_PREFIX_LENGTH).then(|| {
+                    format!("`{function}({prefix})` is a very short prefix, so it may be ambiguous")
+                }),
+            }
+        })
+        .unique()
+        .collect()
+}
+
+/// Resolves an operation such as an operation ID prefix or `@-` to the short
+/// ID of the operation. Returns an error if the operation is ambiguous or
+/// doesn't exist.
+pub fn resolve_operation(operation: &str, repo: &ReadonlyRepo) -> anyhow::Result<String> {
+    match op_walk::resolve_op_with_repo(repo, operation) {
+        Ok(resolved) => Ok(resolved.id().hex()[..SHORT_OPERATION_ID_LENGTH].to_owned()),
+        Err(OpsetEvaluationError::OpsetResolution(err)) => {
+            Err(err).with_context(|| format!("Operation `{operation}` can't be resolved"))
+        }
+        // The operation log couldn't be read, so the operation can't be checked
+        Err(_) => Ok(operation.to_owned()),
+    }
+}
+
+/// Checks that the operation of every `at_operation()` in the revset can be
+/// resolved using [`resolve_operation`].
+pub fn check_operations(expr: &UserRevsetExpression, repo: &ReadonlyRepo) -> anyhow::Result<()> {
+    let mut operations = Vec::new();
+    collect_references(expr, true, &mut Vec::new(), &mut operations);
+    for operation in operations {
+        resolve_operation(operation, repo)?;
+    }
+    Ok(())
+}
+
+/// The number of hex digits of an operation ID to display, matching `jj op
+/// log`.
+const SHORT_OPERATION_ID_LENGTH: usize = 12;
+
+/// Returns true if the revset contains any change ID or commit ID prefixes
+/// which could be resolved using [`resolve_id_prefix`].
+pub fn has_id_prefix(expr: &UserRevsetExpression) -> bool {
+    let mut references = Vec::new();
+    collect_references(expr, true, &mut references, &mut Vec::new());
+    references.iter().any(|reference| {
+        matches!(
+            reference,
+            RevsetCommitRef::ChangeId(_) | RevsetCommitRef::CommitId(_)
+        )
+    })
+}
+
+/// Returns true if the revset contains any `at_operation()` which could be
+/// resolved using [`resolve_operation`].
+pub fn has_at_operation(expr: &UserRevsetExpression) -> bool {
+    let mut operations = Vec::new();
+    collect_references(expr, true, &mut Vec::new(), &mut operations);
+    !operations.is_empty()
+}
+
+/// Checks that every symbol, bookmark, and tag referenced by the revset
+/// matches at least one revision in the repository, returning a warning for
+/// each one which doesn't.
+pub fn validate_references(
+    expr: &UserRevsetExpression,
+    repo: &ReadonlyRepo,
+    extensions: &RevsetExtensions,
+) -> Vec<String> {
+    // Missing references are allowed inside `present()`
+    let mut references = Vec::new();
+    collect_references(expr, false, &mut references, &mut Vec::new());
+    references.retain(|reference| {
+        matches!(
+            reference,
+            RevsetCommitRef::Symbol(_)
+                | RevsetCommitRef::RemoteSymbol(_)
+                | RevsetCommitRef::Bookmarks(_)
+                | RevsetCommitRef::RemoteBookmarks { .. }
+                | RevsetCommitRef::Tags(_)
+        )
+    });
+    let symbol_resolver = SymbolResolver::new(repo, extensions.symbol_resolvers());
+    let mut warnings = Vec::new();
+    for reference in references {
+        let name = resolve_commit_ref(reference);
+        let resolved = Arc::new(RevsetExpression::CommitRef(reference.clone()))
+            .resolve_user_expression(repo, &symbol_resolver);
+        match resolved.as_deref() {
+            Ok(RevsetExpression::Commits(commit_ids)) if commit_ids.is_empty() => {
+                warnings.push(format!("`{name}` doesn't match any revisions"));
+            }
+            Ok(_) => {}
+            Err(RevsetResolutionError::NoSuchRevision { candidates, .. }) => {
+                if candidates.is_empty() {
+                    warnings.push(format!("`{name}` doesn't exist"));
+                } else {
+                    let candidates = candidates
+                        .iter()
+                        .map(|candidate| format!("`{candidate}`"))
+                        .join(", ");
+                    warnings.push(format!(
+                        "`{name}` doesn't exist (did you mean {candidates}?)"
+                    ));
+                }
+            }
+            Err(err) => warnings.push(format!("`{name}` can't be resolved: {err}")),
+        }
+    }
+    warnings
+}
+
+/// Collects every reference in the revset, along with the operation of every
+/// `at_operation()`. References inside of `at_operation()` are skipped, since
+/// they refer to a different operation.
+fn collect_references<'a>(
+    expr: &'a UserRevsetExpression,
+    include_present: bool,
+    references: &mut Vec<&'a RevsetCommitRef>,
+    operations: &mut Vec<&'a str>,
+) {
+    match expr {
+        RevsetExpression::CommitRef(reference) => references.push(reference),
+        RevsetExpression::None
+        | RevsetExpression::All
+        | RevsetExpression::VisibleHeads
+        | RevsetExpression::VisibleHeadsOrReferenced
+        | RevsetExpression::Root
+        | RevsetExpression::Commits(_)
+        | RevsetExpression::Filter(_) => {}
+        RevsetExpression::AtOperation { operation, .. } => operations.push(operation),
+        RevsetExpression::Present(expr) => {
+            if include_present {
+                collect_references(expr, include_present, references, operations);
+            }
+        }
+        RevsetExpression::Ancestors { heads: expr, .. }
+        | RevsetExpression::Descendants { roots: expr, .. }
+        | RevsetExpression::Heads(expr)
+        | RevsetExpression::Roots(expr)
+        | RevsetExpression::ForkPoint(expr)
+        | RevsetExpression::Bisect(expr)
+        | RevsetExpression::HasSize {
+            candidates: expr, ..
+        }
+        | RevsetExpression::Latest {
+            candidates: expr, ..
+        }
+        | RevsetExpression::AsFilter(expr)
+        | RevsetExpression::WithinReference {
+            candidates: expr, ..
+        }
+        | RevsetExpression::WithinVisibility {
+            candidates: expr, ..
+        }
+        | RevsetExpression::NotIn(expr) => {
+            collect_references(expr, include_present, references, operations)
+        }
+        RevsetExpression::Range { roots, heads, .. }
+        | RevsetExpression::DagRange { roots, heads } => {
+            collect_references(roots, include_present, references, operations);
+            collect_references(heads, include_present, references, operations);
+        }
+        RevsetExpression::HeadsRange {
+            roots,
+            heads,
+            filter,
+            ..
+        } => {
+            collect_references(roots, include_present, references, operations);
+            collect_references(heads, include_present, references, operations);
+            collect_references(filter, include_present, references, operations);
+        }
+        RevsetExpression::Reachable { sources, domain } => {
+            collect_references(sources, include_present, references, operations);
+            collect_references(domain, include_present, references, operations);
+        }
+        RevsetExpression::Coalesce(expr1, expr2)
+        | RevsetExpression::Union(expr1, expr2)
+        | RevsetExpression::Intersection(expr1, expr2)
+        | RevsetExpression::Difference(expr1, expr2) => {
+            collect_references(expr1, include_present, references, operations);
+            collect_references(expr2, include_present, references, operations);
+        }
+    }
+}
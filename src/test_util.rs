@@ -0,0 +1,63 @@
+//! Helpers for parsing and printing revsets in tests.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use jj_lib::ref_name::WorkspaceName;
+use jj_lib::repo_path::RepoPathUiConverter;
+use jj_lib::revset::RevsetAliasesMap;
+use jj_lib::revset::RevsetDiagnostics;
+use jj_lib::revset::RevsetParseContext;
+use jj_lib::revset::RevsetWorkspaceContext;
+
+use crate::expr::Expr;
+use crate::parse::ReferenceMap;
+use crate::parse::Timings;
+use crate::parse::{self};
+
+/// Parses and optimizes a revset without any aliases or repo.
+pub fn parse_expr(input: &str) -> Expr<'static> {
+    parse_with(input, &mut ReferenceMap::new(), true)
+}
+
+/// Parses a revset using an existing reference map, so that its settings and
+/// references are used.
+pub fn parse_with(input: &str, reference_map: &mut ReferenceMap, optimize: bool) -> Expr<'static> {
+    with_parse_context(&RevsetAliasesMap::new(), |context| {
+        parse::parse(
+            &mut RevsetDiagnostics::new(),
+            input,
+            context,
+            reference_map,
+            optimize,
+            &mut Timings::default(),
+        )
+        .unwrap()
+        .into_owned()
+    })
+}
+
+/// Calls `f` with a parse context for a workspace at `/repo`.
+pub fn with_parse_context<T>(
+    aliases_map: &RevsetAliasesMap,
+    f: impl FnOnce(&RevsetParseContext) -> T,
+) -> T {
+    let path_converter = RepoPathUiConverter::Fs {
+        cwd: PathBuf::from("/repo"),
+        base: PathBuf::from("/repo"),
+    };
+    let context = RevsetParseContext {
+        aliases_map,
+        local_variables: HashMap::new(),
+        user_email: "test.user@example.com",
+        date_pattern_context: chrono::Local::now().into(),
+        default_ignored_remote: None,
+        use_glob_by_default: true,
+        extensions: &parse::revset_extensions(),
+        workspace: Some(RevsetWorkspaceContext {
+            path_converter: &path_converter,
+            workspace_name: WorkspaceName::DEFAULT,
+        }),
+    };
+    f(&context)
+}
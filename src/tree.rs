@@ -1,16 +1,60 @@
+use std::any::TypeId;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::fmt::Write as _;
+use std::ops;
 use std::ops::Range;
 
+use anyhow::Context as _;
+
+use crate::expr::Predicate;
+use crate::print::FormatOptions;
 use crate::print::format_range;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AnalyzeCost {
     Fast,
+    Medium,
     Slow,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+impl AnalyzeCost {
+    /// The score of a node with this cost, used by [`AnalyzeTree::cost_score`]
+    /// when there is no more precise estimate.
+    pub fn score(self) -> u64 {
+        match self {
+            Self::Fast => 1,
+            Self::Medium => 100,
+            Self::Slow => 1000,
+        }
+    }
+
+    /// Returns the label for a node with this cost, or `None` if the node isn't
+    /// expected to be expensive. Every output format uses the same labels.
+    pub fn label(self) -> Option<&'static str> {
+        match self {
+            Self::Fast => None,
+            Self::Medium => Some("COSTLY"),
+            Self::Slow => Some("EXPENSIVE"),
+        }
+    }
+}
+
+impl fmt::Display for AnalyzeCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fast => write!(f, "fast"),
+            Self::Medium => write!(f, "medium"),
+            Self::Slow => write!(f, "slow"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AnalyzeContext {
     Eager,
     Lazy,
@@ -20,6 +64,8 @@ pub enum AnalyzeContext {
 }
 
 impl AnalyzeContext {
+    pub const ALL: [Self; 4] = [Self::Eager, Self::Lazy, Self::Predicate, Self::Resolved];
+
     pub fn predicate_to_lazy(self) -> Self {
         match self {
             Self::Predicate => Self::Lazy,
@@ -49,6 +95,9 @@ impl fmt::Display for AnalyzeContext {
 #[derive(Debug)]
 pub struct TreeEntry<'a> {
     pub name: Cow<'a, str>,
+    /// Byte ranges of `name` which are the kinds of string patterns, such as
+    /// `regex`, so that they can be styled separately.
+    pub pattern_kinds: Vec<Range<usize>>,
     pub context: AnalyzeContext,
     pub children: Vec<Child<'a>>,
 }
@@ -61,48 +110,624 @@ pub struct Child<'a> {
 }
 
 pub trait AnalyzeTree: fmt::Debug {
-    fn entry(&self, context: AnalyzeContext) -> TreeEntry<'_>;
-    fn cost(&self, context: AnalyzeContext) -> AnalyzeCost;
+    fn entry(&self, context: AnalyzeContext, options: &FormatOptions) -> TreeEntry<'_>;
+    fn cost(&self, context: AnalyzeContext, cache: &CostCache) -> AnalyzeCost;
+    fn cost_reason(&self, context: AnalyzeContext, cache: &CostCache) -> Option<Cow<'static, str>>;
+    /// Returns a score estimating how expensive this node is to evaluate, not
+    /// including its children. Unlike `cost`, scores can be summed across a
+    /// tree with [`total_cost_score`] to rank several revsets. Nodes which
+    /// aren't evaluated, such as counts and strings, have a score of 0.
+    fn cost_score(&self, _context: AnalyzeContext, _cache: &CostCache) -> u64 {
+        0
+    }
+    /// Checks whether the node can never contain any revisions even though it
+    /// isn't written as `none()`, which usually indicates a mistake. Whether
+    /// children are empty should be looked up using
+    /// [`CostCache::is_always_empty`].
+    fn is_always_empty(&self, _cache: &CostCache) -> bool {
+        false
+    }
+    /// Returns the type of the node with any lifetimes replaced by `'static`,
+    /// so that nodes which share an address can be told apart.
+    fn node_type(&self) -> TypeId;
+    fn description(&self) -> Cow<'static, str>;
+    /// Returns the node if it is a [`Predicate`], so that predicates which are
+    /// repeated in the tree can be compared.
+    fn as_predicate(&self) -> Option<&Predicate<'_>> {
+        None
+    }
+    /// Returns the alias which the node was expanded from with
+    /// `--alias-origins`, which is displayed after its name.
+    fn alias_origin(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Caches the cost of each node for each context, since the cost of some nodes
+/// depends on the cost of their children. Whether each node is always empty is
+/// cached for the same reason.
+#[derive(Debug, Default)]
+pub struct CostCache {
+    costs: RefCell<HashMap<NodeKey, AnalyzeCost>>,
+    always_empty: RefCell<HashMap<(usize, TypeId), bool>>,
+    /// The nodes with a context in [`FormatOptions::context_overrides`], which
+    /// are always analyzed in that context.
+    overrides: HashMap<(usize, TypeId), AnalyzeContext>,
+}
+
+impl CostCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a cache for analyzing `tree`, where any node with a context in
+    /// [`FormatOptions::context_overrides`] is analyzed in that context, even
+    /// when its parent looks up its cost.
+    pub fn for_tree(
+        tree: &dyn AnalyzeTree,
+        context: AnalyzeContext,
+        options: &FormatOptions,
+    ) -> Self {
+        let mut overrides = HashMap::new();
+        if !options.context_overrides.is_empty() {
+            walk(tree, context, options, &mut |node| {
+                if options.context_overrides.get(node.path).is_some() {
+                    overrides.insert(node_address(node.tree), node.context);
+                }
+            });
+        }
+        Self {
+            overrides,
+            ..Self::default()
+        }
+    }
+
+    pub fn cost(&self, tree: &dyn AnalyzeTree, context: AnalyzeContext) -> AnalyzeCost {
+        let context = (self.overrides.get(&node_address(tree)).copied()).unwrap_or(context);
+        let key = node_key(tree, context);
+        if let Some(cost) = self.costs.borrow().get(&key) {
+            return *cost;
+        }
+        // A node may look up the cost of its children in any context, so the
+        // children are analyzed first in every context. Otherwise, each level of
+        // a deeply nested revset would add another call to the stack.
+        let options = FormatOptions::default();
+        let mut stack = vec![(tree, false)];
+        while let Some((tree, visited)) = stack.pop() {
+            if visited {
+                for context in AnalyzeContext::ALL {
+                    let key = node_key(tree, context);
+                    if !self.costs.borrow().contains_key(&key) {
+                        let cost = tree.cost(context, self);
+                        self.costs.borrow_mut().insert(key, cost);
+                    }
+                }
+            } else if !self.costs.borrow().contains_key(&node_key(tree, context)) {
+                stack.push((tree, true));
+                let entry = tree.entry(context, &options);
+                stack.extend(entry.children.iter().map(|child| (child.tree, false)));
+            }
+        }
+        self.costs.borrow()[&key]
+    }
+
+    /// Checks whether the node can never contain any revisions using
+    /// [`AnalyzeTree::is_always_empty`].
+    pub fn is_always_empty(&self, tree: &dyn AnalyzeTree) -> bool {
+        let key = node_address(tree);
+        if let Some(always_empty) = self.always_empty.borrow().get(&key) {
+            return *always_empty;
+        }
+        // Children are checked first, so each node only needs to look up whether
+        // its children are empty instead of checking its entire subtree
+        let options = FormatOptions::default();
+        let mut stack = vec![(tree, false)];
+        while let Some((tree, visited)) = stack.pop() {
+            let key = node_address(tree);
+            if visited {
+                let always_empty = tree.is_always_empty(self);
+                self.always_empty.borrow_mut().insert(key, always_empty);
+            } else if !self.always_empty.borrow().contains_key(&key) {
+                stack.push((tree, true));
+                let entry = tree.entry(AnalyzeContext::Lazy, &options);
+                stack.extend(entry.children.iter().map(|child| (child.tree, false)));
+            }
+        }
+        self.always_empty.borrow()[&key]
+    }
+}
+
+/// Identifies a node in a specific context by [`node_address`].
+pub(crate) type NodeKey = (usize, TypeId, AnalyzeContext);
+
+pub(crate) fn node_key(tree: &dyn AnalyzeTree, context: AnalyzeContext) -> NodeKey {
+    let (address, node_type) = node_address(tree);
+    (address, node_type, context)
+}
+
+/// Identifies a node by its address and type. A child stored inline in its
+/// parent may share the parent's address, but it will have a different type.
+fn node_address(tree: &dyn AnalyzeTree) -> (usize, TypeId) {
+    (
+        (tree as *const dyn AnalyzeTree).cast::<()>() as usize,
+        tree.node_type(),
+    )
+}
+
+/// Contexts which replace the context a node would otherwise be evaluated in,
+/// such as from `--select-context`. Nodes are identified by their dotted path,
+/// as returned by [`canonical_path`].
+#[derive(Clone, Debug, Default)]
+pub struct ContextOverrides {
+    contexts: HashMap<String, AnalyzeContext>,
+}
+
+impl ContextOverrides {
+    pub fn insert(&mut self, path: String, context: AnalyzeContext) {
+        self.contexts.insert(path, context);
+    }
+
+    pub fn get(&self, path: &str) -> Option<AnalyzeContext> {
+        self.contexts.get(path).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+}
+
+/// Appends the segment for a child to the dotted path of its parent, using the
+/// label of the child if it has one.
+pub(crate) fn push_path_segment(path: &mut String, label: Option<&str>, index: usize) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    match label {
+        Some(label) => path.push_str(label),
+        None => write!(path, "{index}").unwrap(),
+    }
+}
+
+/// Returns the entry of the node at `path`, sorting the members of unions and
+/// intersections if `--sort-members` was passed, and replacing the context of
+/// any children which have a context in [`FormatOptions::context_overrides`].
+pub fn node_entry<'a>(
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    options: &FormatOptions,
+) -> TreeEntry<'a> {
+    let mut entry = tree.entry(context, options);
+    let sorted = match entry.name.as_ref() {
+        "Union" | "Intersection" => options.sort_members,
+        // The first member which isn't empty is used, so order matters
+        "Coalesce" => options.sort_coalesce,
+        _ => false,
+    };
+    if sorted && entry.children.iter().all(|child| child.label.is_none()) {
+        entry
+            .children
+            .sort_by_cached_key(|child| child.tree.entry(child.context, options).name.into_owned());
+    }
+    // Paths use the sorted order of children, so overrides are applied after
+    // sorting
+    if !options.context_overrides.is_empty() {
+        for (index, child) in entry.children.iter_mut().enumerate() {
+            let mut child_path = path.to_owned();
+            push_path_segment(&mut child_path, child.label.as_deref(), index);
+            if let Some(context) = options.context_overrides.get(&child_path) {
+                child.context = context;
+            }
+        }
+    }
+    entry
+}
+
+/// The number of each node in the order they are visited by [`walk`], so that
+/// every output format can refer to the same node by the same number.
+#[derive(Debug, Default)]
+pub struct NodeNumbers {
+    numbers: HashMap<NodeKey, usize>,
+}
+
+impl NodeNumbers {
+    pub fn new(tree: &dyn AnalyzeTree, context: AnalyzeContext, options: &FormatOptions) -> Self {
+        let mut numbers = HashMap::new();
+        walk(tree, context, options, &mut |node| {
+            numbers
+                .entry(node_key(node.tree, node.context))
+                .or_insert(node.index);
+        });
+        Self { numbers }
+    }
+
+    pub fn get(&self, tree: &dyn AnalyzeTree, context: AnalyzeContext) -> Option<usize> {
+        self.numbers.get(&node_key(tree, context)).copied()
+    }
+}
+
+/// A node visited by [`walk`].
+#[derive(Debug)]
+pub struct WalkNode<'a, 't> {
+    pub tree: &'t dyn AnalyzeTree,
+    pub context: AnalyzeContext,
+    pub label: Option<Cow<'a, str>>,
+    pub entry: &'a TreeEntry<'a>,
+    pub depth: usize,
+    /// The position of the node in the order nodes are visited, starting at 0
+    /// for the root node.
+    pub index: usize,
+    /// The dotted path to the node, as accepted by [`find_node`]. The path of
+    /// the root node is empty.
+    pub path: &'a str,
+}
+
+/// Visits every node in the tree depth-first, in the same order that the
+/// nodes are printed. The root node has a depth of 0.
+///
+/// The tree is traversed without recursion, so any tree which can be parsed
+/// can be walked, no matter how deeply it is nested.
+pub fn walk<'a>(
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &FormatOptions,
+    visitor: &mut dyn FnMut(&WalkNode<'_, 'a>),
+) {
+    let mut stack: Vec<(_, _, Option<Cow<str>>, _, _)> = vec![(tree, context, None, 0, 0)];
+    let mut index = 0;
+    // The path of the current node is built in place, keeping the length of the
+    // path of each ancestor so that it can be truncated when moving to a sibling
+    let mut path = String::new();
+    let mut path_lengths = Vec::new();
+    while let Some((tree, context, label, depth, child_index)) = stack.pop() {
+        path_lengths.truncate(depth);
+        path.truncate(path_lengths.last().copied().unwrap_or_default());
+        if depth > 0 {
+            push_path_segment(&mut path, label.as_deref(), child_index);
+        }
+        path_lengths.push(path.len());
+        let entry = node_entry(tree, context, &path, options);
+        let children = entry
+            .children
+            .iter()
+            .enumerate()
+            .map(|(child_index, child)| {
+                (
+                    child.tree,
+                    child.context,
+                    child.label.clone(),
+                    depth + 1,
+                    child_index,
+                )
+            })
+            .collect::<Vec<_>>();
+        visitor(&WalkNode {
+            tree,
+            context,
+            label,
+            entry: &entry,
+            depth,
+            index,
+            path: &path,
+        });
+        index += 1;
+        stack.extend(children.into_iter().rev());
+    }
+}
+
+/// Converts a node number like `#3` into the dotted path of that node. Any
+/// other path is returned unchanged.
+pub fn resolve_node_number(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<String> {
+    let Some(number) = path.strip_prefix('#') else {
+        return Ok(path.to_owned());
+    };
+    let number: usize = number
+        .parse()
+        .with_context(|| format!("Invalid node number `{path}`"))?;
+    let mut found = None;
+    walk(tree, context, options, &mut |node| {
+        if node.index == number {
+            found = Some(node.path.to_owned());
+        }
+    });
+    found.with_context(|| format!("There is no node numbered `{path}`"))
+}
+
+pub fn only_present(children: Vec<Option<Child>>) -> Vec<Child> {
+    children.into_iter().flatten().collect()
+}
+
+/// Checks whether a child should be displayed. Children which have a default
+/// value, such as a count of 1 for `latest()`, are hidden by `--compact-empty`.
+pub fn is_shown(is_default: bool, options: &FormatOptions) -> bool {
+    !(is_default && options.compact_empty)
+}
+
+/// Returns the label for a count, which is a phrase like `most_recent` if
+/// `--describe-counts` was passed.
+pub fn count_label(phrase: &'static str, options: &FormatOptions) -> &'static str {
+    if options.describe_counts {
+        phrase
+    } else {
+        "count"
+    }
+}
+
+/// Finds the node at a dotted path such as `0.heads.1`. Each segment of the
+/// path is either the label of a child or the index of a child.
+pub fn find_node<'a>(
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<(&'a dyn AnalyzeTree, AnalyzeContext)> {
+    find_node_with_path(tree, context, path, options).map(|(node, _)| node)
+}
+
+/// Converts a dotted path into the same form as [`WalkNode::path`], where
+/// children with labels are always referred to by their label.
+pub fn canonical_path(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<String> {
+    find_node_with_path(tree, context, path, options).map(|(_, path)| path)
+}
+
+/// Finds the node at a dotted path like [`find_node`], also returning the path
+/// in the same form as [`WalkNode::path`].
+pub(crate) fn find_node_with_path<'a>(
+    tree: &'a dyn AnalyzeTree,
+    context: AnalyzeContext,
+    path: &str,
+    options: &FormatOptions,
+) -> anyhow::Result<((&'a dyn AnalyzeTree, AnalyzeContext), String)> {
+    let mut node = (tree, context);
+    let mut node_path = String::new();
+    for segment in path.split('.').filter(|segment| !segment.is_empty()) {
+        let entry = node_entry(node.0, node.1, &node_path, options);
+        let (index, child) = entry
+            .children
+            .iter()
+            .enumerate()
+            .find(|(_, child)| child.label.as_deref() == Some(segment))
+            .or_else(|| {
+                let index = segment.parse::<usize>().ok()?;
+                Some((index, entry.children.get(index)?))
+            })
+            .with_context(|| format!("`{}` has no child `{segment}`", entry.name))?;
+        push_path_segment(&mut node_path, child.label.as_deref(), index);
+        node = (child.tree, child.context);
+    }
+    Ok((node, node_path))
+}
+
+/// Sums the cost score of every node in the tree.
+pub fn total_cost_score(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &FormatOptions,
+) -> u64 {
+    let cache = CostCache::for_tree(tree, context, options);
+    let mut total = 0u64;
+    walk(tree, context, options, &mut |node| {
+        total = total.saturating_add(node.tree.cost_score(node.context, &cache));
+    });
+    total
+}
+
+/// Counts the number of nodes in the tree.
+pub fn count_nodes(
+    tree: &dyn AnalyzeTree,
+    context: AnalyzeContext,
+    options: &FormatOptions,
+) -> usize {
+    let mut count = 0;
+    walk(tree, context, options, &mut |_| count += 1);
+    count
 }
 
 impl AnalyzeTree for usize {
-    fn entry(&self, _context: AnalyzeContext) -> TreeEntry<'_> {
+    fn entry(&self, _context: AnalyzeContext, _options: &FormatOptions) -> TreeEntry<'_> {
         TreeEntry {
             name: self.to_string().into(),
+            pattern_kinds: vec![],
             context: AnalyzeContext::Resolved,
             children: vec![],
         }
     }
 
-    fn cost(&self, _context: AnalyzeContext) -> AnalyzeCost {
+    fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
         AnalyzeCost::Fast
     }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        _cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        None
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<usize>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        "A count.".into()
+    }
 }
 
-impl AnalyzeTree for Range<u64> {
-    fn entry(&self, _context: AnalyzeContext) -> TreeEntry<'_> {
+impl AnalyzeTree for bool {
+    fn entry(&self, _context: AnalyzeContext, _options: &FormatOptions) -> TreeEntry<'_> {
         TreeEntry {
-            name: format_range(self, 0..u64::MAX).into(),
+            name: self.to_string().into(),
+            pattern_kinds: vec![],
             context: AnalyzeContext::Resolved,
             children: vec![],
         }
     }
 
-    fn cost(&self, _context: AnalyzeContext) -> AnalyzeCost {
+    fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
         AnalyzeCost::Fast
     }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        _cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        None
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<bool>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        "A flag.".into()
+    }
 }
 
-impl AnalyzeTree for Range<u32> {
-    fn entry(&self, _context: AnalyzeContext) -> TreeEntry<'_> {
+impl AnalyzeTree for String {
+    fn entry(&self, _context: AnalyzeContext, _options: &FormatOptions) -> TreeEntry<'_> {
         TreeEntry {
-            name: format_range(self, 0..u32::MAX).into(),
+            name: self.as_str().into(),
+            pattern_kinds: vec![],
             context: AnalyzeContext::Resolved,
             children: vec![],
         }
     }
 
-    fn cost(&self, _context: AnalyzeContext) -> AnalyzeCost {
+    fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
         AnalyzeCost::Fast
     }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        _cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        None
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<String>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        "A string.".into()
+    }
+}
+
+// Ranges are displayed relative to a full range of `0..MAX`, so that the full
+// range is displayed as empty
+macro_rules! impl_range_tree {
+    ($($ty:ty => $description:literal, $format:path),* $(,)?) => {
+        $(
+            impl AnalyzeTree for Range<$ty> {
+                fn entry(
+                    &self,
+                    _context: AnalyzeContext,
+                    options: &FormatOptions,
+                ) -> TreeEntry<'_> {
+                    TreeEntry {
+                        name: $format(self, 0..<$ty>::MAX, options).into(),
+                        pattern_kinds: vec![],
+                        context: AnalyzeContext::Resolved,
+                        children: vec![],
+                    }
+                }
+
+                fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
+                    AnalyzeCost::Fast
+                }
+
+                fn cost_reason(
+                    &self,
+                    _context: AnalyzeContext,
+                    _cache: &CostCache,
+                ) -> Option<Cow<'static, str>> {
+                    None
+                }
+
+                fn node_type(&self) -> TypeId {
+                    TypeId::of::<Range<$ty>>()
+                }
+
+                fn description(&self) -> Cow<'static, str> {
+                    $description.into()
+                }
+            }
+        )*
+    };
+}
+
+impl_range_tree! {
+    u64 => "A range of generations.", format_generation,
+    u32 => "A range of parent indices.", format_parent_index,
+    i64 => "A range of values.", format_plain_range,
+}
+
+fn format_plain_range<T>(range: &Range<T>, full_range: Range<T>, _options: &FormatOptions) -> String
+where
+    T: Copy + Eq + From<u32> + ops::Sub<Output = T> + fmt::Display,
+{
+    format_range(range, full_range)
+}
+
+/// Formats a range of generations, as a phrase describing the depth if
+/// `--generation-as-depth` was passed. Generations may count either parents or
+/// children, so the phrases don't mention a direction.
+fn format_generation(
+    range: &Range<u64>,
+    full_range: Range<u64>,
+    options: &FormatOptions,
+) -> String {
+    if !options.generation_as_depth || range.is_empty() {
+        return format_range(range, full_range);
+    }
+    match (range.start, range.end) {
+        (0, 1) => "self".to_owned(),
+        (start, end) if end - start == 1 => format!("depth {start}"),
+        (0, u64::MAX) => "any depth".to_owned(),
+        (start, u64::MAX) => format!("depth {start} or more"),
+        (0, end) => format!("up to depth {}", end - 1),
+        (start, end) => format!("depth {start} to {}", end - 1),
+    }
+}
+
+/// Formats a range of parent indices, as a phrase like `first parent only` if
+/// `--generation-as-depth` was passed.
+fn format_parent_index(
+    range: &Range<u32>,
+    full_range: Range<u32>,
+    options: &FormatOptions,
+) -> String {
+    if !options.generation_as_depth || range.is_empty() {
+        return format_range(range, full_range);
+    }
+    // Parent indices start at 0, but ordinals start at 1
+    let ordinal = |index: u32| match index + 1 {
+        1 => "first".to_owned(),
+        2 => "second".to_owned(),
+        3 => "third".to_owned(),
+        n if (11..=13).contains(&(n % 100)) => format!("{n}th"),
+        n if n % 10 == 1 => format!("{n}st"),
+        n if n % 10 == 2 => format!("{n}nd"),
+        n if n % 10 == 3 => format!("{n}rd"),
+        n => format!("{n}th"),
+    };
+    match (range.start, range.end) {
+        (start, end) if end - start == 1 => format!("{} parent only", ordinal(start)),
+        (0, u32::MAX) => "any parent".to_owned(),
+        (start, u32::MAX) => format!("{} parent and beyond", ordinal(start)),
+        (start, end) => format!("{} to {} parents", ordinal(start), ordinal(end - 1)),
+    }
 }
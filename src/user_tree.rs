@@ -0,0 +1,283 @@
+use std::any::TypeId;
+use std::borrow::Cow;
+
+use itertools::Itertools as _;
+use jj_lib::object_id::ObjectId as _;
+use jj_lib::revset::GENERATION_RANGE_FULL;
+use jj_lib::revset::PARENTS_RANGE_FULL;
+use jj_lib::revset::RevsetExpression;
+use jj_lib::revset::UserRevsetExpression;
+
+use crate::expr::format_filter;
+use crate::parse::resolve_commit_ref;
+use crate::print::FormatOptions;
+use crate::tree::AnalyzeContext;
+use crate::tree::AnalyzeCost;
+use crate::tree::AnalyzeTree;
+use crate::tree::Child;
+use crate::tree::CostCache;
+use crate::tree::TreeEntry;
+use crate::tree::count_label;
+use crate::tree::is_shown;
+use crate::tree::only_present;
+
+fn leaf(name: Cow<'static, str>) -> TreeEntry<'static> {
+    TreeEntry {
+        name,
+        pattern_kinds: vec![],
+        context: AnalyzeContext::Resolved,
+        children: vec![],
+    }
+}
+
+fn child<'a>(
+    context: AnalyzeContext,
+    label: Option<&'static str>,
+    tree: &'a dyn AnalyzeTree,
+) -> Child<'a> {
+    Child {
+        label: label.map(Cow::Borrowed),
+        context,
+        tree,
+    }
+}
+
+fn operation<'a>(
+    context: AnalyzeContext,
+    name: &'static str,
+    children: Vec<Child<'a>>,
+) -> TreeEntry<'a> {
+    TreeEntry {
+        name: name.into(),
+        pattern_kinds: vec![],
+        context,
+        children,
+    }
+}
+
+// The user expression tree is displayed before any resolution or optimization,
+// so it has no evaluation context. Every operation simply inherits the context
+// it was given, and leaves are always resolved.
+impl AnalyzeTree for UserRevsetExpression {
+    fn entry(&self, context: AnalyzeContext, options: &FormatOptions) -> TreeEntry<'_> {
+        match self {
+            Self::None => leaf("none()".into()),
+            Self::All => leaf("all()".into()),
+            Self::VisibleHeads => leaf("visible_heads()".into()),
+            Self::VisibleHeadsOrReferenced => {
+                leaf("visible_heads() and referenced revisions".into())
+            }
+            Self::Root => leaf("root()".into()),
+            Self::Commits(commit_ids) => leaf(
+                format!(
+                    "commits({})",
+                    commit_ids.iter().map(|id| id.hex()).join(", ")
+                )
+                .into(),
+            ),
+            Self::CommitRef(reference) => leaf(resolve_commit_ref(reference).name),
+            Self::Ancestors {
+                heads,
+                generation,
+                parents_range,
+            } => operation(
+                context,
+                "Ancestors",
+                only_present(vec![
+                    (*generation != GENERATION_RANGE_FULL)
+                        .then(|| child(context, Some("generation"), generation)),
+                    (*parents_range != PARENTS_RANGE_FULL)
+                        .then(|| child(context, Some("parent_index"), parents_range)),
+                    Some(child(context, Some("heads"), heads.as_ref())),
+                ]),
+            ),
+            Self::Descendants { roots, generation } => operation(
+                context,
+                "Descendants",
+                only_present(vec![
+                    (*generation != GENERATION_RANGE_FULL)
+                        .then(|| child(context, Some("generation"), generation)),
+                    Some(child(context, Some("roots"), roots.as_ref())),
+                ]),
+            ),
+            Self::Range {
+                roots,
+                heads,
+                generation,
+                parents_range,
+            } => operation(
+                context,
+                "Range",
+                only_present(vec![
+                    (*generation != GENERATION_RANGE_FULL)
+                        .then(|| child(context, Some("generation"), generation)),
+                    (*parents_range != PARENTS_RANGE_FULL)
+                        .then(|| child(context, Some("parent_index"), parents_range)),
+                    Some(child(context, Some("roots"), roots.as_ref())),
+                    Some(child(context, Some("heads"), heads.as_ref())),
+                ]),
+            ),
+            Self::DagRange { roots, heads } => operation(
+                context,
+                "DagRange",
+                vec![
+                    child(context, Some("roots"), roots.as_ref()),
+                    child(context, Some("heads"), heads.as_ref()),
+                ],
+            ),
+            Self::Reachable { sources, domain } => operation(
+                context,
+                "Reachable",
+                vec![
+                    child(context, Some("sources"), sources.as_ref()),
+                    child(context, Some("domain"), domain.as_ref()),
+                ],
+            ),
+            Self::Heads(expr) => {
+                operation(context, "Heads", vec![child(context, None, expr.as_ref())])
+            }
+            Self::HeadsRange {
+                roots,
+                heads,
+                parents_range,
+                filter,
+            } => operation(
+                context,
+                "HeadsRange",
+                only_present(vec![
+                    (*parents_range != PARENTS_RANGE_FULL)
+                        .then(|| child(context, Some("parent_index"), parents_range)),
+                    Some(child(context, Some("roots"), roots.as_ref())),
+                    Some(child(context, Some("heads"), heads.as_ref())),
+                    Some(child(context, Some("filter"), filter.as_ref())),
+                ]),
+            ),
+            Self::Roots(expr) => {
+                operation(context, "Roots", vec![child(context, None, expr.as_ref())])
+            }
+            Self::ForkPoint(expr) => operation(
+                context,
+                "ForkPoint",
+                vec![child(context, None, expr.as_ref())],
+            ),
+            Self::Bisect(expr) => {
+                operation(context, "Bisect", vec![child(context, None, expr.as_ref())])
+            }
+            Self::HasSize { candidates, count } => operation(
+                context,
+                "HasSize",
+                vec![
+                    child(context, Some(count_label("exactly", options)), count),
+                    child(context, Some("candidates"), candidates.as_ref()),
+                ],
+            ),
+            Self::Latest { candidates, count } => operation(
+                context,
+                "Latest",
+                only_present(vec![
+                    is_shown(*count == 1, options)
+                        .then(|| child(context, Some(count_label("most_recent", options)), count)),
+                    Some(child(context, Some("candidates"), candidates.as_ref())),
+                ]),
+            ),
+            Self::Filter(filter) => {
+                let (name, pattern_kinds) = format_filter(filter, options);
+                TreeEntry {
+                    pattern_kinds,
+                    ..leaf(name)
+                }
+            }
+            Self::AsFilter(expr) => operation(
+                context,
+                "AsFilter",
+                vec![child(context, None, expr.as_ref())],
+            ),
+            Self::AtOperation {
+                operation: at_operation,
+                candidates,
+            } => operation(
+                context,
+                "AtOperation",
+                vec![
+                    child(context, Some("operation"), at_operation),
+                    child(context, Some("candidates"), candidates.as_ref()),
+                ],
+            ),
+            Self::WithinReference { candidates, .. } => operation(
+                context,
+                "WithinReference",
+                vec![child(context, Some("candidates"), candidates.as_ref())],
+            ),
+            Self::WithinVisibility { candidates, .. } => operation(
+                context,
+                "WithinVisibility",
+                vec![child(context, Some("candidates"), candidates.as_ref())],
+            ),
+            Self::Coalesce(expr1, expr2) => operation(
+                context,
+                "Coalesce",
+                vec![
+                    child(context, None, expr1.as_ref()),
+                    child(context, None, expr2.as_ref()),
+                ],
+            ),
+            Self::Present(expr) => operation(
+                context,
+                "Present",
+                vec![child(context, None, expr.as_ref())],
+            ),
+            Self::NotIn(expr) => {
+                operation(context, "NotIn", vec![child(context, None, expr.as_ref())])
+            }
+            Self::Union(expr1, expr2) => operation(
+                context,
+                "Union",
+                vec![
+                    child(context, None, expr1.as_ref()),
+                    child(context, None, expr2.as_ref()),
+                ],
+            ),
+            Self::Intersection(expr1, expr2) => operation(
+                context,
+                "Intersection",
+                vec![
+                    child(context, None, expr1.as_ref()),
+                    child(context, None, expr2.as_ref()),
+                ],
+            ),
+            Self::Difference(expr1, expr2) => operation(
+                context,
+                "Difference",
+                vec![
+                    child(context, Some("candidates"), expr1.as_ref()),
+                    child(context, Some("excluded"), expr2.as_ref()),
+                ],
+            ),
+        }
+    }
+
+    fn cost(&self, _context: AnalyzeContext, _cache: &CostCache) -> AnalyzeCost {
+        AnalyzeCost::Fast
+    }
+
+    fn cost_reason(
+        &self,
+        _context: AnalyzeContext,
+        _cache: &CostCache,
+    ) -> Option<Cow<'static, str>> {
+        None
+    }
+
+    fn node_type(&self) -> TypeId {
+        TypeId::of::<UserRevsetExpression>()
+    }
+
+    fn description(&self) -> Cow<'static, str> {
+        match self {
+            RevsetExpression::CommitRef(_) => "A reference to revisions, before resolution.",
+            RevsetExpression::Filter(_) => "A filter on revisions, before resolution.",
+            _ => "A revset expression as written, before resolution or optimization.",
+        }
+        .into()
+    }
+}